@@ -148,5 +148,100 @@ pub fn insert_bench(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, basic_bench, insert_bench, bitmap_bench);
+/// Compare the lookup and insert performance of a loaded `KeyBytes4` filter
+/// for each bitmap backend, each probing the same number of keys per value.
+pub fn backend_bench(c: &mut Criterion) {
+    backend_bench_for::<CompressedBitmap>(c, "compressed");
+    backend_bench_for::<VecBitmap>(c, "vec");
+    backend_bench_for::<BlockedBitmap>(c, "blocked");
+}
+
+fn backend_bench_for<B: Bitmap + Clone>(c: &mut Criterion, name: &str) {
+    const N: usize = 1_000_000;
+
+    // The backends default to a different number of probes - fix it so each
+    // benchmark does the same work.
+    const K: u8 = 8;
+
+    let mut bloom = BloomFilterBuilder::default()
+        .with_bitmap::<B>()
+        .size(FilterSize::KeyBytes4)
+        .hashes(K)
+        .build();
+    bloom.extend(0..N);
+
+    c.bench_function(&format!("backend_{}_lookup_hit", name), |b| {
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 1) % N;
+            black_box(bloom.contains(&i))
+        })
+    });
+
+    c.bench_function(&format!("backend_{}_lookup_miss", name), |b| {
+        let mut i = N;
+        b.iter(|| {
+            i += 1;
+            black_box(bloom.contains(&i))
+        })
+    });
+
+    c.bench_function(&format!("backend_{}_insert", name), |b| {
+        let mut i = N;
+        b.iter(|| {
+            i += 1;
+            bloom.insert(black_box(&i))
+        })
+    });
+}
+
+pub fn batch_bench(c: &mut Criterion) {
+    const N: usize = 1_000_000;
+
+    let mut bloom = BloomFilterBuilder::default()
+        .size(FilterSize::KeyBytes4)
+        .build();
+    bloom.extend(0..N);
+
+    // Half hits, half misses.
+    let values = (0..1024).map(|i| i * 1_953).collect::<Vec<_>>();
+    let mut out = vec![false; values.len()];
+
+    c.bench_function("batch_contains_loop_1024", |b| {
+        b.iter(|| {
+            for (v, out) in values.iter().zip(out.iter_mut()) {
+                *out = bloom.contains(v);
+            }
+            black_box(&out);
+        })
+    });
+
+    c.bench_function("batch_contains_many_1024", |b| {
+        b.iter(|| {
+            bloom.contains_many(&values, &mut out);
+            black_box(&out);
+        })
+    });
+
+    c.bench_function("batch_insert_loop_1024", |b| {
+        b.iter(|| {
+            for v in &values {
+                bloom.insert(black_box(v));
+            }
+        })
+    });
+
+    c.bench_function("batch_insert_many_1024", |b| {
+        b.iter(|| bloom.insert_many(black_box(&values)))
+    });
+}
+
+criterion_group!(
+    benches,
+    basic_bench,
+    insert_bench,
+    bitmap_bench,
+    backend_bench,
+    batch_bench
+);
 criterion_main!(benches);
@@ -0,0 +1,124 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{CompressedBitmap, SharedBitmap, VecBitmap};
+
+use super::{bitmask_for_key, index_for_key};
+
+/// A plain, heap-allocated bitmap of atomic words, supporting concurrent
+/// writes through a shared reference.
+///
+/// Bits are set with a single atomic `fetch_or` of the word holding them,
+/// allowing many threads to insert into a
+/// [`ConcurrentBloom2`](crate::ConcurrentBloom2) without locking.
+///
+/// Like a [`VecBitmap`], this bitmap requires `O(n)` space - once loading
+/// finishes, it can be frozen into a [`VecBitmap`] or a (sparse)
+/// [`CompressedBitmap`] with [`From`].
+#[derive(Debug)]
+pub struct AtomicBitmap {
+    bitmap: Vec<AtomicU64>,
+    max_key: usize,
+}
+
+impl SharedBitmap for AtomicBitmap {
+    fn new_with_capacity(max_key: usize) -> Self {
+        let bitmap = (0..index_for_key(max_key) + 1)
+            .map(|_| AtomicU64::new(0))
+            .collect();
+        Self { bitmap, max_key }
+    }
+
+    fn set(&self, key: usize) {
+        let offset = index_for_key(key);
+
+        // Relaxed ordering is sufficient as bits are only ever set, and
+        // readers synchronise with the writers by other means (such as
+        // joining the writer threads) to observe them.
+        self.bitmap[offset].fetch_or(bitmask_for_key(key) as u64, Ordering::Relaxed);
+    }
+
+    fn get(&self, key: usize) -> bool {
+        let offset = index_for_key(key);
+
+        self.bitmap[offset].load(Ordering::Relaxed) & bitmask_for_key(key) as u64 != 0
+    }
+
+    fn byte_size(&self) -> usize {
+        self.bitmap.len() * std::mem::size_of::<AtomicU64>()
+    }
+}
+
+impl From<AtomicBitmap> for VecBitmap {
+    fn from(v: AtomicBitmap) -> Self {
+        let bitmap = v
+            .bitmap
+            .into_iter()
+            .map(|w| w.into_inner() as usize)
+            .collect();
+
+        VecBitmap::from_parts(bitmap, v.max_key)
+    }
+}
+
+impl From<AtomicBitmap> for CompressedBitmap {
+    fn from(v: AtomicBitmap) -> Self {
+        CompressedBitmap::from(VecBitmap::from(v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::Bitmap;
+
+    const MAX_KEY: usize = 1028;
+
+    #[test]
+    fn test_concurrent_set() {
+        let b = AtomicBitmap::new_with_capacity(MAX_KEY);
+
+        // Every thread sets a disjoint set of bits in the same words.
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let b = &b;
+                s.spawn(move || {
+                    for key in (t..MAX_KEY).step_by(4) {
+                        b.set(key);
+                    }
+                });
+            }
+        });
+
+        assert!((0..MAX_KEY).all(|key| b.get(key)));
+        assert!(!b.get(MAX_KEY));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_freeze(
+            values in prop::collection::vec(0..MAX_KEY, 0..100),
+        ) {
+            let a = AtomicBitmap::new_with_capacity(MAX_KEY);
+            let b = AtomicBitmap::new_with_capacity(MAX_KEY);
+            let mut want = VecBitmap::new_with_capacity(MAX_KEY);
+            for v in &values {
+                a.set(*v);
+                b.set(*v);
+                want.set(*v, true);
+            }
+
+            for v in 0..MAX_KEY {
+                assert_eq!(a.get(v), want.get(v));
+            }
+
+            // Invariant: freezing preserves every bit.
+            assert_eq!(VecBitmap::from(a), want);
+            assert_eq!(
+                CompressedBitmap::from(b).iter_ones().collect::<Vec<_>>(),
+                want.iter_ones().collect::<Vec<_>>()
+            );
+        }
+    }
+}
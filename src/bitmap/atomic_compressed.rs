@@ -0,0 +1,276 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    PoisonError, RwLock,
+};
+
+use crate::{CompressedBitmap, SharedBitmap, VecBitmap};
+
+use super::{
+    bitmask_for_key,
+    compressed_bitmap::{block_map_len, num_segments, SUPERBLOCK_WORDS},
+    index_for_key,
+};
+
+/// A sparse, 2-level bitmap supporting concurrent writes through a shared
+/// reference.
+///
+/// An `AtomicCompressedBitmap` uses the same layout as a [`CompressedBitmap`],
+/// lazily allocating blocks of bits as they are first set, so memory use
+/// remains proportional to the number of bits set. This allows many threads to
+/// load a large sparse filter (such as a
+/// [`KeyBytes4`](crate::FilterSize::KeyBytes4) filter) into a
+/// [`ConcurrentBloom2`](crate::ConcurrentBloom2) without a global lock, or the
+/// memory cost of an [`AtomicBitmap`](crate::AtomicBitmap).
+///
+/// Each superblock of the block map (mapping 1024 blocks) is guarded by its
+/// own lock, striping contention across the bitmap:
+///
+/// * Setting a bit in an allocated block, and all lookups, hold a shared read
+///   lock - bits are set with an atomic `fetch_or`, allowing any number of
+///   threads to set bits in the same superblock at once.
+///
+/// * Allocating a block holds the exclusive write lock of its superblock only.
+///
+/// Once loading finishes, it can be frozen into a [`CompressedBitmap`] (or a
+/// [`VecBitmap`]) with [`From`].
+#[derive(Debug)]
+pub struct AtomicCompressedBitmap {
+    superblocks: Vec<RwLock<Superblock>>,
+
+    /// The maximum key this bitmap was sized to hold.
+    max_key: usize,
+}
+
+/// A superblock of block map words, and the blocks it maps.
+#[derive(Debug, Default)]
+struct Superblock {
+    /// LSB is 0.
+    block_map: [usize; SUPERBLOCK_WORDS],
+
+    /// The allocated blocks, ordered by their logical block index.
+    blocks: Vec<AtomicU64>,
+}
+
+impl Superblock {
+    /// Return the offset into `blocks` of the logical `block_index` within
+    /// this superblock if allocated, or the offset at which it is inserted if
+    /// not.
+    fn offset(&self, block_index: usize) -> Result<usize, usize> {
+        let word = index_for_key(block_index) % SUPERBLOCK_WORDS;
+        let mask = bitmask_for_key(block_index);
+
+        // Count the allocated blocks preceding block_index.
+        let offset = self.block_map[..word]
+            .iter()
+            .map(|v| v.count_ones() as usize)
+            .sum::<usize>()
+            + (self.block_map[word] & (mask - 1)).count_ones() as usize;
+
+        match self.block_map[word] & mask {
+            0 => Err(offset),
+            _ => Ok(offset),
+        }
+    }
+}
+
+impl AtomicCompressedBitmap {
+    /// Return the superblock holding the logical `block_index`.
+    fn superblock(&self, block_index: usize) -> &RwLock<Superblock> {
+        &self.superblocks[index_for_key(block_index) / SUPERBLOCK_WORDS]
+    }
+}
+
+impl SharedBitmap for AtomicCompressedBitmap {
+    fn new_with_capacity(max_key: usize) -> Self {
+        let superblocks = (0..num_segments(block_map_len(max_key)))
+            .map(|_| RwLock::default())
+            .collect();
+
+        Self {
+            superblocks,
+            max_key,
+        }
+    }
+
+    fn set(&self, key: usize) {
+        let block_index = index_for_key(key);
+        let mask = bitmask_for_key(key) as u64;
+        let lock = self.superblock(block_index);
+
+        // Fast path: the block is allocated, and the bit can be set while
+        // sharing the lock with other writers.
+        {
+            let sb = lock.read().unwrap_or_else(PoisonError::into_inner);
+            if let Ok(offset) = sb.offset(block_index) {
+                sb.blocks[offset].fetch_or(mask, Ordering::Relaxed);
+                return;
+            }
+        }
+
+        // Slow path: allocate the block, unless another thread allocated it
+        // between releasing the read lock and acquiring the write lock.
+        let mut sb = lock.write().unwrap_or_else(PoisonError::into_inner);
+        match sb.offset(block_index) {
+            Ok(offset) => {
+                sb.blocks[offset].fetch_or(mask, Ordering::Relaxed);
+            }
+            Err(offset) => {
+                sb.blocks.insert(offset, AtomicU64::new(mask));
+                sb.block_map[index_for_key(block_index) % SUPERBLOCK_WORDS] |=
+                    bitmask_for_key(block_index);
+            }
+        }
+    }
+
+    fn get(&self, key: usize) -> bool {
+        let block_index = index_for_key(key);
+        let sb = self
+            .superblock(block_index)
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        match sb.offset(block_index) {
+            Ok(offset) => {
+                sb.blocks[offset].load(Ordering::Relaxed) & bitmask_for_key(key) as u64 != 0
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn byte_size(&self) -> usize {
+        (self.superblocks.capacity() * std::mem::size_of::<RwLock<Superblock>>())
+            + self
+                .superblocks
+                .iter()
+                .map(|sb| {
+                    let sb = sb.read().unwrap_or_else(PoisonError::into_inner);
+                    sb.blocks.capacity() * std::mem::size_of::<AtomicU64>()
+                })
+                .sum::<usize>()
+            + std::mem::size_of_val(self)
+    }
+}
+
+impl From<AtomicCompressedBitmap> for CompressedBitmap {
+    fn from(v: AtomicCompressedBitmap) -> Self {
+        let num_words = block_map_len(v.max_key);
+
+        let mut block_map = Vec::with_capacity(num_words);
+        let mut bitmap = Vec::with_capacity(v.superblocks.len());
+        for sb in v.superblocks {
+            let sb = sb.into_inner().unwrap_or_else(PoisonError::into_inner);
+            block_map.extend_from_slice(&sb.block_map);
+            bitmap.push(
+                sb.blocks
+                    .into_iter()
+                    .map(|w| w.into_inner() as usize)
+                    .collect(),
+            );
+        }
+
+        // The last superblock may map fewer words than it holds, all of
+        // which are unset.
+        block_map.truncate(num_words);
+
+        CompressedBitmap::from_parts(block_map, bitmap, v.max_key)
+    }
+}
+
+impl From<AtomicCompressedBitmap> for VecBitmap {
+    fn from(v: AtomicCompressedBitmap) -> Self {
+        let max_key = v.max_key;
+        let mut bitmap = vec![0; index_for_key(max_key) + 1];
+        for (idx, block) in CompressedBitmap::from(v).blocks() {
+            bitmap[idx] = block;
+        }
+
+        VecBitmap::from_parts(bitmap, max_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const MAX_KEY: usize = u32::MAX as usize >> 8;
+
+    #[test]
+    fn test_concurrent_set() {
+        let b = AtomicCompressedBitmap::new_with_capacity(MAX_KEY);
+
+        // Every thread sets a disjoint set of bits, racing to allocate the
+        // same blocks.
+        let keys = (0..MAX_KEY).step_by(997).collect::<Vec<_>>();
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let (b, keys) = (&b, &keys);
+                s.spawn(move || {
+                    for key in keys.iter().map(|k| k + t) {
+                        b.set(key);
+                    }
+                });
+            }
+        });
+
+        let mut want = CompressedBitmap::new(MAX_KEY);
+        for key in keys.iter().flat_map(|k| *k..*k + 4) {
+            assert!(b.get(key));
+            want.set(key, true);
+        }
+
+        // Invariant: the blocks are allocated in the same layout as a
+        // CompressedBitmap.
+        assert_eq!(CompressedBitmap::from(b), want);
+    }
+
+    #[test]
+    fn test_sparse() {
+        let b = AtomicCompressedBitmap::new_with_capacity(MAX_KEY);
+        let empty = b.byte_size();
+
+        b.set(42);
+        b.set(43);
+        b.set(MAX_KEY - 1);
+
+        // Invariant: only the two blocks holding the set bits are allocated,
+        // rather than the whole bitmap.
+        assert!(b.byte_size() - empty < 1024);
+        assert_eq!(
+            b.superblocks
+                .iter()
+                .map(|sb| sb.read().unwrap().blocks.len())
+                .sum::<usize>(),
+            2
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_freeze(
+            values in prop::collection::vec(0..MAX_KEY, 0..100),
+            check in prop::collection::vec(0..MAX_KEY, 0..100),
+        ) {
+            let a = AtomicCompressedBitmap::new_with_capacity(MAX_KEY);
+            let b = AtomicCompressedBitmap::new_with_capacity(MAX_KEY);
+            let mut want = CompressedBitmap::new(MAX_KEY);
+            for v in &values {
+                a.set(*v);
+                b.set(*v);
+                want.set(*v, true);
+            }
+
+            for v in values.iter().chain(&check) {
+                assert_eq!(a.get(*v), want.get(*v));
+            }
+
+            // Invariant: freezing preserves every bit.
+            assert_eq!(
+                VecBitmap::from(b).iter_ones().collect::<Vec<_>>(),
+                want.iter_ones().collect::<Vec<_>>()
+            );
+            assert_eq!(CompressedBitmap::from(a), want);
+        }
+    }
+}
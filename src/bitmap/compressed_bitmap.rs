@@ -1,6 +1,24 @@
-use crate::Bitmap;
-
-use super::{bitmask_for_key, index_for_key, vec::VecBitmap};
+use std::{
+    convert::TryFrom,
+    io::{Read, Write},
+};
+
+use crate::{
+    format::{read_usize, read_words},
+    Bitmap, FormatError, LayoutError, PortableBitmap,
+};
+
+use super::{
+    bitmask_for_key, compressed_bitmap_ref::validate_rank, index_for_key, prefetch, vec::VecBitmap,
+    WordOnes,
+};
+
+/// The number of block map words covered by a single physical storage segment.
+///
+/// Locating a physical block popcounts at most this many block map words, and
+/// allocating a block moves at most `SUPERBLOCK_WORDS * 64` existing blocks,
+/// regardless of the size of the bitmap.
+pub(super) const SUPERBLOCK_WORDS: usize = 16;
 
 /// A sparse, 2-level bitmap with a low memory footprint, optimised for reads.
 ///
@@ -30,10 +48,43 @@ use super::{bitmask_for_key, index_for_key, vec::VecBitmap};
 /// This amortised `O(1)` insert operation takes ~4ns, while reading a value
 /// takes a constant time ~1ns on a Core i7 @ 2.60GHz.
 ///
+/// The physical blocks are stored in segments, each holding the allocated
+/// blocks for a superblock of 16 block map words (1024 blocks). Finding the
+/// physical offset of a block counts the set bits in (at most) one superblock,
+/// and allocating a new block only shifts the blocks within its own segment,
+/// keeping both lookups and inserts constant time as the bitmap grows. A
+/// segment is allocated when the first block in its superblock is, so an empty
+/// superblock costs 4 bytes.
+///
 /// In practice inserting large numbers of values into a [`CompressedBitmap`]
 /// can be slow - for higher write performance, use a [`VecBitmap`] and later
 /// convert to a [`CompressedBitmap`] when possible.
 ///
+/// ## Set Operations
+///
+/// Bitmaps with the same `max_key` can be combined with the bitwise operators,
+/// either allocating a new bitmap or in-place:
+///
+/// ```rust
+/// use bloom2::CompressedBitmap;
+///
+/// let mut a = CompressedBitmap::new(1024);
+/// a.set(1, true);
+/// a.set(2, true);
+///
+/// let mut b = CompressedBitmap::new(1024);
+/// b.set(2, true);
+/// b.set(3, true);
+///
+/// assert!((&a & &b).get(2));
+/// assert!(!(&a - &b).get(2));
+///
+/// a ^= &b;
+/// assert!(a.get(1) && !a.get(2) && a.get(3));
+/// ```
+///
+/// Blocks left empty by an operation are not allocated in the result.
+///
 /// ## Features
 ///
 /// If the `serde` feature is enabled, a `CompressedBitmap` supports
@@ -41,13 +92,22 @@ use super::{bitmask_for_key, index_for_key, vec::VecBitmap};
 ///
 /// [serde]: https://github.com/serde-rs/serde
 #[derive(Debug, Clone, PartialEq, Eq)]
-#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(try_from = "CompressedBitmapData")
+)]
 pub struct CompressedBitmap {
     /// LSB is 0.
     block_map: Vec<usize>,
-    bitmap: Vec<usize>,
 
-    #[cfg(debug_assertions)]
+    /// The allocated blocks, segmented by superblock.
+    ///
+    /// Segment N contains the allocated blocks for the block map words in the
+    /// range `N * SUPERBLOCK_WORDS..(N + 1) * SUPERBLOCK_WORDS`, in order.
+    bitmap: Segments,
+
+    /// The maximum key this bitmap was sized to hold.
     max_key: usize,
 }
 
@@ -55,16 +115,7 @@ impl CompressedBitmap {
     /// Construct a `CompressedBitmap` for space to hold up to `max_key` number
     /// of bits.
     pub fn new(max_key: usize) -> Self {
-        // Calculate how many instances of usize (blocks) are needed to hold
-        // max_key number of bits.
-        let blocks = index_for_key(max_key);
-
-        // Figure out how many usize elements are needed to represent blocks
-        // number of bitmaps.
-        let num_blocks = match blocks % (u64::BITS as usize) {
-            0 => index_for_key(blocks),
-            _ => index_for_key(blocks) + 1, // +1 to cover the remainder
-        };
+        let num_blocks = block_map_len(max_key);
 
         // Allocate a block map.
         //
@@ -73,17 +124,32 @@ impl CompressedBitmap {
         let block_map = vec![0; num_blocks];
 
         CompressedBitmap {
-            bitmap: Vec::new(),
+            bitmap: Segments::new(num_segments(num_blocks)),
             block_map,
+            max_key,
+        }
+    }
 
-            #[cfg(debug_assertions)]
+    /// Construct a `CompressedBitmap` from the `block_map` and the segments of
+    /// blocks it maps.
+    pub(super) fn from_parts(
+        block_map: Vec<usize>,
+        bitmap: Vec<Vec<usize>>,
+        max_key: usize,
+    ) -> Self {
+        debug_assert_eq!(block_map.len(), block_map_len(max_key));
+        debug_assert_eq!(bitmap.len(), num_segments(block_map.len()));
+
+        Self {
+            block_map,
+            bitmap: Segments::from_vec(bitmap),
             max_key,
         }
     }
 
     pub fn size(&self) -> usize {
         (self.block_map.capacity() * std::mem::size_of::<usize>())
-            + (self.bitmap.capacity() * std::mem::size_of::<usize>())
+            + self.bitmap.size()
             + std::mem::size_of_val(self)
     }
 
@@ -109,7 +175,7 @@ impl CompressedBitmap {
         for block in self.block_map.iter_mut() {
             *block = 0;
         }
-        self.bitmap.truncate(0);
+        self.bitmap.clear();
     }
 
     /// Inserts `key` into the bitmap.
@@ -124,7 +190,6 @@ impl CompressedBitmap {
     /// values of `key` that are only slightly larger than `max_key` for
     /// performance reasons.
     pub fn set(&mut self, key: usize, value: bool) {
-        #[cfg(debug_assertions)]
         debug_assert!(key <= self.max_key, "key {} > {} max", key, self.max_key);
 
         // First compute the index of the bit in the bitmap if it was fully
@@ -198,23 +263,14 @@ impl CompressedBitmap {
         // In the above example, the popcount() is 3, and the block is the
         // 3+1=4th block in bitmap. However as the arrays are zero-indexed,
         // the +1 is omitted to adjust from the position 4, to index 3.
-
-        // Count the ones in the full blocks.
         //
-        // This could chain() the final masked count_ones() call below using
-        // once_with, and while more readable, it is unfortunately measurably
-        // slower in practice.
-        let offset: usize = (0..block_map_index)
-            .map(|i| self.block_map[i].count_ones() as usize)
-            .sum();
-
-        // Mask out the higher bits in the block map to count the populated
-        // blocks before block_index
-        let mask = block_map_bitmask - 1;
-        let offset = offset + (self.block_map[block_map_index] & mask).count_ones() as usize;
-
-        // Offset now contains the index in bitmap at which block_index can
-        // be found.
+        // Rather than counting every block map word preceding block_index,
+        // only the words in the enclosing superblock are counted to find the
+        // offset within that superblock's segment.
+        let (segment_index, offset) = physical_offset(|i| self.block_map[i], block_index);
+
+        // Offset now contains the index in the segment at which block_index
+        // can be found.
         //
         // Because the blocks are lazily initialised, there may not yet be a
         // block for block_map_index.
@@ -227,28 +283,26 @@ impl CompressedBitmap {
                 return;
             }
 
-            // The block does not exist, insert it into the bitmap at
-            // block_index.
-            if offset >= self.bitmap.len() {
-                self.bitmap.push(bitmask_for_key(key));
-            } else {
-                // If offset is < bitmap.len() this will require moving all
-                // the elements at offset+1 one slot to the right to make
-                // room for the new element.
-                //
-                // For bitmaps with large numbers of elements to the right
-                // of offset, this can become expensive.
-                self.bitmap.insert(offset, bitmask_for_key(key));
-            }
+            // The block does not exist, insert it into the segment at
+            // offset, allocating the segment if this is the first block in
+            // its superblock.
+            //
+            // If offset is < segment.len() this will require moving all the
+            // elements at offset+1 one slot to the right to make room for the
+            // new element, but never more than the blocks of one superblock.
+            self.bitmap
+                .get_mut(segment_index)
+                .insert(offset, bitmask_for_key(key));
             self.block_map[block_map_index] |= block_map_bitmask;
             return;
         }
 
         // Otherwise the block map indicates the block is already allocated
+        let segment = self.bitmap.get_mut(segment_index);
         if value {
-            self.bitmap[offset] |= bitmask_for_key(key);
+            segment[offset] |= bitmask_for_key(key);
         } else {
-            self.bitmap[offset] &= !bitmask_for_key(key);
+            segment[offset] &= !bitmask_for_key(key);
         }
     }
 
@@ -269,14 +323,229 @@ impl CompressedBitmap {
             return false;
         }
 
-        let offset: usize = (0..block_map_index)
-            .map(|i| self.block_map[i].count_ones() as usize)
-            .sum();
+        let (segment, offset) = physical_offset(|i| self.block_map[i], block_index);
 
-        let mask = block_map_bitmask - 1;
-        let offset: usize = offset + (self.block_map[block_map_index] & mask).count_ones() as usize;
+        self.bitmap.get(segment)[offset] & bitmask_for_key(key) != 0
+    }
 
-        self.bitmap[offset] & bitmask_for_key(key) != 0
+    /// Write the bitmap to `w` in the layout read by a
+    /// [`CompressedBitmapRef`], allowing it to be queried without
+    /// deserialising it (for example, by memory mapping a file).
+    ///
+    /// This method writes many small values - using a buffered writer such as
+    /// [`BufWriter`](std::io::BufWriter) is recommended.
+    ///
+    /// [`CompressedBitmapRef`]: crate::CompressedBitmapRef
+    pub fn write_to<W: Write>(&self, mut w: W) -> std::io::Result<()> {
+        let num_blocks = self.bitmap.iter().map(<[usize]>::len).sum::<usize>();
+
+        // The rank directory contains the number of blocks preceding each
+        // segment.
+        let rank = self.bitmap.iter().scan(0, |total, segment| {
+            let preceding = *total;
+            *total += segment.len();
+            Some(preceding)
+        });
+
+        let header = [self.block_map.len(), num_blocks];
+        let words = header
+            .iter()
+            .chain(&self.block_map)
+            .copied()
+            .chain(rank)
+            .chain(self.bitmap.iter().flatten().copied());
+
+        for word in words {
+            w.write_all(&(word as u64).to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns an iterator of the keys set to `true`, in ascending order.
+    ///
+    /// Only the allocated blocks are visited - unallocated blocks are skipped
+    /// using the block map.
+    ///
+    /// ```rust
+    /// use bloom2::CompressedBitmap;
+    ///
+    /// let mut b = CompressedBitmap::new(1024);
+    /// b.set(42, true);
+    /// b.set(7, true);
+    /// b.set(1000, true);
+    ///
+    /// assert_eq!(b.iter_ones().collect::<Vec<_>>(), vec![7, 42, 1000]);
+    /// assert_eq!(b.iter_ones().rev().collect::<Vec<_>>(), vec![1000, 42, 7]);
+    /// ```
+    pub fn iter_ones(&self) -> impl DoubleEndedIterator<Item = usize> + '_ {
+        self.blocks()
+            .flat_map(|(idx, block)| WordOnes::new(idx, block))
+    }
+
+    /// Returns an iterator of `(logical_block_index, word)` pairs for each
+    /// non-empty block of `usize` bits, in ascending order of block index.
+    ///
+    /// Block `N` holds the bits for keys `N * usize::BITS` to
+    /// `(N + 1) * usize::BITS - 1`, with the LSB being the smallest key.
+    pub fn blocks(&self) -> impl DoubleEndedIterator<Item = (usize, usize)> + '_ {
+        Blocks::new(self)
+    }
+
+    /// Return the number of bits set to `true` for keys less than `key`.
+    ///
+    /// The set bits are counted when queried rather than maintained by
+    /// [`set()`](CompressedBitmap::set), so this is `O(n)` in the number of
+    /// allocated blocks preceding `key`.
+    ///
+    /// ```rust
+    /// use bloom2::CompressedBitmap;
+    ///
+    /// let mut b = CompressedBitmap::new(1024);
+    /// b.set(7, true);
+    /// b.set(42, true);
+    ///
+    /// assert_eq!(b.rank(7), 0);
+    /// assert_eq!(b.rank(8), 1);
+    /// assert_eq!(b.rank(1024), 2);
+    /// ```
+    pub fn rank(&self, key: usize) -> usize {
+        let block_index = index_for_key(key);
+        if index_for_key(block_index) >= self.block_map.len() {
+            return self.count_ones();
+        }
+
+        let (segment, offset) = physical_offset(|i| self.block_map[i], block_index);
+
+        // Count the bits in all the allocated blocks preceding the block for
+        // key, which are ordered by their logical block index.
+        let preceding = (0..segment)
+            .flat_map(|s| self.bitmap.get(s))
+            .chain(&self.bitmap.get(segment)[..offset])
+            .map(|v| v.count_ones() as usize)
+            .sum::<usize>();
+
+        // And the bits below key in its own block, if allocated.
+        let block_map_index = index_for_key(block_index);
+        if self.block_map[block_map_index] & bitmask_for_key(block_index) == 0 {
+            return preceding;
+        }
+        let block = self.bitmap.get(segment)[offset];
+        preceding + (block & (bitmask_for_key(key) - 1)).count_ones() as usize
+    }
+
+    /// Return the key of the `n`th (zero-indexed) bit set to `true`, or
+    /// [`None`] if fewer than `n + 1` bits are set.
+    ///
+    /// This is `O(n)` in the number of allocated blocks preceding the returned
+    /// key. Segments are skipped by counting the bits set in their blocks, and
+    /// only the block map of the segment holding the key is read.
+    ///
+    /// ```rust
+    /// use bloom2::CompressedBitmap;
+    ///
+    /// let mut b = CompressedBitmap::new(1024);
+    /// b.set(7, true);
+    /// b.set(42, true);
+    ///
+    /// assert_eq!(b.select(0), Some(7));
+    /// assert_eq!(b.select(1), Some(42));
+    /// assert_eq!(b.select(2), None);
+    /// ```
+    pub fn select(&self, mut n: usize) -> Option<usize> {
+        for (superblock, segment) in self.bitmap.iter().enumerate() {
+            let ones = segment
+                .iter()
+                .map(|v| v.count_ones() as usize)
+                .sum::<usize>();
+            if n >= ones {
+                n -= ones;
+                continue;
+            }
+
+            for (idx, block) in self.superblock_blocks(superblock) {
+                let ones = block.count_ones() as usize;
+                if n < ones {
+                    return WordOnes::new(idx, block).nth(n);
+                }
+                n -= ones;
+            }
+        }
+        None
+    }
+
+    /// Return the smallest key greater than or equal to `key` that is set to
+    /// `true`, or [`None`] if there is no such key.
+    ///
+    /// Unallocated blocks are skipped using the block map.
+    ///
+    /// ```rust
+    /// use bloom2::CompressedBitmap;
+    ///
+    /// let mut b = CompressedBitmap::new(1024);
+    /// b.set(7, true);
+    /// b.set(42, true);
+    ///
+    /// assert_eq!(b.next_set(7), Some(7));
+    /// assert_eq!(b.next_set(8), Some(42));
+    /// assert_eq!(b.next_set(43), None);
+    /// ```
+    pub fn next_set(&self, key: usize) -> Option<usize> {
+        if self.block_map.is_empty() {
+            return None;
+        }
+
+        let last = self.block_map.len() * usize::BITS as usize - 1;
+        let block_index = index_for_key(key);
+        if block_index > last {
+            return None;
+        }
+
+        Blocks::range(self, block_index, last)
+            .flat_map(|(idx, block)| WordOnes::new(idx, block))
+            .find(|&v| v >= key)
+    }
+
+    /// Return the largest key less than or equal to `key` that is set to
+    /// `true`, or [`None`] if there is no such key.
+    ///
+    /// Unallocated blocks are skipped using the block map.
+    ///
+    /// ```rust
+    /// use bloom2::CompressedBitmap;
+    ///
+    /// let mut b = CompressedBitmap::new(1024);
+    /// b.set(7, true);
+    /// b.set(42, true);
+    ///
+    /// assert_eq!(b.prev_set(42), Some(42));
+    /// assert_eq!(b.prev_set(41), Some(7));
+    /// assert_eq!(b.prev_set(6), None);
+    /// ```
+    pub fn prev_set(&self, key: usize) -> Option<usize> {
+        if self.block_map.is_empty() {
+            return None;
+        }
+
+        let last = self.block_map.len() * usize::BITS as usize - 1;
+        let block_index = index_for_key(key).min(last);
+
+        Blocks::range(self, 0, block_index)
+            .rev()
+            .flat_map(|(idx, block)| WordOnes::new(idx, block).rev())
+            .find(|&v| v <= key)
+    }
+
+    /// Return the number of bits set to `true`.
+    ///
+    /// Only the allocated blocks are counted - this is `O(n)` in the number of
+    /// populated blocks, rather than the size of the bitmap.
+    pub fn count_ones(&self) -> usize {
+        self.bitmap
+            .iter()
+            .flatten()
+            .map(|v| v.count_ones() as usize)
+            .sum()
     }
 
     /// Perform a bitwise OR against `self` and `other`, returning the
@@ -287,122 +556,335 @@ impl CompressedBitmap {
     /// This method panics if `other` was not configured with the same
     /// `max_key`.
     pub fn or(&self, other: &Self) -> Self {
-        #[cfg(debug_assertions)]
+        let mut v = self.clone();
+        v.or_assign(other);
+        v
+    }
+
+    /// Perform a bitwise AND against `self` and `other`, returning the
+    /// resulting intersection [`CompressedBitmap`].
+    ///
+    /// Blocks that contain no set bits after the AND are not allocated in the
+    /// result, keeping it compressed.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `other` was not configured with the same
+    /// `max_key`.
+    pub fn and(&self, other: &Self) -> Self {
+        let mut v = self.clone();
+        v.and_assign(other);
+        v
+    }
+
+    /// Perform a bitwise XOR against `self` and `other`, returning a
+    /// [`CompressedBitmap`] containing the bits set in exactly one of them.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `other` was not configured with the same
+    /// `max_key`.
+    pub fn xor(&self, other: &Self) -> Self {
+        let mut v = self.clone();
+        v.xor_assign(other);
+        v
+    }
+
+    /// Return a [`CompressedBitmap`] containing the bits set in `self` that
+    /// are not set in `other` (the difference of `self` and `other`).
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `other` was not configured with the same
+    /// `max_key`.
+    pub fn and_not(&self, other: &Self) -> Self {
+        let mut v = self.clone();
+        v.and_not_assign(other);
+        v
+    }
+
+    /// Perform an in-place bitwise OR of `other` into `self`.
+    ///
+    /// See [`CompressedBitmap::or()`].
+    pub fn or_assign(&mut self, other: &Self) {
+        self.apply(other, |l, r| l | r)
+    }
+
+    /// Perform an in-place bitwise AND of `other` into `self`.
+    ///
+    /// See [`CompressedBitmap::and()`].
+    pub fn and_assign(&mut self, other: &Self) {
+        self.apply(other, |l, r| l & r)
+    }
+
+    /// Perform an in-place bitwise XOR of `other` into `self`.
+    ///
+    /// See [`CompressedBitmap::xor()`].
+    pub fn xor_assign(&mut self, other: &Self) {
+        self.apply(other, |l, r| l ^ r)
+    }
+
+    /// Clear the bits set in `other` from `self`, in-place.
+    ///
+    /// See [`CompressedBitmap::and_not()`].
+    pub fn and_not_assign(&mut self, other: &Self) {
+        self.apply(other, |l, r| l & !r)
+    }
+
+    /// Combine each logical block of `self` with the same logical block in
+    /// `other` using `op`, storing the result in `self`.
+    ///
+    /// Only the superblocks with blocks allocated in either bitmap are visited,
+    /// and any block that is all zero after applying `op` is dropped, keeping
+    /// the result compressed. `op(0, 0)` MUST return 0.
+    fn apply<F>(&mut self, other: &Self, op: F)
+    where
+        F: Fn(usize, usize) -> usize,
+    {
         debug_assert_eq!(self.max_key, other.max_key);
 
-        // Invariant: the block maps are of equal length, meaning the zipped
-        // iters yield both sides to completion.
+        // Invariant: the block maps are of equal length, meaning both bitmaps
+        // have the same superblocks.
         assert_eq!(self.block_map.len(), other.block_map.len());
 
-        let left = BlockMapIter::new(self);
-        let right = BlockMapIter::new(other);
-
-        // Construct the physical set of compressed bitmap blocks.
-        //
-        // By iterating over the non-empty logical blocks and OR-ing them
-        // together (or picking one if only one is non-empty) the merged output
-        // of both compressed bitmaps is computed (itself compressed).
-        let bitmap = left
-            .zip(right)
-            .filter_map(|(l, r)| {
-                Some(match (l, r) {
-                    (None, None) => return None,
-                    (None, Some(r)) => other.bitmap[r],
-                    (Some(l), None) => self.bitmap[l],
-                    (Some(l), Some(r)) => self.bitmap[l] | other.bitmap[r],
-                })
-            })
-            .collect::<Vec<_>>();
-
-        // Then merge the two bitmap blocks, the OR of which is guaranteed to
-        // contain exactly N set bits for the N blocks in "physical".
-        let block_map = self
-            .block_map
-            .iter()
-            .zip(&other.block_map)
-            .map(|(l, r)| l | r)
-            .collect::<Vec<_>>();
-
-        // Invariant: The number of set bits in the block map must match the
-        // number of blocks in the bitmap.
-        debug_assert_eq!(
-            block_map.iter().map(|v| v.count_ones()).sum::<u32>() as usize,
-            bitmap.len()
-        );
+        for superblock in 0..self.bitmap.len() {
+            if self.bitmap.get(superblock).is_empty() && other.bitmap.get(superblock).is_empty() {
+                continue;
+            }
 
-        Self {
-            block_map,
-            bitmap,
+            let (words, segment) = {
+                // Merge the allocated blocks of both superblocks, each ordered
+                // by their logical block index.
+                let mut left = self.superblock_blocks(superblock).peekable();
+                let mut right = other.superblock_blocks(superblock).peekable();
+
+                let first_block = superblock * SUPERBLOCK_WORDS * usize::BITS as usize;
+                let mut words = [0; SUPERBLOCK_WORDS];
+                let mut segment = Vec::new();
+                loop {
+                    let (idx, l, r) = match (left.peek().copied(), right.peek().copied()) {
+                        (None, None) => break,
+                        (Some((li, l)), Some((ri, r))) if li == ri => {
+                            left.next();
+                            right.next();
+                            (li, l, r)
+                        }
+                        (Some((li, l)), Some((ri, _))) if li < ri => {
+                            left.next();
+                            (li, l, 0)
+                        }
+                        (Some((li, l)), None) => {
+                            left.next();
+                            (li, l, 0)
+                        }
+                        (_, Some((ri, r))) => {
+                            right.next();
+                            (ri, 0, r)
+                        }
+                    };
+
+                    let block = op(l, r);
+                    if block == 0 {
+                        continue;
+                    }
+
+                    words[index_for_key(idx - first_block)] |= bitmask_for_key(idx);
+                    segment.push(block);
+                }
+
+                (words, segment)
+            };
+
+            let start = superblock * SUPERBLOCK_WORDS;
+            let end = (start + SUPERBLOCK_WORDS).min(self.block_map.len());
+            self.block_map[start..end].copy_from_slice(&words[..end - start]);
+
+            self.bitmap.replace(superblock, segment);
+        }
+    }
 
-            #[cfg(debug_assertions)]
-            max_key: self.max_key,
+    /// Return the value at `key`, resolving the block offset with `cursor`.
+    #[inline(always)]
+    fn get_with(&self, cursor: &mut OffsetCursor, key: usize) -> bool {
+        let block_index = index_for_key(key);
+        if self.block_map[index_for_key(block_index)] & bitmask_for_key(block_index) == 0 {
+            return false;
         }
+
+        let (segment, offset) = cursor.physical_offset(&self.block_map, block_index);
+        self.bitmap.get(segment)[offset] & bitmask_for_key(key) != 0
+    }
+
+    /// Yields the logical block index and value of each allocated block in
+    /// `superblock`, in order.
+    fn superblock_blocks(&self, superblock: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let start = superblock * SUPERBLOCK_WORDS;
+        let end = (start + SUPERBLOCK_WORDS).min(self.block_map.len());
+
+        (start..end)
+            .flat_map(move |i| WordOnes::new(i, self.block_map[i]))
+            .zip(self.bitmap.get(superblock).iter().copied())
     }
 }
 
-/// Yields the 0-indexed physical indexes into the sparse bitmap for non-empty
-/// blocks.
+/// Yields the logical block index and value of each non-empty block in a
+/// [`CompressedBitmap`], in ascending order from the front and descending
+/// order from the back.
 ///
-/// If for the Nth call to `next()` the Nth sparse bitmap block is elided,
-/// [`None`] is returned. If the Nth bitmap block is non-empty, the physical
-/// index into the compressed vec is yielded.
-#[derive(Debug)]
-struct BlockMapIter<'a> {
-    bitmap: &'a CompressedBitmap,
-
-    /// The index into bitmap.block_map to be processed next (0 -> N).
-    block_idx: usize,
-    /// The bit in the block to be evaluated next (LSB -> MSB).
-    block_bit: u8,
-    /// The physical index to be yielded next.
-    physical_idx: usize,
+/// The front and back cursors each track the block map word they are
+/// consuming, the bits of that word not yet yielded, and the physical offset of
+/// the next block within the segment for that word. When both cursors reach
+/// the same word, the bits remaining are those not yet consumed by either.
+#[derive(Debug, Clone)]
+struct Blocks<'a> {
+    block_map: &'a [usize],
+    bitmap: &'a Segments,
+
+    front_word: usize,
+    front_bits: usize,
+    /// The physical offset of the next block yielded from the front.
+    front_offset: usize,
+
+    back_word: usize,
+    back_bits: usize,
+    /// One past the physical offset of the next block yielded from the back.
+    back_offset: usize,
 }
 
-impl<'a> BlockMapIter<'a> {
-    /// Construct a new [`BlockMapIter`] that yields indexes into the physical
-    /// bitmap blocks in `bitmap`.
+impl<'a> Blocks<'a> {
     fn new(bitmap: &'a CompressedBitmap) -> Self {
+        match bitmap.block_map.len() {
+            0 => Self {
+                block_map: &bitmap.block_map,
+                bitmap: &bitmap.bitmap,
+                front_word: 0,
+                front_bits: 0,
+                front_offset: 0,
+                back_word: 0,
+                back_bits: 0,
+                back_offset: 0,
+            },
+            n => Self::range(bitmap, 0, n * usize::BITS as usize - 1),
+        }
+    }
+
+    /// Yield the non-empty blocks with a logical block index in the inclusive
+    /// range `first..=last`.
+    fn range(bitmap: &'a CompressedBitmap, first: usize, last: usize) -> Self {
+        let block_map = |i| bitmap.block_map[i];
+
+        // Mask out the blocks outside of the range in the first and last
+        // words.
+        let front_word = index_for_key(first);
+        let front_bits = block_map(front_word) & !(bitmask_for_key(first) - 1);
+
+        let back_word = index_for_key(last);
+        let back_bits =
+            block_map(back_word) & ((bitmask_for_key(last) - 1) | bitmask_for_key(last));
+
+        // The physical offset of the first block in the range, and one past
+        // the physical offset of the last.
+        let (_, front_offset) = physical_offset(block_map, first);
+        let (_, back_offset) = physical_offset(block_map, last);
+        let back_offset = back_offset + (back_bits & bitmask_for_key(last) != 0) as usize;
+
         Self {
-            bitmap,
-            block_idx: 0,
-            block_bit: 0,
-            physical_idx: 0,
+            block_map: &bitmap.block_map,
+            bitmap: &bitmap.bitmap,
+            front_word,
+            front_bits,
+            front_offset,
+            back_word,
+            back_bits,
+            back_offset,
+        }
+    }
+
+    /// Return the bits of the front word not yet yielded from either end.
+    fn front_mask(&self) -> usize {
+        if self.front_word == self.back_word {
+            self.front_bits & self.back_bits
+        } else {
+            self.front_bits
+        }
+    }
+
+    /// Return the bits of the back word not yet yielded from either end.
+    fn back_mask(&self) -> usize {
+        if self.front_word == self.back_word {
+            self.front_bits & self.back_bits
+        } else {
+            self.back_bits
         }
     }
 }
 
-impl Iterator for BlockMapIter<'_> {
-    type Item = Option<usize>;
+impl Iterator for Blocks<'_> {
+    type Item = (usize, usize);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let block = self.bitmap.block_map.get(self.block_idx)?;
+        loop {
+            let mask = self.front_mask();
+            if mask != 0 {
+                let bit = mask.trailing_zeros() as usize;
+                self.front_bits &= !(1 << bit);
+
+                let block = self.bitmap.get(self.front_word / SUPERBLOCK_WORDS)[self.front_offset];
+                self.front_offset += 1;
+
+                // Blocks may remain allocated after all their bits are unset.
+                if block == 0 {
+                    continue;
+                }
+                return Some((self.front_word * usize::BITS as usize + bit, block));
+            }
 
-        let v = if (block & (1 << self.block_bit)) > 0 {
-            // This logical block is non-empty.
+            if self.front_word >= self.back_word {
+                return None;
+            }
 
-            // Read the physical index for the nth logical block.
-            let idx = self.physical_idx;
+            self.front_word += 1;
+            self.front_bits = self.block_map[self.front_word];
 
-            // Increment for the next physical block.
-            self.physical_idx += 1;
+            // Physical blocks are indexed from the start of each segment.
+            if self.front_word.is_multiple_of(SUPERBLOCK_WORDS) {
+                self.front_offset = 0;
+            }
+        }
+    }
+}
 
-            Some(idx)
-        } else {
-            // This logical block is empty.
-            None
-        };
+impl DoubleEndedIterator for Blocks<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            let mask = self.back_mask();
+            if mask != 0 {
+                let bit = (usize::BITS - 1 - mask.leading_zeros()) as usize;
+                self.back_bits &= !(1 << bit);
+
+                self.back_offset -= 1;
+                let block = self.bitmap.get(self.back_word / SUPERBLOCK_WORDS)[self.back_offset];
+
+                // Blocks may remain allocated after all their bits are unset.
+                if block == 0 {
+                    continue;
+                }
+                return Some((self.back_word * usize::BITS as usize + bit, block));
+            }
 
-        // Advance the bit within the block to evaluate next.
-        self.block_bit += 1;
+            if self.back_word <= self.front_word {
+                return None;
+            }
 
-        // Advance the block index (and wrap the bit index) if the last
-        // inspected bit was the last bit in the block.
-        if self.block_bit == usize::BITS as u8 {
-            self.block_bit = 0;
-            self.block_idx += 1;
-        }
+            // Physical blocks are indexed from the end of each segment when
+            // moving into the preceding superblock.
+            if self.back_word.is_multiple_of(SUPERBLOCK_WORDS) {
+                self.back_offset = self.bitmap.get(self.back_word / SUPERBLOCK_WORDS - 1).len();
+            }
 
-        Some(v)
+            self.back_word -= 1;
+            self.back_bits = self.block_map[self.back_word];
+        }
     }
 }
 
@@ -415,38 +897,146 @@ impl Bitmap for CompressedBitmap {
         self.set(key, value)
     }
 
+    fn set_all<I>(&mut self, keys: I)
+    where
+        I: IntoIterator<Item = usize>,
+    {
+        let mut cursor = OffsetCursor::new();
+        for key in keys {
+            debug_assert!(key <= self.max_key, "key {} > {} max", key, self.max_key);
+
+            let block_index = index_for_key(key);
+            let block_map_index = index_for_key(block_index);
+            let (segment, offset) = cursor.physical_offset(&self.block_map, block_index);
+
+            let blocks = self.bitmap.get_mut(segment);
+            if self.block_map[block_map_index] & bitmask_for_key(block_index) == 0 {
+                blocks.insert(offset, bitmask_for_key(key));
+                self.block_map[block_map_index] |= bitmask_for_key(block_index);
+                cursor.allocated(block_index);
+            } else {
+                blocks[offset] |= bitmask_for_key(key);
+            }
+        }
+    }
+
+    fn get_all<I>(&self, keys: I) -> bool
+    where
+        I: IntoIterator<Item = usize>,
+    {
+        let mut cursor = OffsetCursor::new();
+        keys.into_iter().all(|key| self.get_with(&mut cursor, key))
+    }
+
+    fn get_many(&self, keys: &[usize], out: &mut [bool]) {
+        let mut cursor = OffsetCursor::new();
+        for (key, out) in keys.iter().zip(out) {
+            *out = self.get_with(&mut cursor, *key);
+        }
+    }
+
+    fn prefetch(&self, key: usize) {
+        // The physical block cannot be located without first reading the block
+        // map words preceding it in its superblock, and the segment index.
+        let block_map_index = index_for_key(index_for_key(key));
+        if block_map_index >= self.block_map.len() {
+            return;
+        }
+
+        let superblock = block_map_index / SUPERBLOCK_WORDS;
+        prefetch(&self.block_map[superblock * SUPERBLOCK_WORDS]);
+        prefetch(&self.block_map[block_map_index]);
+        prefetch(&self.bitmap.index[superblock]);
+    }
+
     fn byte_size(&self) -> usize {
         self.size()
     }
 
+    fn count_ones(&self) -> usize {
+        self.count_ones()
+    }
+
     fn or(&self, other: &Self) -> Self {
         self.or(other)
     }
 
+    fn and(&self, other: &Self) -> Self {
+        self.and(other)
+    }
+
     fn new_with_capacity(max_key: usize) -> Self {
         Self::new(max_key)
     }
 }
 
+impl PortableBitmap for CompressedBitmap {
+    const KIND: u8 = 1;
+
+    fn write_words<W: Write>(&self, w: W) -> std::io::Result<()> {
+        self.write_to(w)
+    }
+
+    fn read_words<R: Read>(mut r: R, max_key: usize) -> Result<Self, FormatError> {
+        let num_words = read_usize(&mut r)?;
+        let num_blocks = read_usize(&mut r)?;
+
+        // Invariant: the block map is sized to hold max_key bits.
+        if num_words != block_map_len(max_key) {
+            return Err(LayoutError::FilterSize.into());
+        }
+
+        let rank_len = num_segments(num_words);
+        let to_usize = |v: Vec<u64>| v.into_iter().map(|v| v as usize).collect::<Vec<_>>();
+
+        let block_map = to_usize(read_words(&mut r, num_words)?);
+        let rank = to_usize(read_words(&mut r, rank_len)?);
+        validate_rank(|i| block_map[i], num_words, |i| rank[i], num_blocks)?;
+
+        let blocks = to_usize(read_words(&mut r, num_blocks)?);
+        let bitmap = into_segments(&block_map, blocks)?;
+
+        Ok(Self::from_parts(block_map, bitmap, max_key))
+    }
+}
+
+/// Construct a [`CompressedBitmap`] sized to hold the largest key yielded by
+/// the iterator, with every yielded key set to `true`.
+///
+/// The bitmap has a capacity of `max + 1` bits for the largest key `max`, as a
+/// [`VecBitmap`] collected from the same keys does.
+///
+/// ```rust
+/// use bloom2::CompressedBitmap;
+///
+/// let b: CompressedBitmap = vec![1, 42, 1000].into_iter().collect();
+/// assert!(b.get(42));
+/// ```
+impl std::iter::FromIterator<usize> for CompressedBitmap {
+    fn from_iter<I: IntoIterator<Item = usize>>(iter: I) -> Self {
+        let mut keys = iter.into_iter().collect::<Vec<_>>();
+
+        // Setting the keys in order appends each newly allocated block to the
+        // end of its segment.
+        keys.sort_unstable();
+
+        let mut b = Self::new_with_capacity(keys.last().map_or(0, |&k| k + 1));
+        for key in keys {
+            b.set(key, true);
+        }
+        b
+    }
+}
+
 impl From<VecBitmap> for CompressedBitmap {
     fn from(bitmap: VecBitmap) -> Self {
         let (bitmap, max_key) = bitmap.into_parts();
-
-        // Calculate how many instances of usize (blocks) are needed to hold
-        // max_key number of bits.
-        let num_blocks = index_for_key(max_key);
-
-        // Figure out how many usize elements are needed to represent blocks
-        // number of bitmaps.
-        let num_blocks = match num_blocks % (u64::BITS as usize) {
-            0 => index_for_key(num_blocks),
-            _ => index_for_key(num_blocks) + 1, // +1 to cover the remainder
-        };
+        let num_blocks = block_map_len(max_key);
 
         // Then shrink the bitmap into a 2-level compressed bitmap, dropping runs of
         // 0 bits in the raw bitmap.
         let mut block_map = vec![0; num_blocks];
-        let mut compressed = Vec::default();
+        let mut compressed = vec![Vec::new(); num_segments(num_blocks)];
         for (idx, block) in bitmap.into_iter().enumerate() {
             // If this block contains no set bits, it is elided from the compressed
             // representation.
@@ -458,17 +1048,333 @@ impl From<VecBitmap> for CompressedBitmap {
             //
             // Add the block to the compressed representation and mark it in the
             // block map.
-            compressed.push(block);
+            compressed[segment_for_block(idx)].push(block);
             block_map[index_for_key(idx)] |= bitmask_for_key(idx);
         }
 
-        CompressedBitmap {
-            block_map,
-            bitmap: compressed,
+        CompressedBitmap::from_parts(block_map, compressed, max_key)
+    }
+}
 
-            #[cfg(debug_assertions)]
-            max_key,
+/// Return the number of block map words needed to map the blocks holding
+/// `max_key` number of bits.
+pub(crate) fn block_map_len(max_key: usize) -> usize {
+    // Calculate how many instances of usize (blocks) are needed to hold
+    // max_key number of bits.
+    let blocks = max_key.div_ceil(u64::BITS as usize);
+
+    // Figure out how many usize elements are needed to represent blocks
+    // number of bitmaps, rounding up to cover the remainder.
+    blocks.div_ceil(u64::BITS as usize)
+}
+
+/// Return the number of segments needed to hold the blocks of a block map of
+/// `block_map_len` words.
+pub(super) fn num_segments(block_map_len: usize) -> usize {
+    block_map_len.div_ceil(SUPERBLOCK_WORDS)
+}
+
+/// Return the index of the segment holding the logical `block_index`.
+#[inline(always)]
+fn segment_for_block(block_index: usize) -> usize {
+    index_for_key(block_index) / SUPERBLOCK_WORDS
+}
+
+/// Split the flattened `blocks` into the segments for `block_map`.
+///
+/// Returns an error if the number of blocks does not match the number of
+/// blocks set in `block_map`.
+pub(super) fn into_segments<T, I>(
+    block_map: &[usize],
+    blocks: I,
+) -> Result<Vec<Vec<T>>, LayoutError>
+where
+    I: IntoIterator<Item = T>,
+{
+    let mut blocks = blocks.into_iter();
+    let segments = block_map
+        .chunks(SUPERBLOCK_WORDS)
+        .map(|superblock| {
+            let n = superblock
+                .iter()
+                .map(|v| v.count_ones() as usize)
+                .sum::<usize>();
+            let segment = blocks.by_ref().take(n).collect::<Vec<_>>();
+            if segment.len() != n {
+                return Err(LayoutError::RankDirectory);
+            }
+            Ok(segment)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if blocks.next().is_some() {
+        return Err(LayoutError::RankDirectory);
+    }
+
+    Ok(segments)
+}
+
+/// Return the segment holding the logical `block_index`, and the number of
+/// allocated blocks preceding it within that segment, which is the index of
+/// `block_index` in the segment if it has been allocated.
+///
+/// The `block_map` function returns the block map word at the given index,
+/// allowing the block map to be read from any backing storage.
+#[inline(always)]
+pub(super) fn physical_offset<F>(block_map: F, block_index: usize) -> (usize, usize)
+where
+    F: Fn(usize) -> usize,
+{
+    let block_map_index = index_for_key(block_index);
+    let superblock = block_map_index / SUPERBLOCK_WORDS;
+
+    // Count the ones in the full blocks of this superblock.
+    //
+    // This could chain() the final masked count_ones() call below using
+    // once_with, and while more readable, it is unfortunately measurably
+    // slower in practice.
+    let offset = (superblock * SUPERBLOCK_WORDS..block_map_index)
+        .map(|i| block_map(i).count_ones() as usize)
+        .sum::<usize>();
+
+    // Mask out the higher bits in the block map to count the populated
+    // blocks before block_index
+    let mask = bitmask_for_key(block_index) - 1;
+    let offset = offset + (block_map(block_map_index) & mask).count_ones() as usize;
+
+    (superblock, offset)
+}
+
+/// Resolves the physical offsets of a sequence of blocks, sharing the block
+/// map popcount work between blocks in the same superblock.
+///
+/// The cursor caches the number of allocated blocks preceding each word of the
+/// last superblock resolved, counting words only as far as a lookup requires.
+/// Resolving a block in the same superblock, in any order, reuses the cached
+/// counts - resolving a block in another superblock restarts the count.
+#[derive(Debug)]
+struct OffsetCursor {
+    superblock: usize,
+
+    /// `prefix[i]` is the number of allocated blocks in the first `i` words
+    /// of `superblock`, valid for `i <= counted`.
+    prefix: [usize; SUPERBLOCK_WORDS],
+    counted: usize,
+}
+
+impl OffsetCursor {
+    fn new() -> Self {
+        Self {
+            superblock: usize::MAX,
+            prefix: [0; SUPERBLOCK_WORDS],
+            counted: 0,
+        }
+    }
+
+    /// Return the `(segment, offset)` of `block_index`, as
+    /// [`physical_offset()`] does.
+    #[inline(always)]
+    fn physical_offset(&mut self, block_map: &[usize], block_index: usize) -> (usize, usize) {
+        let block_map_index = index_for_key(block_index);
+        let superblock = block_map_index / SUPERBLOCK_WORDS;
+        let word = block_map_index % SUPERBLOCK_WORDS;
+
+        if superblock != self.superblock {
+            self.superblock = superblock;
+            self.counted = 0;
         }
+
+        let start = superblock * SUPERBLOCK_WORDS;
+        while self.counted < word {
+            self.prefix[self.counted + 1] =
+                self.prefix[self.counted] + block_map[start + self.counted].count_ones() as usize;
+            self.counted += 1;
+        }
+
+        let mask = bitmask_for_key(block_index) - 1;
+        let offset = self.prefix[word] + (block_map[block_map_index] & mask).count_ones() as usize;
+
+        (superblock, offset)
+    }
+
+    /// Invalidate the counts following the word mapping `block_index`, after
+    /// allocating it.
+    fn allocated(&mut self, block_index: usize) {
+        let word = index_for_key(block_index) % SUPERBLOCK_WORDS;
+        self.counted = self.counted.min(word);
+    }
+}
+
+/// The physical blocks of a [`CompressedBitmap`], segmented by superblock.
+///
+/// A segment is allocated when a block is first inserted into its superblock,
+/// and the segments are held in the order they were allocated. Each superblock
+/// holds the position of its segment in a 4 byte index, so a superblock with
+/// no allocated blocks costs 4 bytes, rather than the size of an empty `Vec`.
+#[derive(Debug, Clone)]
+struct Segments {
+    /// One more than the position in `segments` of the segment for each
+    /// superblock, or 0 if no segment has been allocated.
+    index: Vec<u32>,
+    segments: Vec<Vec<usize>>,
+}
+
+impl Segments {
+    /// Construct the index for `n` superblocks, with no segments allocated.
+    fn new(n: usize) -> Self {
+        Self {
+            index: vec![0; n],
+            segments: Vec::new(),
+        }
+    }
+
+    /// Construct from the segment of every superblock, in order, allocating
+    /// only the non-empty segments.
+    fn from_vec(segments: Vec<Vec<usize>>) -> Self {
+        let mut v = Self::new(segments.len());
+        for (superblock, segment) in segments.into_iter().enumerate() {
+            v.replace(superblock, segment);
+        }
+        v
+    }
+
+    /// Return the number of superblocks.
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Return the blocks allocated in `superblock`.
+    #[inline(always)]
+    fn get(&self, superblock: usize) -> &[usize] {
+        match self.index[superblock] {
+            0 => &[],
+            i => &self.segments[i as usize - 1],
+        }
+    }
+
+    /// Return the segment for `superblock`, allocating it if necessary.
+    #[inline(always)]
+    fn get_mut(&mut self, superblock: usize) -> &mut Vec<usize> {
+        if self.index[superblock] == 0 {
+            self.segments.push(Vec::new());
+            self.index[superblock] =
+                u32::try_from(self.segments.len()).expect("segment count exceeds u32::MAX");
+        }
+        &mut self.segments[self.index[superblock] as usize - 1]
+    }
+
+    /// Replace the blocks of `superblock` with `segment`.
+    fn replace(&mut self, superblock: usize, segment: Vec<usize>) {
+        if segment.is_empty() && self.index[superblock] == 0 {
+            return;
+        }
+        *self.get_mut(superblock) = segment;
+    }
+
+    /// Yields the blocks allocated in each superblock, in order.
+    fn iter(&self) -> impl Iterator<Item = &[usize]> + '_ {
+        (0..self.len()).map(move |superblock| self.get(superblock))
+    }
+
+    /// Remove all blocks, retaining the allocated segments.
+    fn clear(&mut self) {
+        for segment in self.segments.iter_mut() {
+            segment.truncate(0);
+        }
+    }
+
+    fn shrink_to_fit(&mut self) {
+        for segment in self.segments.iter_mut() {
+            segment.shrink_to_fit();
+        }
+        self.segments.shrink_to_fit();
+        self.index.shrink_to_fit();
+    }
+
+    /// Return the heap size of the index and segments in bytes.
+    fn size(&self) -> usize {
+        (self.index.capacity() * std::mem::size_of::<u32>())
+            + (self.segments.capacity() * std::mem::size_of::<Vec<usize>>())
+            + self
+                .segments
+                .iter()
+                .map(|v| v.capacity() * std::mem::size_of::<usize>())
+                .sum::<usize>()
+    }
+}
+
+/// Segments are equal if they hold the same blocks for each superblock,
+/// regardless of the order they were allocated in.
+impl PartialEq for Segments {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl Eq for Segments {}
+
+/// The serialised form of a [`CompressedBitmap`], with the physical blocks of
+/// all segments flattened into a single sequence.
+///
+/// Prior versions omitted `max_key` from the serialised form in release
+/// builds. When absent, it is derived from the block map length.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct CompressedBitmapData {
+    block_map: Vec<usize>,
+    bitmap: Vec<usize>,
+
+    #[serde(default)]
+    max_key: Option<usize>,
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for CompressedBitmap {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+
+        /// Serialises the segments as a single flat sequence of blocks.
+        struct Flatten<'a>(&'a Segments);
+
+        impl serde::Serialize for Flatten<'_> {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                serializer.collect_seq(self.0.iter().flatten())
+            }
+        }
+
+        let mut s = serializer.serialize_struct("CompressedBitmap", 3)?;
+        s.serialize_field("block_map", &self.block_map)?;
+        s.serialize_field("bitmap", &Flatten(&self.bitmap))?;
+        s.serialize_field("max_key", &self.max_key)?;
+        s.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<CompressedBitmapData> for CompressedBitmap {
+    type Error = LayoutError;
+
+    fn try_from(v: CompressedBitmapData) -> Result<Self, Self::Error> {
+        // Default to the largest key the block map can address if the
+        // max_key is absent.
+        let max_key = v
+            .max_key
+            .unwrap_or_else(|| (v.block_map.len() * (u64::BITS as usize).pow(2)).saturating_sub(1));
+
+        // Invariant: the block map is sized to hold max_key bits.
+        if v.block_map.len() != block_map_len(max_key) {
+            return Err(LayoutError::Length);
+        }
+
+        let bitmap = into_segments(&v.block_map, v.bitmap)?;
+
+        Ok(Self::from_parts(v.block_map, bitmap, max_key))
     }
 }
 
@@ -528,36 +1434,66 @@ mod tests {
     }
 
     #[test]
-    fn test_block_map_iter() {
-        let mut bitmap = CompressedBitmap::new(i16::MAX as _);
+    fn test_superblock_blocks() {
+        let mut bitmap = CompressedBitmap::new(u32::MAX as usize >> 8);
         bitmap.set(1, true); // Block 0
-        bitmap.set(usize::BITS as usize * 4, true); // Block 4
-        bitmap.set(usize::BITS as usize * 64, true); // Block 64
-        bitmap.set(usize::BITS as usize * 65, true); // Block 65
-        bitmap.set(usize::BITS as usize * 128, true); // Block 128
-
-        let mut iter = BlockMapIter::new(&bitmap).enumerate();
-
-        assert_eq!(iter.next().unwrap(), (0, Some(0))); // The 0th block is non-empty and at physical index 0.
-        assert_eq!(iter.next().unwrap(), (1, None)); // The 1st block is all zero and elided.
-        assert_eq!(iter.next().unwrap(), (2, None)); // The 2nd block is all zero and elided.
-        assert_eq!(iter.next().unwrap(), (3, None)); // The 3rd block is all zero and elided.
-        assert_eq!(iter.next().unwrap(), (4, Some(1))); // The 4rd block is non-empty and at physical index 1.
-
-        // Filter out all the None entries, preserving the enumerated idx.
-        //
-        // This causes the iterator to yield (logical block, physical block).
-        let mut iter = iter.filter_map(|(idx, block)| block.map(|v| (idx, v)));
-
-        // Then the next non-empty blocks and their physical indexes:
-        assert_eq!(iter.next().unwrap(), (64, 2)); // The 64th block is non-empty and at physical index 2.
-        assert_eq!(iter.next().unwrap(), (65, 3)); // The 65th block is non-empty and at physical index 3.
-
-        // Finally the last bit!
-        assert_eq!(iter.next().unwrap(), (128, 4)); // The 128th block is non-empty and at physical index 4.
+        bitmap.set(usize::BITS as usize * 4 + 2, true); // Block 4
+        bitmap.set(usize::BITS as usize * 64 + 3, true); // Block 64
+        bitmap.set(usize::BITS as usize * 65 + 4, true); // Block 65
+        bitmap.set(usize::BITS as usize * 128 + 5, true); // Block 128
+        bitmap.set(usize::BITS as usize * 1024 + 6, true); // Block 1024 (segment 1)
+        bitmap.set(usize::BITS as usize * 2050 + 7, true); // Block 2050 (segment 2)
+
+        // The iterator yields (logical block, physical block) for each
+        // allocated block, eliding the all zero blocks.
+        let mut iter = (0..bitmap.bitmap.len()).flat_map(|s| bitmap.superblock_blocks(s));
+
+        assert_eq!(iter.next().unwrap(), (0, 1 << 1));
+        assert_eq!(iter.next().unwrap(), (4, 1 << 2));
+        assert_eq!(iter.next().unwrap(), (64, 1 << 3));
+        assert_eq!(iter.next().unwrap(), (65, 1 << 4));
+        assert_eq!(iter.next().unwrap(), (128, 1 << 5));
+
+        // Blocks in subsequent segments.
+        assert_eq!(iter.next().unwrap(), (1024, 1 << 6));
+        assert_eq!(iter.next().unwrap(), (2050, 1 << 7));
 
         // And the iterator should terminate.
         assert!(iter.next().is_none());
+
+        // Superblocks are yielded independently.
+        let got = bitmap.superblock_blocks(1).collect::<Vec<_>>();
+        assert_eq!(got, vec![(1024, 1 << 6)]);
+    }
+
+    #[test]
+    fn test_segments_allocated_on_demand() {
+        let max_key = u32::MAX as usize >> 8;
+        let keys = [
+            usize::BITS as usize * 4096 + 3,
+            1,
+            usize::BITS as usize * 1024 + 5,
+        ];
+
+        // Invariant: an empty bitmap allocates no segments.
+        let mut a = CompressedBitmap::new(max_key);
+        assert!(a.bitmap.segments.is_empty());
+
+        // Invariant: a segment is allocated for each superblock written to.
+        for key in keys {
+            a.set(key, true);
+        }
+        a.set(usize::BITS as usize * 8192, false);
+        assert_eq!(a.bitmap.segments.len(), keys.len());
+
+        // Invariant: bitmaps holding the same blocks are equal, regardless of
+        // the order their segments were allocated in.
+        let mut b = CompressedBitmap::new(max_key);
+        for key in keys.iter().rev() {
+            b.set(*key, true);
+        }
+        assert_ne!(a.bitmap.segments, b.bitmap.segments);
+        assert_eq!(a, b);
     }
 
     #[quickcheck]
@@ -585,6 +1521,66 @@ mod tests {
         }
     }
 
+    #[quickcheck]
+    fn test_and(mut a: Vec<u16>, mut b: Vec<u16>) {
+        a.truncate(10);
+        let mut bitmap_a = CompressedBitmap::new(u16::MAX.into());
+        for v in &a {
+            bitmap_a.set(*v as usize, true);
+        }
+
+        b.truncate(10);
+        let mut bitmap_b = CompressedBitmap::new(u16::MAX.into());
+        for v in &b {
+            bitmap_b.set(*v as usize, true);
+        }
+
+        let intersection = bitmap_a.and(&bitmap_b);
+
+        for i in 0..u16::MAX {
+            let want_hit = a.contains(&i) && b.contains(&i);
+            assert!(
+                intersection.get(i as usize) == want_hit,
+                "unexpected value {} want={:?}",
+                i,
+                want_hit
+            );
+        }
+
+        // Invariant: no empty blocks are allocated.
+        assert!(intersection.bitmap.iter().flatten().all(|&v| v != 0));
+        assert_eq!(
+            intersection
+                .block_map
+                .iter()
+                .map(|v| v.count_ones())
+                .sum::<u32>() as usize,
+            intersection
+                .bitmap
+                .iter()
+                .map(<[usize]>::len)
+                .sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn test_and_drops_empty_blocks() {
+        let mut a = CompressedBitmap::new(u32::MAX as usize >> 8);
+        a.set(1, true);
+        a.set(usize::BITS as usize * 1024 + 2, true);
+
+        // The second block overlaps, but shares no set bits.
+        let mut b = CompressedBitmap::new(u32::MAX as usize >> 8);
+        b.set(1, true);
+        b.set(usize::BITS as usize * 1024 + 3, true);
+
+        let got = a.and(&b);
+        assert_eq!(got.bitmap.iter().map(<[usize]>::len).sum::<usize>(), 1);
+        assert_eq!(got.block_map.iter().map(|v| v.count_ones()).sum::<u32>(), 1);
+        assert!(got.get(1));
+        assert!(!got.get(usize::BITS as usize * 1024 + 2));
+    }
+
     #[quickcheck]
     fn test_or(mut a: Vec<u16>, mut b: Vec<u16>) {
         a.truncate(10);
@@ -626,9 +1622,341 @@ mod tests {
         contains_only_truthy!(decoded, 100; 1, 3);
     }
 
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_segments() {
+        let mut b = CompressedBitmap::new(u32::MAX as usize >> 8);
+        b.set(1, true);
+        b.set(usize::BITS as usize * 1024, true);
+        b.set(usize::BITS as usize * 4096 + 1, true);
+
+        let encoded = serde_json::to_string(&b).unwrap();
+        let decoded: CompressedBitmap = serde_json::from_str(&encoded).unwrap();
+        assert_eq!(decoded, b);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_block_count_mismatch() {
+        let got = serde_json::from_str::<CompressedBitmap>(
+            r#"{"block_map":[3],"bitmap":[1],"max_key":100}"#,
+        );
+        assert!(got.is_err());
+
+        let got = serde_json::from_str::<CompressedBitmap>(
+            r#"{"block_map":[1],"bitmap":[1,2],"max_key":100}"#,
+        );
+        assert!(got.is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_max_key() {
+        let mut b = CompressedBitmap::new(100);
+        b.set(10, true);
+
+        // Invariant: the serialised form is identical across build profiles.
+        let encoded = serde_json::to_string(&b).unwrap();
+        assert_eq!(
+            encoded,
+            r#"{"block_map":[1],"bitmap":[1024],"max_key":100}"#
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_missing_max_key() {
+        // Payloads serialised by release builds of prior versions omit the
+        // max_key.
+        let got: CompressedBitmap =
+            serde_json::from_str(r#"{"block_map":[1],"bitmap":[1024]}"#).unwrap();
+        contains_only_truthy!(got, 100; 10);
+        assert_eq!(got.max_key, 4095);
+
+        // A max_key that does not match the block map is rejected.
+        let got = serde_json::from_str::<CompressedBitmap>(
+            r#"{"block_map":[1],"bitmap":[1024],"max_key":5000}"#,
+        );
+        assert!(got.is_err());
+    }
+
     const MAX_KEY: usize = 1028;
 
+    #[quickcheck]
+    fn test_from_iter(values: Vec<u16>) {
+        let b = values
+            .iter()
+            .map(|&v| v as usize)
+            .collect::<CompressedBitmap>();
+
+        // Invariant: the bitmap is sized for the largest value.
+        let max = values.iter().map(|&v| v as usize + 1).max().unwrap_or(0);
+        assert_eq!(b.max_key, max);
+
+        let mut want = values.iter().map(|&v| v as usize).collect::<Vec<_>>();
+        want.sort_unstable();
+        want.dedup();
+        assert_eq!(b.iter_ones().collect::<Vec<_>>(), want);
+    }
+
+    #[quickcheck]
+    fn test_from_iter_matches_vec(values: Vec<u16>) {
+        let keys = || values.iter().map(|&v| v as usize);
+
+        // Invariant: both bitmaps size themselves by the same rule, so
+        // compressing the collected VecBitmap yields the same bitmap.
+        let want = CompressedBitmap::from(keys().collect::<VecBitmap>());
+        let got = keys().collect::<CompressedBitmap>();
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn test_small_max_key() {
+        for max_key in [1, 10, 63, 64, 65, 4100] {
+            let mut b = CompressedBitmap::new(max_key);
+            assert_eq!(b.iter_ones().next(), None);
+
+            b.set(max_key - 1, true);
+            assert!(b.get(max_key - 1));
+            assert_eq!(b.iter_ones().collect::<Vec<_>>(), vec![max_key - 1]);
+        }
+
+        let b = CompressedBitmap::new(0);
+        assert_eq!(b.iter_ones().next(), None);
+        assert_eq!(b.blocks().next(), None);
+    }
+
+    #[test]
+    fn test_rank_select_empty() {
+        let empty = [
+            CompressedBitmap::new(0),
+            std::iter::empty::<usize>().collect::<CompressedBitmap>(),
+            CompressedBitmap::new(100),
+        ];
+
+        for b in &empty {
+            for key in [0, 1, 64, usize::MAX] {
+                assert_eq!(b.next_set(key), None);
+                assert_eq!(b.prev_set(key), None);
+                assert_eq!(b.rank(key), 0);
+            }
+            assert_eq!(b.select(0), None);
+            assert_eq!(b.count_ones(), 0);
+        }
+    }
+
+    #[test]
+    fn test_iter_ones_empty() {
+        let b = CompressedBitmap::new(100);
+        assert_eq!(b.iter_ones().next(), None);
+        assert_eq!(b.iter_ones().next_back(), None);
+        assert_eq!(b.blocks().next(), None);
+    }
+
+    #[test]
+    fn test_blocks_skips_empty() {
+        let mut b = CompressedBitmap::new(1024);
+        b.set(1, true);
+        b.set(100, true);
+        b.set(100, false);
+
+        // The second block remains allocated, but contains no set bits.
+        assert_eq!(b.blocks().collect::<Vec<_>>(), vec![(0, 1 << 1)]);
+        assert_eq!(b.blocks().rev().collect::<Vec<_>>(), vec![(0, 1 << 1)]);
+    }
+
     proptest! {
+        #[test]
+        fn prop_batch_ops(
+            keys in prop::collection::vec(0..1_usize << 18, 0..200),
+            check in prop::collection::vec(0..1_usize << 18, 0..200),
+        ) {
+            let mut want = CompressedBitmap::new(1 << 18);
+            for key in &keys {
+                want.set(*key, true);
+            }
+
+            // Invariant: setting keys in any order, ascending or not, is
+            // equivalent to setting each.
+            let mut got = CompressedBitmap::new(1 << 18);
+            Bitmap::set_all(&mut got, keys.iter().copied());
+            assert_eq!(got, want);
+
+            let mut sorted = keys.clone();
+            sorted.sort_unstable();
+            let mut got = CompressedBitmap::new(1 << 18);
+            Bitmap::set_all(&mut got, sorted.iter().copied());
+            assert_eq!(got, want);
+
+            // Invariant: batch lookups agree with individual lookups.
+            let mut check = check;
+            for _ in 0..2 {
+                let mut out = vec![false; check.len()];
+                got.get_many(&check, &mut out);
+                assert_eq!(out, check.iter().map(|k| got.get(*k)).collect::<Vec<_>>());
+                assert_eq!(
+                    got.get_all(check.iter().copied()),
+                    check.iter().all(|k| got.get(*k))
+                );
+                check.sort_unstable();
+            }
+            assert!(got.get_all(keys.iter().copied()));
+        }
+
+        #[test]
+        fn prop_iter_ones(
+            values in prop::collection::vec((0..u32::MAX as usize >> 8, any::<bool>()), 0..100),
+            ends in prop::collection::vec(any::<bool>(), 0..200),
+        ) {
+            let mut b = CompressedBitmap::new(u32::MAX as usize >> 8);
+            let mut control = std::collections::BTreeSet::new();
+            for (v, set) in &values {
+                b.set(*v, *set);
+                if *set {
+                    control.insert(*v);
+                } else {
+                    control.remove(v);
+                }
+            }
+
+            // Invariant: the set bits are yielded in order from either end.
+            assert_eq!(b.iter_ones().collect::<Vec<_>>(), control.iter().copied().collect::<Vec<_>>());
+            assert_eq!(
+                b.iter_ones().rev().collect::<Vec<_>>(),
+                control.iter().rev().copied().collect::<Vec<_>>()
+            );
+
+            // Invariant: consuming from both ends yields each bit exactly once.
+            let mut want = control.iter().copied().collect::<std::collections::VecDeque<_>>();
+            let mut iter = b.iter_ones();
+            for front in ends {
+                if front {
+                    assert_eq!(iter.next(), want.pop_front());
+                } else {
+                    assert_eq!(iter.next_back(), want.pop_back());
+                }
+            }
+            assert_eq!(iter.collect::<Vec<_>>(), Vec::from(want));
+
+            // Invariant: the blocks match those of the equivalent dense bitmap.
+            let mut v = VecBitmap::new_with_capacity(u32::MAX as usize >> 8);
+            for key in &control {
+                v.set(*key, true);
+            }
+            assert_eq!(b.blocks().collect::<Vec<_>>(), v.blocks().collect::<Vec<_>>());
+            assert_eq!(
+                b.blocks().rev().collect::<Vec<_>>(),
+                v.blocks().rev().collect::<Vec<_>>()
+            );
+        }
+
+        #[test]
+        fn prop_rank_select(
+            values in prop::collection::vec((0..u32::MAX as usize >> 8, any::<bool>()), 0..100),
+            check in prop::collection::vec(0..u32::MAX as usize >> 8, 0..50),
+        ) {
+            let max_key = u32::MAX as usize >> 8;
+            let mut b = CompressedBitmap::new(max_key);
+            let mut control = std::collections::BTreeSet::new();
+            for (v, set) in &values {
+                b.set(*v, *set);
+                if *set {
+                    control.insert(*v);
+                } else {
+                    control.remove(v);
+                }
+            }
+
+            // Check the set values, their neighbours, and arbitrary keys.
+            let keys = control
+                .iter()
+                .flat_map(|&v| [v.saturating_sub(1), v, v + 1])
+                .chain(check)
+                .chain([0, max_key]);
+
+            for key in keys {
+                assert_eq!(b.rank(key), control.range(..key).count());
+                assert_eq!(b.next_set(key), control.range(key..).next().copied());
+                assert_eq!(b.prev_set(key), control.range(..=key).next_back().copied());
+            }
+
+            for (n, want) in control.iter().enumerate() {
+                assert_eq!(b.select(n), Some(*want));
+
+                // Invariant: select is the inverse of rank.
+                assert_eq!(b.rank(*want), n);
+            }
+            assert_eq!(b.select(control.len()), None);
+            assert_eq!(b.rank(usize::MAX), control.len());
+            assert_eq!(b.next_set(usize::MAX), None);
+            assert_eq!(b.prev_set(usize::MAX), control.iter().next_back().copied());
+        }
+
+        #[test]
+        fn prop_segments(
+            values in prop::collection::vec((0..u32::MAX as usize >> 8, any::<bool>()), 0..100),
+        ) {
+            let max_key = u32::MAX as usize >> 8;
+            let mut b = CompressedBitmap::new(max_key);
+
+            let mut control = std::collections::HashSet::new();
+            for (v, set) in &values {
+                b.set(*v, *set);
+                if *set {
+                    control.insert(*v);
+                } else {
+                    control.remove(v);
+                }
+
+                // Invariant: each segment holds exactly the blocks marked in
+                // the block map words of its superblock.
+                for (superblock, segment) in b.block_map.chunks(SUPERBLOCK_WORDS).zip(b.bitmap.iter()) {
+                    let want = superblock.iter().map(|v| v.count_ones() as usize).sum::<usize>();
+                    assert_eq!(segment.len(), want);
+                }
+            }
+
+            for (v, _) in &values {
+                assert_eq!(b.get(*v), control.contains(v));
+            }
+            assert_eq!(b.count_ones(), control.len());
+        }
+
+        #[test]
+        fn prop_ops_rank_select(
+            a in prop::collection::vec(0..u32::MAX as usize >> 8, 0..100),
+            b in prop::collection::vec(0..u32::MAX as usize >> 8, 0..100),
+        ) {
+            let max_key = u32::MAX as usize >> 8;
+            let mut left = CompressedBitmap::new(max_key);
+            left.set_all(a.iter().copied());
+            let mut right = CompressedBitmap::new(max_key);
+            right.set_all(b.iter().copied());
+
+            // Invariant: the set bits are counted and selected from the
+            // segments produced by every operation.
+            let ops: [fn(&CompressedBitmap, &CompressedBitmap) -> CompressedBitmap; 4] = [
+                CompressedBitmap::or,
+                CompressedBitmap::and,
+                CompressedBitmap::xor,
+                CompressedBitmap::and_not,
+            ];
+            for op in &ops {
+                let got = op(&left, &right);
+                let ones = got.iter_ones().collect::<Vec<_>>();
+                assert_eq!(got.count_ones(), ones.len());
+                for (n, key) in ones.iter().enumerate() {
+                    assert_eq!(got.select(n), Some(*key));
+                    assert_eq!(got.rank(*key), n);
+                }
+                assert_eq!(got.select(ones.len()), None);
+            }
+
+            left.clear();
+            assert_eq!(left.count_ones(), 0);
+            assert_eq!(left.select(0), None);
+        }
+
         #[test]
         fn prop_compress(
             values in prop::collection::hash_set(0..MAX_KEY, 0..20),
@@ -0,0 +1,323 @@
+use std::convert::TryFrom;
+
+use super::{
+    bitmask_for_key,
+    compressed_bitmap::{num_segments, physical_offset, SUPERBLOCK_WORDS},
+    index_for_key,
+};
+
+/// The number of header words preceding the block map.
+const HEADER_WORDS: usize = 2;
+
+/// The size of a single word in the byte layout.
+const WORD_BYTES: usize = std::mem::size_of::<u64>();
+
+/// A read-only, zero-copy view of a [`CompressedBitmap`] over a byte slice.
+///
+/// A `CompressedBitmapRef` performs lookups directly against the bytes
+/// produced by [`CompressedBitmap::write_to()`], without copying or
+/// allocating. This allows a large bitmap stored on disk to be memory mapped
+/// and queried in place, with the OS lazily loading only the blocks that are
+/// accessed.
+///
+/// Lookups cost the same as a [`CompressedBitmap`]: a single block map word
+/// read for unpopulated blocks, and a rank directory read plus at most one
+/// superblock of popcounts for populated blocks.
+///
+/// ## Layout
+///
+/// The layout is a sequence of little-endian `u64` words - every field starts
+/// at an offset that is a multiple of 8 bytes:
+///
+/// ```text
+///     ┌──────────────────────┐
+///     │ block map length (N) │  1 word
+///     ├──────────────────────┤
+///     │   block count (M)    │  1 word
+///     ├──────────────────────┤
+///     │      block map       │  N words
+///     ├──────────────────────┤
+///     │    rank directory    │  ceil(N / 16) words
+///     ├──────────────────────┤
+///     │        blocks        │  M words
+///     └──────────────────────┘
+/// ```
+///
+/// Entry `i` of the rank directory holds the number of blocks set in the block
+/// map words preceding word `i * 16`, and the blocks are ordered by their
+/// logical block index.
+///
+/// The layout is validated when constructing a `CompressedBitmapRef`, ensuring
+/// lookups never read outside of the provided bytes.
+///
+/// [`CompressedBitmap`]: crate::CompressedBitmap
+/// [`CompressedBitmap::write_to()`]: crate::CompressedBitmap::write_to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressedBitmapRef<'a> {
+    block_map: &'a [u8],
+    rank: &'a [u8],
+    bitmap: &'a [u8],
+}
+
+impl<'a> CompressedBitmapRef<'a> {
+    /// Construct a `CompressedBitmapRef` over `bytes` in the layout written by
+    /// [`CompressedBitmap::write_to()`].
+    ///
+    /// Returns an error if `bytes` does not contain a valid layout.
+    ///
+    /// [`CompressedBitmap::write_to()`]: crate::CompressedBitmap::write_to
+    pub fn new(bytes: &'a [u8]) -> Result<Self, LayoutError> {
+        if !bytes.len().is_multiple_of(WORD_BYTES) || bytes.len() < HEADER_WORDS * WORD_BYTES {
+            return Err(LayoutError::Length);
+        }
+
+        let block_map_len = word_to_usize(read_word(bytes, 0))?;
+        let num_blocks = word_to_usize(read_word(bytes, 1))?;
+        let rank_len = num_segments(block_map_len);
+
+        // Invariant: the bytes contain exactly the header, block map, rank
+        // directory and blocks.
+        let want = HEADER_WORDS
+            .checked_add(block_map_len)
+            .and_then(|v| v.checked_add(rank_len))
+            .and_then(|v| v.checked_add(num_blocks))
+            .and_then(|v| v.checked_mul(WORD_BYTES))
+            .ok_or(LayoutError::Length)?;
+        if bytes.len() != want {
+            return Err(LayoutError::Length);
+        }
+
+        let (block_map, rest) =
+            bytes[HEADER_WORDS * WORD_BYTES..].split_at(block_map_len * WORD_BYTES);
+        let (rank, bitmap) = rest.split_at(rank_len * WORD_BYTES);
+
+        let b = Self {
+            block_map,
+            rank,
+            bitmap,
+        };
+
+        validate_rank(
+            |i| b.block_map_word(i),
+            block_map_len,
+            |i| b.rank(i),
+            num_blocks,
+        )?;
+
+        Ok(b)
+    }
+
+    /// Returns the value at `key`.
+    ///
+    /// If a value for `key` was not previously set, `false` is returned.
+    ///
+    /// # Panics
+    ///
+    /// This method MAY panic if `key` is more than the `max_key` value of the
+    /// [`CompressedBitmap`](crate::CompressedBitmap) this layout was wrote
+    /// from.
+    pub fn get(&self, key: usize) -> bool {
+        let block_index = index_for_key(key);
+        let block_map_index = index_for_key(block_index);
+
+        if self.block_map_word(block_map_index) & bitmask_for_key(block_index) == 0 {
+            return false;
+        }
+
+        let (superblock, offset) = physical_offset(|i| self.block_map_word(i), block_index);
+
+        read_word(self.bitmap, self.rank(superblock) + offset) as usize & bitmask_for_key(key) != 0
+    }
+
+    /// Return the size of the underlying byte slice.
+    pub fn byte_size(&self) -> usize {
+        (HEADER_WORDS * WORD_BYTES) + self.block_map.len() + self.rank.len() + self.bitmap.len()
+    }
+
+    /// Return the number of block map words.
+    pub(crate) fn block_map_len(&self) -> usize {
+        self.block_map.len() / WORD_BYTES
+    }
+
+    #[inline(always)]
+    fn block_map_word(&self, idx: usize) -> usize {
+        read_word(self.block_map, idx) as usize
+    }
+
+    #[inline(always)]
+    fn rank(&self, superblock: usize) -> usize {
+        read_word(self.rank, superblock) as usize
+    }
+}
+
+/// Validate the rank directory entries returned by `rank` match the
+/// `block_map_len` words returned by `block_map`, and the block map accounts
+/// for exactly `num_blocks` blocks.
+pub(super) fn validate_rank<F, G>(
+    block_map: F,
+    block_map_len: usize,
+    rank: G,
+    num_blocks: usize,
+) -> Result<(), LayoutError>
+where
+    F: Fn(usize) -> usize,
+    G: Fn(usize) -> usize,
+{
+    let mut total = 0;
+    for i in 0..block_map_len {
+        if i % SUPERBLOCK_WORDS == 0 && rank(i / SUPERBLOCK_WORDS) != total {
+            return Err(LayoutError::RankDirectory);
+        }
+        total += block_map(i).count_ones() as usize;
+    }
+
+    if total != num_blocks {
+        return Err(LayoutError::RankDirectory);
+    }
+
+    Ok(())
+}
+
+/// Read the little-endian `u64` at word index `idx` in `bytes`.
+#[inline(always)]
+fn read_word(bytes: &[u8], idx: usize) -> u64 {
+    let offset = idx * WORD_BYTES;
+    let mut word = [0; WORD_BYTES];
+    word.copy_from_slice(&bytes[offset..offset + WORD_BYTES]);
+    u64::from_le_bytes(word)
+}
+
+fn word_to_usize(v: u64) -> Result<usize, LayoutError> {
+    usize::try_from(v).map_err(|_| LayoutError::Length)
+}
+
+/// An error returned when constructing a [`CompressedBitmapRef`] from bytes
+/// that do not contain a valid layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutError {
+    /// The length of the bytes does not match the lengths described in the
+    /// header.
+    Length,
+
+    /// The rank directory or block count is inconsistent with the block map.
+    RankDirectory,
+
+    /// The bitmap is not sized for the [`FilterSize`](crate::FilterSize) of
+    /// the filter.
+    FilterSize,
+}
+
+impl std::fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Length => write!(f, "bitmap byte length does not match header"),
+            Self::RankDirectory => write!(f, "bitmap rank directory does not match block map"),
+            Self::FilterSize => write!(f, "bitmap size does not match filter size"),
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::CompressedBitmap;
+
+    fn to_bytes(b: &CompressedBitmap) -> Vec<u8> {
+        let mut buf = Vec::new();
+        b.write_to(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_empty() {
+        let b = CompressedBitmap::new(100);
+        let bytes = to_bytes(&b);
+        let r = CompressedBitmapRef::new(&bytes).unwrap();
+
+        assert_eq!(r.byte_size(), bytes.len());
+        for i in 0..100 {
+            assert!(!r.get(i));
+        }
+    }
+
+    #[test]
+    fn test_invalid_length() {
+        let mut b = CompressedBitmap::new(u16::MAX as _);
+        b.set(42, true);
+        let bytes = to_bytes(&b);
+
+        assert_eq!(CompressedBitmapRef::new(&[]), Err(LayoutError::Length));
+        assert_eq!(
+            CompressedBitmapRef::new(&bytes[..bytes.len() - 1]),
+            Err(LayoutError::Length)
+        );
+        assert_eq!(
+            CompressedBitmapRef::new(&bytes[..bytes.len() - WORD_BYTES]),
+            Err(LayoutError::Length)
+        );
+
+        // A block count that overflows the length calculation.
+        let mut bad = bytes.clone();
+        bad[WORD_BYTES..2 * WORD_BYTES].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(CompressedBitmapRef::new(&bad), Err(LayoutError::Length));
+    }
+
+    #[test]
+    fn test_invalid_block_map() {
+        let mut b = CompressedBitmap::new(u16::MAX as _);
+        b.set(42, true);
+        let mut bytes = to_bytes(&b);
+
+        // Mark a second block as allocated in the block map without a
+        // corresponding block.
+        bytes[HEADER_WORDS * WORD_BYTES] |= 0b10;
+        assert_eq!(
+            CompressedBitmapRef::new(&bytes),
+            Err(LayoutError::RankDirectory)
+        );
+    }
+
+    #[test]
+    fn test_invalid_rank() {
+        let mut b = CompressedBitmap::new(u32::MAX as usize >> 8);
+        b.set(42, true);
+        b.set(usize::BITS as usize * 1024 * 4, true);
+        let mut bytes = to_bytes(&b);
+
+        // Corrupt the rank directory entry for the second superblock.
+        let block_map_len = read_word(&bytes, 0) as usize;
+        let rank_offset = (HEADER_WORDS + block_map_len + 1) * WORD_BYTES;
+        bytes[rank_offset] = 42;
+        assert_eq!(
+            CompressedBitmapRef::new(&bytes),
+            Err(LayoutError::RankDirectory)
+        );
+    }
+
+    const MAX_KEY: usize = u32::MAX as usize >> 8;
+
+    proptest! {
+        #[test]
+        fn prop_ref_lookup(
+            values in prop::collection::hash_set(0..MAX_KEY, 0..100),
+            check in prop::collection::vec(0..MAX_KEY, 0..100),
+        ) {
+            let mut b = CompressedBitmap::new(MAX_KEY);
+            for v in &values {
+                b.set(*v, true);
+            }
+
+            let bytes = to_bytes(&b);
+            let r = CompressedBitmapRef::new(&bytes).unwrap();
+
+            // Invariant: the view and the owned bitmap agree on every value.
+            for v in values.iter().chain(&check) {
+                assert_eq!(r.get(*v), b.get(*v));
+            }
+        }
+    }
+}
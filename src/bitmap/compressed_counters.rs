@@ -0,0 +1,272 @@
+use super::{
+    bitmask_for_key,
+    compressed_bitmap::{block_map_len, num_segments, physical_offset},
+    index_for_key, WordOnes,
+};
+
+/// The width of each saturating counter in a
+/// [`CountingBloom2`](crate::CountingBloom2).
+///
+/// Wider counters use more memory, but saturate after more inserts of values
+/// sharing a key. Once saturated, a counter is never decremented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterWidth {
+    /// 4 bit counters, saturating at 15.
+    Bits4 = 4,
+
+    /// 8 bit counters, saturating at 255.
+    Bits8 = 8,
+}
+
+impl CounterWidth {
+    /// The number of counters packed into each block.
+    fn per_block(self) -> usize {
+        u64::BITS as usize / self as usize
+    }
+
+    /// The saturated value of a counter.
+    fn max(self) -> u64 {
+        (1 << self as u32) - 1
+    }
+}
+
+/// A sparse, 2-level array of saturating counters.
+///
+/// The counters are packed into `u64` blocks, with a block map marking the
+/// allocated blocks in the same layout as a
+/// [`CompressedBitmap`](super::CompressedBitmap) - blocks are allocated when a
+/// counter is first incremented, and freed when every counter in the block
+/// returns to zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CompressedCounters {
+    block_map: Vec<usize>,
+
+    /// The allocated blocks, segmented by superblock.
+    blocks: Vec<Vec<u64>>,
+
+    width: CounterWidth,
+}
+
+impl CompressedCounters {
+    /// Construct a `CompressedCounters` with a counter for each key up to and
+    /// including `max_key`.
+    pub(crate) fn new(max_key: usize, width: CounterWidth) -> Self {
+        // Map enough blocks to hold max_key + 1 counters.
+        let blocks = (max_key / width.per_block()) + 1;
+        let num_blocks = block_map_len(blocks * u64::BITS as usize);
+
+        Self {
+            block_map: vec![0; num_blocks],
+            blocks: vec![Vec::new(); num_segments(num_blocks)],
+            width,
+        }
+    }
+
+    /// Return the counter for `key`.
+    pub(crate) fn get(&self, key: usize) -> u64 {
+        let (block_index, shift) = self.locate(key);
+        if !self.is_allocated(block_index) {
+            return 0;
+        }
+
+        let (segment, offset) = physical_offset(|i| self.block_map[i], block_index);
+        (self.blocks[segment][offset] >> shift) & self.width.max()
+    }
+
+    /// Increment the counter for `key`, unless it is saturated.
+    pub(crate) fn increment(&mut self, key: usize) {
+        let (block_index, shift) = self.locate(key);
+        let allocated = self.is_allocated(block_index);
+
+        let (segment, offset) = physical_offset(|i| self.block_map[i], block_index);
+        let segment = &mut self.blocks[segment];
+
+        if !allocated {
+            segment.insert(offset, 1 << shift);
+            self.block_map[index_for_key(block_index)] |= bitmask_for_key(block_index);
+            return;
+        }
+
+        if (segment[offset] >> shift) & self.width.max() < self.width.max() {
+            segment[offset] += 1 << shift;
+        }
+    }
+
+    /// Decrement the counter for `key`, unless it is zero or saturated.
+    ///
+    /// The block holding `key` is freed if all its counters reach zero.
+    pub(crate) fn decrement(&mut self, key: usize) {
+        let (block_index, shift) = self.locate(key);
+        if !self.is_allocated(block_index) {
+            return;
+        }
+
+        let (segment, offset) = physical_offset(|i| self.block_map[i], block_index);
+        let segment = &mut self.blocks[segment];
+
+        // A saturated counter no longer tracks the number of inserts, so
+        // decrementing it may cause a false negative.
+        let counter = (segment[offset] >> shift) & self.width.max();
+        if counter == 0 || counter == self.width.max() {
+            return;
+        }
+
+        segment[offset] -= 1 << shift;
+        if segment[offset] == 0 {
+            segment.remove(offset);
+            self.block_map[index_for_key(block_index)] &= !bitmask_for_key(block_index);
+        }
+    }
+
+    /// Yields the keys of all non-zero counters, in ascending order.
+    pub(crate) fn iter_nonzero(&self) -> impl Iterator<Item = usize> + '_ {
+        let per_block = self.width.per_block();
+        let width = self.width as usize;
+        let max = self.width.max();
+
+        let logical = self
+            .block_map
+            .iter()
+            .enumerate()
+            .flat_map(|(i, &word)| WordOnes::new(i, word));
+
+        logical
+            .zip(self.blocks.iter().flatten())
+            .flat_map(move |(block_index, &block)| {
+                (0..per_block)
+                    .filter(move |i| (block >> (i * width)) & max != 0)
+                    .map(move |i| block_index * per_block + i)
+            })
+    }
+
+    /// Return the number of allocated blocks.
+    #[cfg(test)]
+    pub(crate) fn num_blocks(&self) -> usize {
+        self.blocks.iter().map(Vec::len).sum()
+    }
+
+    /// Return the byte size of the counters.
+    pub(crate) fn byte_size(&self) -> usize {
+        (self.block_map.capacity() * std::mem::size_of::<usize>())
+            + (self.blocks.capacity() * std::mem::size_of::<Vec<u64>>())
+            + self
+                .blocks
+                .iter()
+                .map(|v| v.capacity() * std::mem::size_of::<u64>())
+                .sum::<usize>()
+            + std::mem::size_of_val(self)
+    }
+
+    /// Return the width of each counter.
+    pub(crate) fn width(&self) -> CounterWidth {
+        self.width
+    }
+
+    /// Return the logical block holding the counter for `key`, and the bit
+    /// offset of the counter within it.
+    fn locate(&self, key: usize) -> (usize, u32) {
+        let per_block = self.width.per_block();
+        (
+            key / per_block,
+            ((key % per_block) * self.width as usize) as u32,
+        )
+    }
+
+    fn is_allocated(&self, block_index: usize) -> bool {
+        self.block_map[index_for_key(block_index)] & bitmask_for_key(block_index) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const MAX_KEY: usize = u32::MAX as usize >> 8;
+
+    #[test]
+    fn test_saturate() {
+        for width in [CounterWidth::Bits4, CounterWidth::Bits8] {
+            let mut c = CompressedCounters::new(MAX_KEY, width);
+
+            for _ in 0..1000 {
+                c.increment(42);
+            }
+            assert_eq!(c.get(42), width.max());
+
+            // Invariant: saturated counters are never decremented.
+            c.decrement(42);
+            assert_eq!(c.get(42), width.max());
+
+            // Neighbouring counters are unaffected.
+            assert_eq!(c.get(41), 0);
+            assert_eq!(c.get(43), 0);
+        }
+    }
+
+    #[test]
+    fn test_free_blocks() {
+        let mut c = CompressedCounters::new(MAX_KEY, CounterWidth::Bits4);
+        c.increment(1);
+        c.increment(2);
+        c.increment(MAX_KEY);
+        assert_eq!(c.num_blocks(), 2);
+
+        c.decrement(1);
+        assert_eq!(c.num_blocks(), 2);
+
+        // Invariant: a block is freed once all its counters are zero.
+        c.decrement(2);
+        assert_eq!(c.num_blocks(), 1);
+        c.decrement(MAX_KEY);
+        assert_eq!(c.num_blocks(), 0);
+        assert!(c.block_map.iter().all(|&v| v == 0));
+
+        // Decrementing an unallocated counter is a no-op.
+        c.decrement(2);
+        assert_eq!(c.get(2), 0);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_counters(
+            ops in prop::collection::vec((0..MAX_KEY, any::<bool>()), 0..200),
+            bits8 in any::<bool>(),
+        ) {
+            let width = if bits8 { CounterWidth::Bits8 } else { CounterWidth::Bits4 };
+            let mut c = CompressedCounters::new(MAX_KEY, width);
+            let mut control = HashMap::<usize, u64>::new();
+
+            for (key, increment) in ops {
+                let v = control.entry(key).or_default();
+                if increment {
+                    c.increment(key);
+                    *v = (*v + 1).min(width.max());
+                } else {
+                    c.decrement(key);
+                    if *v != 0 && *v != width.max() {
+                        *v -= 1;
+                    }
+                }
+            }
+
+            for (key, want) in &control {
+                assert_eq!(c.get(*key), *want);
+            }
+
+            let mut want = control
+                .iter()
+                .filter(|(_, v)| **v != 0)
+                .map(|(k, _)| *k)
+                .collect::<Vec<_>>();
+            want.sort_unstable();
+            assert_eq!(c.iter_nonzero().collect::<Vec<_>>(), want);
+
+            // Invariant: only blocks with non-zero counters are allocated.
+            assert!(c.blocks.iter().flatten().all(|&v| v != 0));
+        }
+    }
+}
@@ -1,8 +1,21 @@
 //! Bitmap implementations for the backing storage of a [`Bloom2`](crate::Bloom2).
 
+mod atomic;
+mod atomic_compressed;
+mod blocked;
 mod compressed_bitmap;
+mod compressed_bitmap_ref;
+mod compressed_counters;
+mod ops;
 mod vec;
+pub use atomic::*;
+pub use atomic_compressed::*;
+pub use blocked::*;
+pub(crate) use compressed_bitmap::block_map_len;
 pub use compressed_bitmap::*;
+pub use compressed_bitmap_ref::*;
+pub(crate) use compressed_counters::CompressedCounters;
+pub use compressed_counters::CounterWidth;
 pub use vec::*;
 
 #[inline(always)]
@@ -14,3 +27,64 @@ pub(crate) fn bitmask_for_key(key: usize) -> usize {
 pub(crate) fn index_for_key(key: usize) -> usize {
     key / (u64::BITS as usize)
 }
+
+/// Hint to the CPU that `value` will be read soon, loading the cache line
+/// holding it ahead of the read.
+///
+/// This is a no-op on targets without a supported prefetch instruction.
+#[inline(always)]
+pub(crate) fn prefetch<T>(value: &T) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+
+        // SAFETY: SSE is always available on x86_64, and a prefetch never
+        // faults - the pointer is not dereferenced.
+        unsafe { _mm_prefetch::<_MM_HINT_T0>(value as *const T as *const i8) }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = value;
+}
+
+/// Yields the keys of the set bits in a single bitmap word, offset by `base`,
+/// in ascending order from the front and descending order from the back.
+#[derive(Debug, Clone)]
+pub(crate) struct WordOnes {
+    base: usize,
+    bits: usize,
+}
+
+impl WordOnes {
+    /// Iterate over the set bits of the `word` at `word_index`.
+    pub(crate) fn new(word_index: usize, word: usize) -> Self {
+        Self {
+            base: word_index * usize::BITS as usize,
+            bits: word,
+        }
+    }
+}
+
+impl Iterator for WordOnes {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.bits == 0 {
+            return None;
+        }
+        let bit = self.bits.trailing_zeros() as usize;
+        self.bits &= self.bits - 1;
+        Some(self.base + bit)
+    }
+}
+
+impl DoubleEndedIterator for WordOnes {
+    fn next_back(&mut self) -> Option<usize> {
+        if self.bits == 0 {
+            return None;
+        }
+        let bit = (usize::BITS - 1 - self.bits.leading_zeros()) as usize;
+        self.bits &= !(1 << bit);
+        Some(self.base + bit)
+    }
+}
@@ -0,0 +1,175 @@
+//! Bitwise operator implementations for the bitmap types.
+
+use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign, Sub, SubAssign};
+
+use super::{CompressedBitmap, VecBitmap};
+
+/// Implement the bitwise operator traits for `$t` in terms of its inherent
+/// set operation methods.
+///
+/// Both `&a | &b` (allocating a new bitmap) and `a | &b` (reusing `a`) are
+/// supported, along with the in-place `a |= &b` forms.
+macro_rules! impl_ops {
+    ($t:ty) => {
+        impl_ops!($t, BitOr, bitor, or, BitOrAssign, bitor_assign, or_assign);
+        impl_ops!(
+            $t,
+            BitAnd,
+            bitand,
+            and,
+            BitAndAssign,
+            bitand_assign,
+            and_assign
+        );
+        impl_ops!(
+            $t,
+            BitXor,
+            bitxor,
+            xor,
+            BitXorAssign,
+            bitxor_assign,
+            xor_assign
+        );
+        impl_ops!($t, Sub, sub, and_not, SubAssign, sub_assign, and_not_assign);
+    };
+    ($t:ty, $op:ident, $op_fn:ident, $method:ident, $assign:ident, $assign_fn:ident, $assign_method:ident) => {
+        impl $op<&$t> for &$t {
+            type Output = $t;
+
+            fn $op_fn(self, other: &$t) -> $t {
+                self.$method(other)
+            }
+        }
+
+        impl $op<&$t> for $t {
+            type Output = $t;
+
+            fn $op_fn(mut self, other: &$t) -> $t {
+                self.$assign_method(other);
+                self
+            }
+        }
+
+        impl $assign<&$t> for $t {
+            fn $assign_fn(&mut self, other: &$t) {
+                self.$assign_method(other)
+            }
+        }
+    };
+}
+
+impl_ops!(CompressedBitmap);
+impl_ops!(VecBitmap);
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::Bitmap;
+
+    const MAX_KEY: usize = u32::MAX as usize >> 12;
+
+    fn new_bitmap<B: Bitmap>(values: &[usize]) -> B {
+        let mut b = B::new_with_capacity(MAX_KEY);
+        for v in values {
+            b.set(*v, true);
+        }
+        b
+    }
+
+    /// Assert the operators of `B` match the results of `op` applied to each
+    /// bit of `a` and `b`.
+    fn check_ops<B>(a: &[usize], b: &[usize])
+    where
+        B: Bitmap + Clone + std::fmt::Debug + PartialEq,
+        for<'a> &'a B: BitOr<&'a B, Output = B>
+            + BitAnd<&'a B, Output = B>
+            + BitXor<&'a B, Output = B>
+            + Sub<&'a B, Output = B>,
+        for<'a> B: BitOr<&'a B, Output = B>
+            + BitOrAssign<&'a B>
+            + BitAndAssign<&'a B>
+            + BitXorAssign<&'a B>
+            + SubAssign<&'a B>,
+    {
+        let left = new_bitmap::<B>(a);
+        let right = new_bitmap::<B>(b);
+
+        let check = |got: B, assigned: B, op: fn(bool, bool) -> bool| {
+            // Invariant: the allocating and in-place operators agree.
+            assert_eq!(got, assigned);
+
+            for v in a.iter().chain(b) {
+                assert_eq!(got.get(*v), op(left.get(*v), right.get(*v)));
+            }
+            let want = a
+                .iter()
+                .chain(b)
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .filter(|v| op(a.contains(v), b.contains(v)))
+                .count();
+            assert_eq!(got.count_ones(), want);
+        };
+
+        let mut v = left.clone();
+        v |= &right;
+        check(&left | &right, v, |l, r| l | r);
+
+        let mut v = left.clone();
+        v &= &right;
+        check(&left & &right, v, |l, r| l & r);
+
+        let mut v = left.clone();
+        v ^= &right;
+        check(&left ^ &right, v, |l, r| l ^ r);
+
+        let mut v = left.clone();
+        v -= &right;
+        check(&left - &right, v, |l, r| l & !r);
+
+        // And the owned form reuses the left hand side.
+        assert_eq!(left.clone() | &right, &left | &right);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_ops_compressed(
+            a in prop::collection::vec(0..MAX_KEY, 0..50),
+            b in prop::collection::vec(0..MAX_KEY, 0..50),
+        ) {
+            check_ops::<CompressedBitmap>(&a, &b);
+        }
+
+        #[test]
+        fn prop_ops_vec(
+            a in prop::collection::vec(0..MAX_KEY, 0..50),
+            b in prop::collection::vec(0..MAX_KEY, 0..50),
+        ) {
+            check_ops::<VecBitmap>(&a, &b);
+        }
+
+        #[test]
+        fn prop_ops_equivalent(
+            a in prop::collection::vec(0..MAX_KEY, 0..50),
+            b in prop::collection::vec(0..MAX_KEY, 0..50),
+        ) {
+            let (ca, cb) = (new_bitmap::<CompressedBitmap>(&a), new_bitmap::<CompressedBitmap>(&b));
+            let (va, vb) = (new_bitmap::<VecBitmap>(&a), new_bitmap::<VecBitmap>(&b));
+
+            // Invariant: both bitmap types produce the same results, and the
+            // compressed results contain no empty blocks.
+            for (c, v) in [
+                (&ca | &cb, &va | &vb),
+                (&ca & &cb, &va & &vb),
+                (&ca ^ &cb, &va ^ &vb),
+                (&ca - &cb, &va - &vb),
+            ] {
+                assert_eq!(c, CompressedBitmap::from(v));
+            }
+        }
+    }
+}
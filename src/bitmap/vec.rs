@@ -1,6 +1,11 @@
-use crate::Bitmap;
+use std::io::{Read, Write};
 
-use super::{bitmask_for_key, index_for_key};
+use crate::{
+    format::{read_usize, read_words},
+    Bitmap, FormatError, LayoutError, PortableBitmap,
+};
+
+use super::{bitmask_for_key, index_for_key, prefetch, WordOnes};
 
 /// A plain, heap-allocated, `O(1)` indexed bitmap.
 ///
@@ -9,16 +14,147 @@ use super::{bitmask_for_key, index_for_key};
 ///
 /// This type is fast for both read and writes, but trades additional space for
 /// the additional performance.
+///
+/// ## Features
+///
+/// If the `serde` feature is enabled, a `VecBitmap` supports
+/// (de)serialisation with [serde].
+///
+/// [serde]: https://github.com/serde-rs/serde
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "VecBitmapData")
+)]
 pub struct VecBitmap {
     bitmap: Vec<usize>,
     max_key: usize,
 }
 
 impl VecBitmap {
+    pub(crate) fn from_parts(bitmap: Vec<usize>, max_key: usize) -> Self {
+        debug_assert_eq!(bitmap.len(), index_for_key(max_key) + 1);
+        Self { bitmap, max_key }
+    }
+
     pub(crate) fn into_parts(self) -> (Vec<usize>, usize) {
         (self.bitmap, self.max_key)
     }
+
+    /// Returns an iterator of the keys set to `true`, in ascending order.
+    pub fn iter_ones(&self) -> impl DoubleEndedIterator<Item = usize> + '_ {
+        self.blocks()
+            .flat_map(|(idx, block)| WordOnes::new(idx, block))
+    }
+
+    /// Returns an iterator of `(logical_block_index, word)` pairs for each
+    /// non-empty block of `usize` bits, in ascending order of block index.
+    ///
+    /// Block `N` holds the bits for keys `N * usize::BITS` to
+    /// `(N + 1) * usize::BITS - 1`, with the LSB being the smallest key.
+    pub fn blocks(&self) -> impl DoubleEndedIterator<Item = (usize, usize)> + '_ {
+        self.bitmap
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|&(_, block)| block != 0)
+    }
+
+    /// Perform a bitwise OR against `self` and `other`, returning the
+    /// resulting merged [`VecBitmap`].
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `other` was not configured with the same
+    /// `max_key`.
+    pub fn or(&self, other: &Self) -> Self {
+        let mut v = self.clone();
+        v.or_assign(other);
+        v
+    }
+
+    /// Perform a bitwise AND against `self` and `other`, returning the
+    /// resulting intersection [`VecBitmap`].
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `other` was not configured with the same
+    /// `max_key`.
+    pub fn and(&self, other: &Self) -> Self {
+        let mut v = self.clone();
+        v.and_assign(other);
+        v
+    }
+
+    /// Perform a bitwise XOR against `self` and `other`, returning a
+    /// [`VecBitmap`] containing the bits set in exactly one of them.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `other` was not configured with the same
+    /// `max_key`.
+    pub fn xor(&self, other: &Self) -> Self {
+        let mut v = self.clone();
+        v.xor_assign(other);
+        v
+    }
+
+    /// Return a [`VecBitmap`] containing the bits set in `self` that are not
+    /// set in `other` (the difference of `self` and `other`).
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `other` was not configured with the same
+    /// `max_key`.
+    pub fn and_not(&self, other: &Self) -> Self {
+        let mut v = self.clone();
+        v.and_not_assign(other);
+        v
+    }
+
+    /// Perform an in-place bitwise OR of `other` into `self`.
+    ///
+    /// See [`VecBitmap::or()`].
+    pub fn or_assign(&mut self, other: &Self) {
+        self.apply(other, |l, r| l | r)
+    }
+
+    /// Perform an in-place bitwise AND of `other` into `self`.
+    ///
+    /// See [`VecBitmap::and()`].
+    pub fn and_assign(&mut self, other: &Self) {
+        self.apply(other, |l, r| l & r)
+    }
+
+    /// Perform an in-place bitwise XOR of `other` into `self`.
+    ///
+    /// See [`VecBitmap::xor()`].
+    pub fn xor_assign(&mut self, other: &Self) {
+        self.apply(other, |l, r| l ^ r)
+    }
+
+    /// Clear the bits set in `other` from `self`, in-place.
+    ///
+    /// See [`VecBitmap::and_not()`].
+    pub fn and_not_assign(&mut self, other: &Self) {
+        self.apply(other, |l, r| l & !r)
+    }
+
+    /// Combine each word of `self` with the same word in `other` using `op`,
+    /// storing the result in `self`.
+    fn apply<F>(&mut self, other: &Self, op: F)
+    where
+        F: Fn(usize, usize) -> usize,
+    {
+        // Invariant: the bitmaps are of equal length, meaning the zipped iters
+        // yield both sides to completion.
+        assert_eq!(self.bitmap.len(), other.bitmap.len());
+
+        for (l, r) in self.bitmap.iter_mut().zip(&other.bitmap) {
+            *l = op(*l, *r);
+        }
+    }
 }
 
 impl Bitmap for VecBitmap {
@@ -38,26 +174,26 @@ impl Bitmap for VecBitmap {
         self.bitmap[offset] & bitmask_for_key(key) != 0
     }
 
+    fn prefetch(&self, key: usize) {
+        if let Some(word) = self.bitmap.get(index_for_key(key)) {
+            prefetch(word);
+        }
+    }
+
     fn byte_size(&self) -> usize {
         self.bitmap.len() * std::mem::size_of::<usize>()
     }
 
-    fn or(&self, other: &Self) -> Self {
-        // Invariant: the block maps are of equal length, meaning the zipped
-        // iters yield both sides to completion.
-        assert_eq!(self.bitmap.len(), other.bitmap.len());
+    fn count_ones(&self) -> usize {
+        self.bitmap.iter().map(|v| v.count_ones() as usize).sum()
+    }
 
-        let bitmap = self
-            .bitmap
-            .iter()
-            .zip(&other.bitmap)
-            .map(|(a, b)| a | b)
-            .collect();
+    fn or(&self, other: &Self) -> Self {
+        self.or(other)
+    }
 
-        Self {
-            bitmap,
-            max_key: self.max_key,
-        }
+    fn and(&self, other: &Self) -> Self {
+        self.and(other)
     }
 
     fn new_with_capacity(max_key: usize) -> Self {
@@ -66,6 +202,75 @@ impl Bitmap for VecBitmap {
     }
 }
 
+/// The serialised form of a [`VecBitmap`], validated before use.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct VecBitmapData {
+    bitmap: Vec<usize>,
+    max_key: usize,
+}
+
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<VecBitmapData> for VecBitmap {
+    type Error = LayoutError;
+
+    fn try_from(v: VecBitmapData) -> Result<Self, Self::Error> {
+        // Invariant: the bitmap has exactly the number of words needed to hold
+        // max_key bits.
+        if v.bitmap.len() != index_for_key(v.max_key) + 1 {
+            return Err(LayoutError::Length);
+        }
+
+        Ok(Self {
+            bitmap: v.bitmap,
+            max_key: v.max_key,
+        })
+    }
+}
+
+/// Construct a [`VecBitmap`] sized to hold the largest key yielded by the
+/// iterator, with every yielded key set to `true`.
+///
+/// The bitmap has a capacity of `max + 1` bits for the largest key `max`, as a
+/// [`CompressedBitmap`](crate::CompressedBitmap) collected from the same keys
+/// does.
+impl std::iter::FromIterator<usize> for VecBitmap {
+    fn from_iter<I: IntoIterator<Item = usize>>(iter: I) -> Self {
+        let keys = iter.into_iter().collect::<Vec<_>>();
+
+        let mut b = Self::new_with_capacity(keys.iter().max().map_or(0, |&k| k + 1));
+        for key in keys {
+            b.set(key, true);
+        }
+        b
+    }
+}
+
+impl PortableBitmap for VecBitmap {
+    const KIND: u8 = 0;
+
+    fn write_words<W: Write>(&self, mut w: W) -> std::io::Result<()> {
+        w.write_all(&(self.bitmap.len() as u64).to_le_bytes())?;
+        for word in &self.bitmap {
+            w.write_all(&(*word as u64).to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn read_words<R: Read>(mut r: R, max_key: usize) -> Result<Self, FormatError> {
+        // Invariant: the bitmap has exactly the number of words needed to hold
+        // max_key bits.
+        let n = read_usize(&mut r)?;
+        if n != index_for_key(max_key) + 1 {
+            return Err(LayoutError::Length.into());
+        }
+
+        let bitmap = read_words(r, n)?.into_iter().map(|v| v as usize).collect();
+
+        Ok(Self { bitmap, max_key })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use proptest::prelude::*;
@@ -74,7 +279,73 @@ mod tests {
 
     const MAX_KEY: usize = 1028;
 
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let mut b = VecBitmap::new_with_capacity(MAX_KEY);
+        b.set(1, true);
+        b.set(2, false);
+        b.set(MAX_KEY, true);
+
+        let encoded = serde_json::to_string(&b).unwrap();
+        let decoded: VecBitmap = serde_json::from_str(&encoded).unwrap();
+        assert_eq!(decoded, b);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_word_count_mismatch() {
+        let got = serde_json::from_str::<VecBitmap>(r#"{"bitmap":[0],"max_key":64}"#);
+        assert!(got.is_err());
+
+        let got = serde_json::from_str::<VecBitmap>(r#"{"bitmap":[0,0,0],"max_key":64}"#);
+        assert!(got.is_err());
+
+        let got = serde_json::from_str::<VecBitmap>(r#"{"bitmap":[0,0],"max_key":64}"#);
+        assert!(got.is_ok());
+    }
+
+    #[test]
+    fn test_from_iter() {
+        let b = vec![3, MAX_KEY, 1].into_iter().collect::<VecBitmap>();
+        assert_eq!(b.max_key, MAX_KEY + 1);
+        assert_eq!(b.iter_ones().collect::<Vec<_>>(), vec![1, 3, MAX_KEY]);
+
+        let b = std::iter::empty().collect::<VecBitmap>();
+        assert_eq!(b.count_ones(), 0);
+    }
+
+    #[test]
+    fn test_blocks() {
+        let mut b = VecBitmap::new_with_capacity(MAX_KEY);
+        b.set(1, true);
+        b.set(3, true);
+        b.set(MAX_KEY, true);
+
+        assert_eq!(
+            b.blocks().collect::<Vec<_>>(),
+            vec![(0, 0b1010), (MAX_KEY / 64, 1 << (MAX_KEY % 64))]
+        );
+    }
+
     proptest! {
+        #[test]
+        fn prop_iter_ones(
+            values in prop::collection::btree_set(0..MAX_KEY, 0..20),
+        ) {
+            let mut b = VecBitmap::new_with_capacity(MAX_KEY);
+            for v in &values {
+                b.set(*v, true);
+            }
+
+            // Invariant: the set bits are yielded in order from either end.
+            assert_eq!(b.iter_ones().collect::<Vec<_>>(), values.iter().copied().collect::<Vec<_>>());
+            assert_eq!(
+                b.iter_ones().rev().collect::<Vec<_>>(),
+                values.iter().rev().copied().collect::<Vec<_>>()
+            );
+        }
+
         #[test]
         fn prop_insert_contains(
             values in prop::collection::hash_set(0..MAX_KEY, 0..20),
@@ -89,6 +360,7 @@ mod tests {
             for i in 0..MAX_KEY {
                 assert_eq!(b.get(i), values.contains(&i));
             }
+            assert_eq!(b.count_ones(), values.len());
         }
 
         #[test]
@@ -124,5 +396,30 @@ mod tests {
                 assert_eq!(union.get(i), combined_bitmap.get(i));
             }
         }
+
+        #[test]
+        fn prop_and(
+            a in prop::collection::vec(0..MAX_KEY, 0..20),
+            b in prop::collection::vec(0..MAX_KEY, 0..20),
+        ) {
+            let mut a_bitmap = VecBitmap::new_with_capacity(MAX_KEY);
+            let mut b_bitmap = VecBitmap::new_with_capacity(MAX_KEY);
+
+            for v in a.iter() {
+                a_bitmap.set(*v, true);
+            }
+
+            for v in b.iter() {
+                b_bitmap.set(*v, true);
+            }
+
+            let intersection = a_bitmap.and(&b_bitmap);
+
+            // Invariant: the key space contains true entries only when the
+            // value appears in both a and b.
+            for i in 0..MAX_KEY {
+                assert_eq!(intersection.get(i), a.contains(&i) && b.contains(&i));
+            }
+        }
     }
 }
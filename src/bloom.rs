@@ -1,9 +1,13 @@
-use crate::{bitmap::CompressedBitmap, FilterSize, VecBitmap};
+use crate::{
+    bitmap::CompressedBitmap, CapacityError, ChunkScheme, ConcurrentBloom2, CounterWidth,
+    CountingBloom2, FilterParams, FilterSize, HashWidth, RotatingBloom2, ScalableBloom2,
+    SharedBitmap, VecBitmap,
+};
 use std::collections::hash_map::RandomState;
 use std::hash::{BuildHasher, Hash};
 use std::marker::PhantomData;
 
-// TODO(dom): AND, XOR, NOT + examples
+// TODO(dom): NOT + examples
 
 // [`Bloom2`]: crate::bloom2::Bloom2
 // [`BloomFilterBuilder`]: crate::BloomFilterBuilder
@@ -26,8 +30,67 @@ pub trait Bitmap {
     /// Return the size of the bitmap in bytes.
     fn byte_size(&self) -> usize;
 
+    /// Return the number of bits set to `true`.
+    fn count_ones(&self) -> usize;
+
     /// Return the bitwise OR of both `self` and `other`.`
     fn or(&self, other: &Self) -> Self;
+
+    /// Return the bitwise AND of both `self` and `other`.
+    fn and(&self, other: &Self) -> Self;
+
+    /// Set every bit indexed by `keys` to `true`.
+    ///
+    /// Implementations may override this to share the cost of locating the
+    /// storage for keys yielded consecutively from the same block.
+    fn set_all<I>(&mut self, keys: I)
+    where
+        I: IntoIterator<Item = usize>,
+        Self: Sized,
+    {
+        for key in keys {
+            self.set(key, true);
+        }
+    }
+
+    /// Write the value of the bit indexed by each of `keys` to the
+    /// corresponding element of `out`.
+    ///
+    /// Implementations may override this to share work between keys yielded
+    /// in ascending order.
+    fn get_many(&self, keys: &[usize], out: &mut [bool]) {
+        for (key, out) in keys.iter().zip(out) {
+            *out = self.get(*key);
+        }
+    }
+
+    /// Return `true` if every bit indexed by `keys` was previously set to
+    /// `true`.
+    ///
+    /// Implementations may override this to share the cost of locating the
+    /// storage for keys yielded consecutively from the same block.
+    fn get_all<I>(&self, keys: I) -> bool
+    where
+        I: IntoIterator<Item = usize>,
+        Self: Sized,
+    {
+        keys.into_iter().all(|key| self.get(key))
+    }
+
+    /// Hint that the bit indexed by `key` will be read or written soon,
+    /// allowing the storage holding it to be loaded into the CPU cache ahead
+    /// of the access.
+    ///
+    /// This is only a hint, and the default implementation does nothing.
+    fn prefetch(&self, key: usize) {
+        let _ = key;
+    }
+
+    /// The number of bits in each block that the probes for a single value are
+    /// confined to, or `None` if the probes address the whole bitmap.
+    ///
+    /// See [`BlockedBitmap`](crate::BlockedBitmap).
+    const BLOCK_BITS: Option<usize> = None;
 }
 
 /// Construct [`Bloom2`] instances with varying parameters.
@@ -50,6 +113,10 @@ where
     hasher: H,
     bitmap: B,
     key_size: FilterSize,
+    hashes: Option<u8>,
+    hash_width: HashWidth,
+    chunk_scheme: ChunkScheme,
+    params: Option<FilterParams>,
 }
 
 /// Initialise a `BloomFilterBuilder` that unless changed, will construct a
@@ -66,6 +133,10 @@ impl std::default::Default for BloomFilterBuilder<RandomState, CompressedBitmap>
             hasher: RandomState::default(),
             bitmap: CompressedBitmap::new(key_size_to_bits(size)),
             key_size: size,
+            hashes: None,
+            hash_width: HashWidth::default(),
+            chunk_scheme: ChunkScheme::default(),
+            params: None,
         }
     }
 }
@@ -84,7 +155,9 @@ where
     ///
     /// Providing a `bitmap` instance that is non-empty can be used to restore
     /// the state of a [`Bloom2`] instance (although using `serde` can achieve
-    /// this safely too).
+    /// this safely too). A `bitmap` populated by a prior release of this crate
+    /// may also require the [`ChunkScheme::Truncated`] chunk scheme - see
+    /// [`chunk_scheme()`](BloomFilterBuilder::chunk_scheme).
     pub fn with_bitmap_data(self, bitmap: B, key_size: FilterSize) -> Self {
         // Invariant: reading the last bit succeeds, ensuring it has sufficient
         // capacity.
@@ -93,6 +166,7 @@ where
         Self {
             bitmap,
             key_size,
+            params: None,
             ..self
         }
     }
@@ -105,6 +179,10 @@ where
             hasher: self.hasher,
             bitmap: U::new_with_capacity(key_size_to_bits(self.key_size)),
             key_size: self.key_size,
+            hashes: self.hashes,
+            hash_width: self.hash_width,
+            chunk_scheme: self.chunk_scheme,
+            params: self.params,
         }
     }
 
@@ -114,10 +192,89 @@ where
             hasher: self.hasher,
             bitmap: self.bitmap,
             key_size: self.key_size,
+            hashes: self.hashes,
+            hash_width: self.hash_width,
+            chunk_scheme: self.chunk_scheme,
             _key_type: PhantomData,
         }
     }
 
+    /// Initialise a [`CountingBloom2`] instance with the provided parameters,
+    /// using counters of the given `width`.
+    ///
+    /// The counting filter allocates its own counter storage - any bitmap
+    /// configured on this builder is discarded.
+    pub fn build_counting<T: Hash>(self, width: CounterWidth) -> CountingBloom2<H, T> {
+        CountingBloom2::new(
+            self.hasher,
+            self.key_size,
+            self.hashes,
+            self.hash_width,
+            width,
+        )
+    }
+
+    /// Initialise a [`ConcurrentBloom2`] instance with the provided
+    /// parameters, backed by a [`SharedBitmap`] of type `S`.
+    ///
+    /// The concurrent filter allocates its own bitmap - any bitmap configured
+    /// on this builder is discarded.
+    pub fn build_concurrent<S, T>(self) -> ConcurrentBloom2<H, S, T>
+    where
+        S: SharedBitmap,
+        T: Hash,
+    {
+        ConcurrentBloom2::new(
+            self.hasher,
+            S::new_with_capacity(key_size_to_bits(self.key_size)),
+            self.key_size,
+            self.hashes,
+            self.hash_width,
+        )
+    }
+
+    /// Initialise a [`RotatingBloom2`] instance with the provided parameters,
+    /// holding values inserted within the last `generations` rotations.
+    ///
+    /// The rotating filter allocates a [`CompressedBitmap`] for each
+    /// generation - any bitmap configured on this builder is discarded.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `generations` is 0.
+    pub fn build_rotating<T: Hash>(self, generations: usize) -> RotatingBloom2<H, T> {
+        RotatingBloom2::new(
+            self.hasher,
+            self.key_size,
+            self.hashes,
+            self.hash_width,
+            generations,
+        )
+    }
+
+    /// Initialise a [`ScalableBloom2`] instance, starting with a stage sized
+    /// for `initial_capacity` values and growing to bound the false positive
+    /// rate of the whole filter to `target_fpr`.
+    ///
+    /// The stages are sized by [`with_capacity()`], and any size or number of
+    /// hashes configured on this builder is discarded (the
+    /// [`hash_width()`](BloomFilterBuilder::hash_width) is retained).
+    ///
+    /// Returns an error if `target_fpr` is not within `(0, 1)`, or cannot be
+    /// reached by the first stage.
+    ///
+    /// [`with_capacity()`]: BloomFilterBuilder::with_capacity
+    pub fn build_scalable<T: Hash>(
+        self,
+        initial_capacity: usize,
+        target_fpr: f64,
+    ) -> Result<ScalableBloom2<H, B, T>, CapacityError>
+    where
+        H: Clone,
+    {
+        ScalableBloom2::new(self.hasher, self.hash_width, initial_capacity, target_fpr)
+    }
+
     /// Control the in-memory size and false-positive probability of the filter.
     ///
     /// Setting the bitmap size replaces the current `Bitmap` instance with a
@@ -128,6 +285,124 @@ where
         Self {
             key_size: size,
             bitmap: B::new_with_capacity(key_size_to_bits(size)),
+            params: None,
+            ..self
+        }
+    }
+
+    /// Size the filter to hold `expected_items` unique items with a false
+    /// positive rate of no more than `target_fpr`.
+    ///
+    /// The smallest [`FilterSize`] able to reach `target_fpr` is chosen, along
+    /// with the fewest [`hashes`](BloomFilterBuilder::hashes) that reach it -
+    /// each hash costs a probe per operation. The chosen parameters (and the
+    /// predicted false positive rate) are available from
+    /// [`params()`](BloomFilterBuilder::params).
+    ///
+    /// ```rust
+    /// use bloom2::{BloomFilterBuilder, FilterSize};
+    ///
+    /// let builder = BloomFilterBuilder::default()
+    ///     .with_capacity(10_000, 0.01)
+    ///     .expect("unreachable false positive rate");
+    ///
+    /// let params = builder.params().unwrap();
+    /// assert_eq!(params.size(), FilterSize::KeyBytes3);
+    /// assert!(params.predicted_fpr() <= 0.01);
+    ///
+    /// let mut filter = builder.build();
+    /// filter.insert(&"hello 🐐");
+    /// ```
+    ///
+    /// Returns an error if `target_fpr` is not within `(0, 1)`, or cannot be
+    /// reached by any filter size.
+    pub fn with_capacity(
+        self,
+        expected_items: usize,
+        target_fpr: f64,
+    ) -> Result<Self, CapacityError> {
+        let params = FilterParams::new(expected_items, target_fpr)?;
+
+        Ok(Self {
+            params: Some(params),
+            ..self.size(params.size()).hashes(params.hashes())
+        })
+    }
+
+    /// Return the parameters chosen by
+    /// [`with_capacity()`](BloomFilterBuilder::with_capacity).
+    ///
+    /// Returns `None` if the filter was not sized by `with_capacity()`, or the
+    /// size or number of hashes was subsequently changed.
+    pub fn params(&self) -> Option<&FilterParams> {
+        self.params.as_ref()
+    }
+
+    /// Set the number of bitmap keys (`k`) probed for each value.
+    ///
+    /// By default, the 64-bit hash of a value is split into as many
+    /// [`FilterSize`] sized keys as it holds, fixing `k` for each filter size
+    /// (for example, `k=2` for [`FilterSize::KeyBytes4`], or `k=4` with a
+    /// 128-bit [`hash_width()`](BloomFilterBuilder::hash_width)). Setting
+    /// `hashes` instead derives `k` keys from the hash using
+    /// [Kirsch-Mitzenmacher double hashing], allowing `k` to be tuned for the
+    /// expected load of the filter.
+    ///
+    /// ```rust
+    /// use bloom2::{BloomFilterBuilder, FilterSize};
+    ///
+    /// let mut filter = BloomFilterBuilder::default()
+    ///     .size(FilterSize::KeyBytes3)
+    ///     .hashes(7)
+    ///     .build();
+    ///
+    /// filter.insert(&"hello 🐐");
+    /// assert!(filter.contains(&"hello 🐐"));
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `k` is 0.
+    ///
+    /// [Kirsch-Mitzenmacher double hashing]:
+    ///     https://www.eecs.harvard.edu/~michaelm/postscripts/rsa2008.pdf
+    pub fn hashes(self, k: u8) -> Self {
+        assert!(k > 0, "a filter must probe at least one key per value");
+        Self {
+            hashes: Some(k),
+            params: None,
+            ..self
+        }
+    }
+
+    /// Set the width of the hash computed for each value, from which the
+    /// bitmap keys probed for it are derived.
+    ///
+    /// A [`HashWidth::Bits128`] filter hashes each value twice, doubling the
+    /// number of keys split from the hash - see [`HashWidth`]. Defaults to
+    /// [`HashWidth::Bits64`].
+    ///
+    /// The number of [`hashes`](BloomFilterBuilder::hashes) is unaffected, and
+    /// any [`params()`](BloomFilterBuilder::params) remain valid.
+    pub fn hash_width(self, width: HashWidth) -> Self {
+        Self {
+            hash_width: width,
+            ..self
+        }
+    }
+
+    /// Set the scheme splitting the hash of a value into [`FilterSize`] chunks,
+    /// for sizes that do not evenly divide it.
+    ///
+    /// Defaults to [`ChunkScheme::Extended`] - only filters restoring a bitmap
+    /// populated by a prior release require [`ChunkScheme::Truncated`]. The
+    /// scheme applies to filters initialised by [`build()`], as all other
+    /// filters allocate their own bitmap. See [`ChunkScheme`].
+    ///
+    /// [`build()`]: BloomFilterBuilder::build
+    pub fn chunk_scheme(self, scheme: ChunkScheme) -> Self {
+        Self {
+            chunk_scheme: scheme,
             ..self
         }
     }
@@ -147,11 +422,15 @@ where
             hasher,
             bitmap: CompressedBitmap::new(key_size_to_bits(size)),
             key_size: size,
+            hashes: None,
+            hash_width: HashWidth::default(),
+            chunk_scheme: ChunkScheme::default(),
+            params: None,
         }
     }
 }
 
-fn key_size_to_bits(k: FilterSize) -> usize {
+pub(crate) fn key_size_to_bits(k: FilterSize) -> usize {
     2_usize.pow(8 * k as u32)
 }
 
@@ -189,10 +468,16 @@ where
     B: Bitmap,
 {
     #[cfg_attr(feature = "serde", serde(skip))]
-    hasher: H,
-    bitmap: B,
-    key_size: FilterSize,
-    _key_type: PhantomData<T>,
+    pub(crate) hasher: H,
+    pub(crate) bitmap: B,
+    pub(crate) key_size: FilterSize,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) hashes: Option<u8>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) hash_width: HashWidth,
+    #[cfg_attr(feature = "serde", serde(default = "ChunkScheme::legacy"))]
+    pub(crate) chunk_scheme: ChunkScheme,
+    pub(crate) _key_type: PhantomData<T>,
 }
 
 /// Initialise a `Bloom2` instance using the default implementation of
@@ -269,13 +554,7 @@ where
     /// assert!(b.contains(&&user));
     /// ```
     pub fn insert(&mut self, data: &'_ T) {
-        // Generate a hash (u64) value for data and split the u64 hash into
-        // several smaller values to use as unique indexes in the bitmap.
-        self.hasher
-            .hash_one(data)
-            .to_be_bytes()
-            .chunks(self.key_size as usize)
-            .for_each(|chunk| self.bitmap.set(bytes_to_usize_key(chunk), true));
+        self.set_hash(self.hash_width.hash_one(&self.hasher, data))
     }
 
     /// Checks if `data` exists in the filter.
@@ -284,12 +563,251 @@ where
     /// previously. If `contains` returns false, `hash` has **definitely not**
     /// been inserted into the filter.
     pub fn contains(&self, data: &'_ T) -> bool {
-        // Generate a hash (u64) value for data
-        self.hasher
-            .hash_one(data)
-            .to_be_bytes()
-            .chunks(self.key_size as usize)
-            .any(|chunk| self.bitmap.get(bytes_to_usize_key(chunk)))
+        self.get_hash(self.hash_width.hash_one(&self.hasher, data))
+    }
+
+    /// Insert a value with the pre-computed `hash` into the filter, without
+    /// hashing it with the filter's [`BuildHasher`].
+    ///
+    /// The `hash` is mapped to bitmap keys exactly as
+    /// [`insert()`](Bloom2::insert) maps the hash of a value, so callers that
+    /// already hold a well distributed 64-bit hash of their values can skip
+    /// hashing them again:
+    ///
+    /// ```rust
+    /// use bloom2::Bloom2;
+    ///
+    /// let mut b: Bloom2<_, _, ()> = Bloom2::default();
+    /// b.insert_hash(0x9E37_79B9_7F4A_7C15);
+    ///
+    /// assert!(b.contains_hash(0x9E37_79B9_7F4A_7C15));
+    /// ```
+    ///
+    /// A value inserted with `insert_hash()` is only found by
+    /// [`contains()`](Bloom2::contains) if `hash` is the hash the filter's
+    /// hasher produces for it.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the filter uses a [`HashWidth::Bits128`] hash -
+    /// see [`insert_hash128()`](Bloom2::insert_hash128).
+    pub fn insert_hash(&mut self, hash: u64) {
+        assert_eq!(
+            self.hash_width,
+            HashWidth::Bits64,
+            "filter requires a 128-bit hash"
+        );
+        self.set_hash(u128::from(hash));
+    }
+
+    /// Checks if a value with the pre-computed `hash` exists in the filter,
+    /// without hashing it with the filter's [`BuildHasher`].
+    ///
+    /// See [`insert_hash()`](Bloom2::insert_hash).
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the filter uses a [`HashWidth::Bits128`] hash.
+    pub fn contains_hash(&self, hash: u64) -> bool {
+        assert_eq!(
+            self.hash_width,
+            HashWidth::Bits64,
+            "filter requires a 128-bit hash"
+        );
+        self.get_hash(u128::from(hash))
+    }
+
+    /// Insert a value with the pre-computed 128-bit `hash` into a
+    /// [`HashWidth::Bits128`] filter, without hashing it with the filter's
+    /// [`BuildHasher`].
+    ///
+    /// Callers already holding a well distributed 128-bit hash of their values
+    /// (such as from a 128-bit hash function) can provide it directly:
+    ///
+    /// ```rust
+    /// use bloom2::{Bloom2, BloomFilterBuilder, FilterSize, HashWidth};
+    ///
+    /// let mut b: Bloom2<_, _, ()> = BloomFilterBuilder::default()
+    ///     .size(FilterSize::KeyBytes4)
+    ///     .hash_width(HashWidth::Bits128)
+    ///     .build();
+    ///
+    /// b.insert_hash128(0x9E37_79B9_7F4A_7C15_F39C_C060_5CED_C834);
+    /// assert!(b.contains_hash128(0x9E37_79B9_7F4A_7C15_F39C_C060_5CED_C834));
+    /// ```
+    ///
+    /// A value inserted with `insert_hash128()` is only found by
+    /// [`contains()`](Bloom2::contains) if the high 64 bits of `hash` are the
+    /// hash the filter's hasher produces for it, and the low 64 bits the hash
+    /// of it prefixed with the filter's seed.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the filter uses a [`HashWidth::Bits64`] hash -
+    /// see [`insert_hash()`](Bloom2::insert_hash).
+    pub fn insert_hash128(&mut self, hash: u128) {
+        assert_eq!(
+            self.hash_width,
+            HashWidth::Bits128,
+            "filter requires a 64-bit hash"
+        );
+        self.set_hash(hash);
+    }
+
+    /// Checks if a value with the pre-computed 128-bit `hash` exists in a
+    /// [`HashWidth::Bits128`] filter, without hashing it with the filter's
+    /// [`BuildHasher`].
+    ///
+    /// See [`insert_hash128()`](Bloom2::insert_hash128).
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the filter uses a [`HashWidth::Bits64`] hash.
+    pub fn contains_hash128(&self, hash: u128) -> bool {
+        assert_eq!(
+            self.hash_width,
+            HashWidth::Bits128,
+            "filter requires a 64-bit hash"
+        );
+        self.get_hash(hash)
+    }
+
+    /// Set the keys derived from `hash`, a hash of the filter's width.
+    fn set_hash(&mut self, hash: u128) {
+        // Split the hash into several smaller values to use as unique indexes
+        // in the bitmap.
+        self.bitmap.set_all(self.keys(hash));
+    }
+
+    /// Return `true` if every key derived from `hash`, a hash of the filter's
+    /// width, is set.
+    fn get_hash(&self, hash: u128) -> bool {
+        // Check every key derived from the hash is set in the bitmap - a single
+        // unset bit proves the value was never inserted.
+        self.bitmap.get_all(self.keys(hash))
+    }
+
+    /// Return the bitmap keys probed for a value with `hash`, a hash of the
+    /// filter's width.
+    pub(crate) fn keys(&self, hash: u128) -> impl Iterator<Item = usize> {
+        probe_keys(
+            hash,
+            self.hash_width,
+            self.key_size,
+            self.hashes,
+            self.chunk_scheme,
+            B::BLOCK_BITS,
+        )
+    }
+
+    /// Return the bitmap keys (bit positions) `data` maps to, in probe order.
+    ///
+    /// These are the bits set by [`insert()`](Bloom2::insert), and checked by
+    /// [`contains()`](Bloom2::contains) - useful when debugging the
+    /// distribution of values within the filter:
+    ///
+    /// ```rust
+    /// use bloom2::{Bloom2, Bitmap};
+    ///
+    /// let mut b = Bloom2::default();
+    /// b.insert(&"hello 🐐");
+    ///
+    /// for key in b.probe_indices(&"hello 🐐") {
+    ///     assert!(b.bitmap().get(key));
+    /// }
+    /// ```
+    ///
+    /// A key may be repeated if two probes of the value collide.
+    pub fn probe_indices(&self, data: &'_ T) -> impl Iterator<Item = usize> {
+        self.keys(self.hash_width.hash_one(&self.hasher, data))
+    }
+
+    /// Insert each of `values` into the filter, as if by calling
+    /// [`insert()`](Bloom2::insert) for each.
+    ///
+    /// The values are hashed in batches, and the bitmap keys of each batch are
+    /// set in ascending order, prefetching the storage for the next keys (see
+    /// [`Bitmap::prefetch()`]) while setting each - for a
+    /// [`CompressedBitmap`], the block map popcounts locating the blocks are
+    /// shared between ascending keys.
+    pub fn insert_many(&mut self, values: &[T]) {
+        self.extend(values);
+    }
+
+    /// Check if each of `values` exists in the filter, writing the result for
+    /// each to the corresponding element of `out`, as if by calling
+    /// [`contains()`](Bloom2::contains) for each.
+    ///
+    /// ```rust
+    /// use bloom2::Bloom2;
+    ///
+    /// let mut b = Bloom2::default();
+    /// b.insert_many(&["cat", "dog"]);
+    ///
+    /// let mut out = [false; 3];
+    /// b.contains_many(&["cat", "goat", "dog"], &mut out);
+    /// assert_eq!(out, [true, false, true]);
+    /// ```
+    ///
+    /// The values are hashed in batches, and the probes of each batch then
+    /// resolved in a single pass, grouped by the region of the bitmap they
+    /// fall in. The storage for the next probes is prefetched (see
+    /// [`Bitmap::prefetch()`]) while resolving each, so the cache misses of
+    /// the batch overlap rather than each lookup waiting on its own miss. For
+    /// a [`CompressedBitmap`], the block map popcounts locating the blocks are
+    /// shared between the probes of each region.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `values` and `out` are not the same length.
+    pub fn contains_many(&self, values: &[T], out: &mut [bool]) {
+        assert_eq!(
+            values.len(),
+            out.len(),
+            "values and out must be the same length"
+        );
+
+        // The (key, value index) of each probe in a batch, and the bit value
+        // at each key.
+        let mut probes = Vec::new();
+        let mut scratch = Vec::new();
+        let mut keys = Vec::new();
+        let mut bits = Vec::new();
+
+        for (values, out) in values.chunks(BATCH_SIZE).zip(out.chunks_mut(BATCH_SIZE)) {
+            probes.clear();
+            for (i, v) in values.iter().enumerate() {
+                let hash = self.hash_width.hash_one(&self.hasher, v);
+                for key in self.keys(hash) {
+                    probes.push((key, i));
+                }
+            }
+            sort_probes(&mut probes, &mut scratch, self.key_size);
+
+            keys.clear();
+            keys.extend(probes.iter().map(|&(key, _)| key));
+            bits.clear();
+            bits.resize(keys.len(), false);
+
+            // Resolve the probes in windows, prefetching the storage for the
+            // next window of probes while resolving the current one.
+            let mut windows = keys
+                .chunks(PREFETCH_KEYS)
+                .zip(bits.chunks_mut(PREFETCH_KEYS))
+                .peekable();
+            while let Some((keys, bits)) = windows.next() {
+                if let Some((next, _)) = windows.peek() {
+                    next.iter().for_each(|&key| self.bitmap.prefetch(key));
+                }
+                self.bitmap.get_many(keys, bits);
+            }
+
+            // A value is contained only if every one of its probes is set.
+            out.fill(true);
+            for (&(_, i), &bit) in probes.iter().zip(&bits) {
+                out[i] &= bit;
+            }
+        }
     }
 
     /// Union two [`Bloom2`] instances (of identical configuration), returning
@@ -306,13 +824,117 @@ where
     /// configuration.
     pub fn union(&mut self, other: &Self) {
         assert_eq!(self.key_size, other.key_size);
+        assert_eq!(self.hashes, other.hashes);
+        assert_eq!(self.hash_width, other.hash_width);
+        assert_eq!(self.chunk_scheme, other.chunk_scheme);
         self.bitmap = self.bitmap.or(&other.bitmap);
     }
 
+    /// Intersect two [`Bloom2`] instances (of identical configuration),
+    /// retaining only the bits set in both.
+    ///
+    /// The resulting filter will return "true" for all calls to
+    /// [`Bloom2::contains()`] for values that were inserted into both of the
+    /// inputs, and "false" for all values that return false from either input.
+    /// This allows an approximate semi-join between two datasets without
+    /// rebuilding either filter.
+    ///
+    /// Unlike [`Bloom2::union()`], the intersection may return true for values
+    /// that were inserted into only one of the inputs, in addition to the
+    /// false positives of the inputs - the bits for a value inserted into one
+    /// filter may all be set in the other by coincidence.
+    ///
+    /// ```rust
+    /// use bloom2::Bloom2;
+    ///
+    /// let mut a = Bloom2::default();
+    /// a.insert(&"cat");
+    /// a.insert(&"dog");
+    ///
+    /// let mut b = a.clone();
+    /// b.insert(&"goat");
+    /// a.insert(&"bat");
+    ///
+    /// a.intersect(&b);
+    /// assert!(a.contains(&"cat"));
+    /// assert!(a.contains(&"dog"));
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the two [`Bloom2`] instances have different
+    /// configuration.
+    pub fn intersect(&mut self, other: &Self) {
+        assert_eq!(self.key_size, other.key_size);
+        assert_eq!(self.hashes, other.hashes);
+        assert_eq!(self.hash_width, other.hash_width);
+        assert_eq!(self.chunk_scheme, other.chunk_scheme);
+        self.bitmap = self.bitmap.and(&other.bitmap);
+    }
+
     /// Return the byte size of this filter.
     pub fn byte_size(&mut self) -> usize {
         self.bitmap.byte_size()
     }
+
+    /// Return the fraction of bits set in the filter, from `0.0` when empty to
+    /// `1.0` when every bit is set.
+    pub fn fill_ratio(&self) -> f64 {
+        self.bitmap.count_ones() as f64 / key_size_to_bits(self.key_size) as f64
+    }
+
+    /// Estimate the number of unique values inserted into the filter from the
+    /// number of bits set, using the [Swamidass-Baldi estimator]:
+    ///
+    /// ```text
+    ///     n = -(m / k) * ln(1 - X / m)
+    /// ```
+    ///
+    /// Where `m` is the number of bits in the filter, `k` the number of keys
+    /// probed per value, and `X` the number of bits set.
+    ///
+    /// Returns [`f64::INFINITY`] if every bit in the filter is set.
+    ///
+    /// ```rust
+    /// use bloom2::Bloom2;
+    ///
+    /// let mut b = Bloom2::default();
+    /// for i in 0..1_000 {
+    ///     b.insert(&i);
+    /// }
+    ///
+    /// let n = b.estimated_len();
+    /// assert!(n > 900.0 && n < 1_100.0);
+    /// ```
+    ///
+    /// [Swamidass-Baldi estimator]: https://doi.org/10.1021/ci600358f
+    pub fn estimated_len(&self) -> f64 {
+        let m = key_size_to_bits(self.key_size) as f64;
+        let k = num_probes(self.key_size, self.hashes, self.hash_width, B::BLOCK_BITS) as f64;
+
+        // ln_1p() retains precision for lightly loaded filters.
+        -(m / k) * (-self.fill_ratio()).ln_1p()
+    }
+
+    /// Estimate the probability of [`contains()`](Bloom2::contains) returning
+    /// a false positive for a value never inserted, given the current
+    /// [`fill_ratio()`](Bloom2::fill_ratio) of the filter.
+    ///
+    /// This tracks the actual load of the filter, making it suitable for
+    /// detecting a long-lived filter becoming saturated.
+    pub fn estimated_fpr(&self) -> f64 {
+        self.fill_ratio().powi(num_probes(
+            self.key_size,
+            self.hashes,
+            self.hash_width,
+            B::BLOCK_BITS,
+        ) as i32)
+    }
+
+    /// Return a reference to the underlying bitmap storage.
+    pub fn bitmap(&self) -> &B {
+        &self.bitmap
+    }
 }
 
 impl<H, T> Bloom2<H, CompressedBitmap, T>
@@ -340,51 +962,453 @@ where
     }
 }
 
-fn bytes_to_usize_key<'a, I: IntoIterator<Item = &'a u8>>(bytes: I) -> usize {
-    bytes
-        .into_iter()
-        .fold(0, |key, &byte| (key << 8) | byte as usize)
+/// Derive the bitmap keys probed for a value with `hash` (of `width`) in a
+/// filter of `key_size`.
+///
+/// If `hashes` is set, that many keys are derived by double hashing, otherwise
+/// `hash` is split into `key_size` chunks.
+pub(crate) fn hash_to_keys(
+    hash: u128,
+    width: HashWidth,
+    key_size: FilterSize,
+    hashes: Option<u8>,
+) -> impl Iterator<Item = usize> {
+    probe_keys(hash, width, key_size, hashes, ChunkScheme::Extended, None)
 }
 
-impl<H, T> From<Bloom2<H, VecBitmap, T>> for Bloom2<H, CompressedBitmap, T>
-where
-    H: BuildHasher,
-{
-    fn from(v: Bloom2<H, VecBitmap, T>) -> Self {
-        Self {
-            hasher: v.hasher,
-            bitmap: CompressedBitmap::from(v.bitmap),
-            key_size: v.key_size,
-            _key_type: PhantomData,
+/// Derive the bitmap keys probed for a value with `hash` (of `width`) in a
+/// filter of `key_size`, split by `scheme` or confined to a single block of
+/// `block_bits` if set (see [`Bitmap::BLOCK_BITS`]).
+pub(crate) fn probe_keys(
+    hash: u128,
+    width: HashWidth,
+    key_size: FilterSize,
+    hashes: Option<u8>,
+    scheme: ChunkScheme,
+    block_bits: Option<usize>,
+) -> impl Iterator<Item = usize> {
+    match (block_bits, hashes) {
+        (Some(bits), k) => {
+            // A 64-bit hash is split between block and bit selection, while a
+            // 128-bit hash selects the block with its second half, leaving
+            // both halves of the first to select bits.
+            let (block, h) = match width {
+                HashWidth::Bits64 => ((hash >> 32) as u64, [hash as u32; 2]),
+                HashWidth::Bits128 => (hash as u64, [(hash >> 64) as u32, (hash >> 96) as u32]),
+            };
+            Probes::Blocked(blocked_keys(
+                block,
+                h,
+                key_size,
+                k.unwrap_or(BLOCKED_HASHES),
+                bits,
+            ))
         }
+        (None, None) => Probes::Chunks(chunk_keys(hash, width, key_size, scheme)),
+        (None, Some(k)) => Probes::Hashes(double_hash_keys(hash, width, key_size, k)),
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use proptest::prelude::*;
-    use quickcheck_macros::quickcheck;
-
-    use std::{
-        cell::RefCell,
-        collections::HashSet,
-        hash::{BuildHasherDefault, Hasher},
+/// Split `hash` (of `width`) into `key_size` chunks, one for each (possibly
+/// partial) chunk of `key_size` it holds.
+///
+/// If `key_size` does not evenly divide the bytes of `hash` (as for
+/// [`FilterSize::KeyBytes3`] and [`FilterSize::KeyBytes5`]) the final chunk is
+/// completed with the high bits of [`extend_hash()`] - every key then spans
+/// the full key space, rather than only the prefix of it addressable by the
+/// bytes remaining in `hash`. The [`ChunkScheme::Truncated`] scheme of prior
+/// releases uses the remaining bytes alone.
+fn chunk_keys(
+    hash: u128,
+    width: HashWidth,
+    key_size: FilterSize,
+    scheme: ChunkScheme,
+) -> impl Iterator<Item = usize> {
+    let bits = 8 * key_size as u32;
+    let hash_bits = width.bits();
+    let extension = match (hash_bits % bits, scheme) {
+        (0, _) | (_, ChunkScheme::Truncated) => 0,
+        _ => extend_hash((hash >> 64) as u64 ^ hash as u64),
     };
 
-    #[derive(Debug, Clone, Default)]
-    struct MockHasher {
-        return_hash: u64,
-    }
+    // Each key is read from the big-endian concatenation of hash and its
+    // extension.
+    (0..hash_bits).step_by(bits as usize).map(move |offset| {
+        let end = offset + bits;
+        let (key, key_bits) = match (end.checked_sub(hash_bits), scheme) {
+            (None, _) | (Some(0), _) => (hash >> (hash_bits - end), bits),
+            (Some(spill), ChunkScheme::Truncated) => (hash, bits - spill),
+            (Some(spill), ChunkScheme::Extended) => (
+                (hash << spill) | u128::from(extension >> (u64::BITS - spill)),
+                bits,
+            ),
+        };
+        (key & ((1 << key_bits) - 1)) as usize
+    })
+}
 
-    impl Hasher for MockHasher {
-        fn write(&mut self, _bytes: &[u8]) {}
-        fn finish(&self) -> u64 {
-            self.return_hash
-        }
-    }
+/// Derive 64 further bits from `hash`, using the SplitMix64 finaliser.
+///
+/// The finaliser is a bijection with full avalanche, so the derived bits are
+/// uniformly distributed when `hash` is, and appear independent of it.
+fn extend_hash(hash: u64) -> u64 {
+    let mut z = hash.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
 
-    impl BuildHasher for MockHasher {
+/// Derive `k` keys from `hash` using Kirsch-Mitzenmacher double hashing:
+///
+/// ```text
+///     key_i = h1 + i * h2 (mod 2^bits)
+/// ```
+///
+/// For a 64-bit `hash`, `h1` is the whole hash and `h2` is the hash rotated
+/// by 32 bits, so the low 32 bits of `h2` are the high half of `hash`. Key
+/// spaces wider than 32 bits ([`FilterSize::KeyBytes5`]) also use the bits of
+/// `h2` above bit 32, which are the low bits of `h1` - `h1` and `h2` are not
+/// independent for these filters. For a 128-bit `hash`, `h1` and `h2` are the
+/// independent first and second 64-bit hashes respectively.
+///
+/// `h2` is forced to be odd, making it coprime with the (power of 2) key space
+/// so that the keys for a single value never repeat before the key space is
+/// exhausted.
+fn double_hash_keys(
+    hash: u128,
+    width: HashWidth,
+    key_size: FilterSize,
+    k: u8,
+) -> impl Iterator<Item = usize> {
+    let mask = key_size_to_bits(key_size) as u64 - 1;
+    let h1 = width.first_u64(hash);
+    let h2 = match width {
+        HashWidth::Bits64 => h1.rotate_left(32),
+        HashWidth::Bits128 => hash as u64,
+    } | 1;
+
+    (0..u64::from(k)).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) & mask) as usize)
+}
+
+/// The number of keys probed for each value in a blocked filter, unless set
+/// with [`BloomFilterBuilder::hashes()`] - one for each word of a 512 bit
+/// block.
+const BLOCKED_HASHES: u8 = 8;
+
+/// The odd multipliers selecting the bit within each word of a block, from
+/// the split block bloom filter used by Apache Parquet.
+const BLOCK_SALTS: [u32; 8] = [
+    0x47b6137b, 0x44974d91, 0x8824ad5b, 0xa2b7289d, 0x705495c7, 0x2df1424b, 0x9efc4947, 0x5c6bfb31,
+];
+
+/// Derive `k` keys within a single block of `block_bits`.
+///
+/// The `block` hash selects the block, and the `h` hashes (alternating between
+/// probes) are multiplied by a per-probe salt to select a bit in word `i` (mod
+/// words per block) of the block - each probe costs a multiply and shift, and
+/// all probes fall within a single cache line.
+fn blocked_keys(
+    block: u64,
+    h: [u32; 2],
+    key_size: FilterSize,
+    k: u8,
+    block_bits: usize,
+) -> impl Iterator<Item = usize> {
+    let bits = key_size_to_bits(key_size);
+
+    // Filters smaller than a block are a single block.
+    let block_bits = block_bits.min(bits);
+    let block = (block as usize) & (bits / block_bits - 1);
+    let base = block * block_bits;
+    let words = block_bits / u64::BITS as usize;
+
+    (0..usize::from(k)).map(move |i| {
+        // Probes beyond the first 8 use rotated salts, forced to be odd.
+        let salt =
+            BLOCK_SALTS[i % BLOCK_SALTS.len()].rotate_left((i / BLOCK_SALTS.len()) as u32 * 5) | 1;
+        let bit = (h[i % 2].wrapping_mul(salt) >> 26) as usize;
+
+        base + (i % words) * u64::BITS as usize + bit
+    })
+}
+
+/// Return the number of keys probed for each value in a filter of `key_size`
+/// (see [`probe_keys()`]).
+pub(crate) fn num_probes(
+    key_size: FilterSize,
+    hashes: Option<u8>,
+    width: HashWidth,
+    block_bits: Option<usize>,
+) -> usize {
+    match (block_bits, hashes) {
+        (_, Some(k)) => k as usize,
+        (Some(_), None) => BLOCKED_HASHES as usize,
+        (None, None) => (width.bits() as usize / 8).div_ceil(key_size as usize),
+    }
+}
+
+/// An iterator of keys produced by one of the probe strategies.
+enum Probes<C, D, E> {
+    Chunks(C),
+    Hashes(D),
+    Blocked(E),
+}
+
+impl<C, D, E> Iterator for Probes<C, D, E>
+where
+    C: Iterator<Item = usize>,
+    D: Iterator<Item = usize>,
+    E: Iterator<Item = usize>,
+{
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        match self {
+            Self::Chunks(v) => v.next(),
+            Self::Hashes(v) => v.next(),
+            Self::Blocked(v) => v.next(),
+        }
+    }
+}
+
+/// The number of values hashed before their keys are resolved in the bitmap
+/// when extending a [`Bloom2`], or by the batch operations.
+const BATCH_SIZE: usize = 1024;
+
+/// The number of keys of a batch resolved at a time, while prefetching the
+/// storage for the next keys.
+///
+/// Prefetching only a short distance ahead keeps the prefetched cache lines
+/// from being evicted before they are read.
+const PREFETCH_KEYS: usize = 16;
+
+/// Set the keys for each hash (of `width`) yielded by `iter` in `bitmap`.
+///
+/// The keys for a batch of hashes are collected and set in ascending order,
+/// improving the locality of the bitmap writes, and skipping duplicate keys.
+/// The storage for each key is prefetched shortly before it is set.
+fn set_hashes<B, I>(
+    bitmap: &mut B,
+    key_size: FilterSize,
+    hashes: Option<u8>,
+    width: HashWidth,
+    scheme: ChunkScheme,
+    iter: I,
+) where
+    B: Bitmap,
+    I: IntoIterator<Item = u128>,
+{
+    let mut iter = iter.into_iter();
+    let mut keys = Vec::new();
+
+    loop {
+        keys.clear();
+        for hash in iter.by_ref().take(BATCH_SIZE) {
+            keys.extend(probe_keys(
+                hash,
+                width,
+                key_size,
+                hashes,
+                scheme,
+                B::BLOCK_BITS,
+            ));
+        }
+        if keys.is_empty() {
+            return;
+        }
+
+        keys.sort_unstable();
+        keys.dedup();
+
+        // Set the keys in windows, prefetching the storage for the next window
+        // of keys while setting the current one.
+        let mut windows = keys.chunks(PREFETCH_KEYS).peekable();
+        while let Some(window) = windows.next() {
+            if let Some(next) = windows.peek() {
+                next.iter().for_each(|&key| bitmap.prefetch(key));
+            }
+            bitmap.set_all(window.iter().copied());
+        }
+    }
+}
+
+/// The number of low bits of a key ignored when ordering the probes of a
+/// batch - the probes are grouped by each 64KiB span of keys, one
+/// [`CompressedBitmap`] superblock.
+const PROBE_ORDER_SHIFT: usize = 16;
+
+/// Order `probes` by key, ignoring the low [`PROBE_ORDER_SHIFT`] bits, using
+/// `scratch` as an intermediate buffer.
+///
+/// The keys of a filter of `key_size` span exactly `key_size` bytes, of which
+/// the high bytes are ordered with a (stable) LSD radix sort of one pass per
+/// byte - for the thousands of probes in a batch, this is several times faster
+/// than a comparison sort, and the probes within each group need no ordering
+/// to share their block map popcounts.
+///
+/// If there are fewer probes than groups (such as in a large
+/// [`FilterSize::KeyBytes4`] filter) few probes would share a group, and the
+/// cost of ordering them outweighs the work saved - they are left unordered.
+fn sort_probes(
+    probes: &mut Vec<(usize, usize)>,
+    scratch: &mut Vec<(usize, usize)>,
+    key_size: FilterSize,
+) {
+    if probes.len() < key_size_to_bits(key_size) >> PROBE_ORDER_SHIFT {
+        return;
+    }
+
+    scratch.clear();
+    scratch.resize(probes.len(), (0, 0));
+
+    for shift in (PROBE_ORDER_SHIFT..8 * key_size as usize).step_by(8) {
+        // Compute the start offset of each digit in the output.
+        let mut offsets = [0_usize; 256];
+        for &(key, _) in probes.iter() {
+            offsets[(key >> shift) & 0xFF] += 1;
+        }
+        let mut start = 0;
+        for v in offsets.iter_mut() {
+            let n = *v;
+            *v = start;
+            start += n;
+        }
+
+        for &probe in probes.iter() {
+            let digit = (probe.0 >> shift) & 0xFF;
+            scratch[offsets[digit]] = probe;
+            offsets[digit] += 1;
+        }
+        std::mem::swap(probes, scratch);
+    }
+}
+
+/// Insert each value yielded by the iterator, as if by calling
+/// [`Bloom2::insert()`] for each.
+///
+/// Values are hashed in batches, with the resulting bitmap keys set in
+/// ascending order.
+impl<'a, H, B, T> Extend<&'a T> for Bloom2<H, B, T>
+where
+    H: BuildHasher,
+    B: Bitmap,
+    T: Hash + 'a,
+{
+    fn extend<I: IntoIterator<Item = &'a T>>(&mut self, iter: I) {
+        let hasher = &self.hasher;
+        let width = self.hash_width;
+        set_hashes(
+            &mut self.bitmap,
+            self.key_size,
+            self.hashes,
+            width,
+            self.chunk_scheme,
+            iter.into_iter().map(|v| width.hash_one(hasher, v)),
+        );
+    }
+}
+
+/// Insert each value yielded by the iterator, as if by calling
+/// [`Bloom2::insert()`] for each.
+///
+/// Values are hashed in batches, with the resulting bitmap keys set in
+/// ascending order.
+impl<H, B, T> Extend<T> for Bloom2<H, B, T>
+where
+    H: BuildHasher,
+    B: Bitmap,
+    T: Hash,
+{
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        let hasher = &self.hasher;
+        let width = self.hash_width;
+        set_hashes(
+            &mut self.bitmap,
+            self.key_size,
+            self.hashes,
+            width,
+            self.chunk_scheme,
+            iter.into_iter().map(|v| width.hash_one(hasher, &v)),
+        );
+    }
+}
+
+/// Construct a [`Bloom2`] using the default [`BloomFilterBuilder`]
+/// configuration, containing every value yielded by the iterator.
+///
+/// ```rust
+/// use bloom2::Bloom2;
+///
+/// let b: Bloom2<_, _, _> = ["cat", "dog"].iter().collect();
+/// assert!(b.contains(&"cat"));
+/// ```
+impl<'a, T> std::iter::FromIterator<&'a T> for Bloom2<RandomState, CompressedBitmap, T>
+where
+    T: Hash + 'a,
+{
+    fn from_iter<I: IntoIterator<Item = &'a T>>(iter: I) -> Self {
+        let mut b = Self::default();
+        b.extend(iter);
+        b
+    }
+}
+
+/// Construct a [`Bloom2`] using the default [`BloomFilterBuilder`]
+/// configuration, containing every value yielded by the iterator.
+impl<T> std::iter::FromIterator<T> for Bloom2<RandomState, CompressedBitmap, T>
+where
+    T: Hash,
+{
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut b = Self::default();
+        b.extend(iter);
+        b
+    }
+}
+
+impl<H, T> From<Bloom2<H, VecBitmap, T>> for Bloom2<H, CompressedBitmap, T>
+where
+    H: BuildHasher,
+{
+    fn from(v: Bloom2<H, VecBitmap, T>) -> Self {
+        Self {
+            hasher: v.hasher,
+            bitmap: CompressedBitmap::from(v.bitmap),
+            key_size: v.key_size,
+            hashes: v.hashes,
+            hash_width: v.hash_width,
+            chunk_scheme: v.chunk_scheme,
+            _key_type: PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BlockedBitmap;
+    use proptest::prelude::*;
+    use quickcheck_macros::quickcheck;
+
+    use std::{
+        cell::RefCell,
+        collections::HashSet,
+        hash::{BuildHasherDefault, Hasher},
+    };
+
+    #[derive(Debug, Clone, Default)]
+    struct MockHasher {
+        return_hash: u64,
+    }
+
+    impl Hasher for MockHasher {
+        fn write(&mut self, _bytes: &[u8]) {}
+        fn finish(&self) -> u64 {
+            self.return_hash
+        }
+    }
+
+    impl BuildHasher for MockHasher {
         type Hasher = Self;
         fn build_hasher(&self) -> MockHasher {
             self.clone()
@@ -408,10 +1432,18 @@ mod tests {
             42
         }
 
+        fn count_ones(&self) -> usize {
+            unreachable!()
+        }
+
         fn or(&self, _other: &Self) -> Self {
             unreachable!()
         }
 
+        fn and(&self, _other: &Self) -> Self {
+            unreachable!()
+        }
+
         fn new_with_capacity(_max_key: usize) -> Self {
             Self::default()
         }
@@ -422,6 +1454,9 @@ mod tests {
             hasher: MockHasher::default(),
             bitmap: MockBitmap::default(),
             key_size: FilterSize::KeyBytes1,
+            hashes: None,
+            hash_width: HashWidth::Bits64,
+            chunk_scheme: ChunkScheme::Extended,
             _key_type: PhantomData,
         }
     }
@@ -467,11 +1502,10 @@ mod tests {
             ]
         );
 
-        b.contains(&[1, 2, 3, 4]);
-        assert_eq!(
-            b.bitmap.get_calls.into_inner(),
-            vec![171, 84, 169, 140, 235, 31, 10, 210]
-        );
+        // The mock bitmap reports every bit as unset, so the lookup stops at
+        // the first probe.
+        assert!(!b.contains(&[1, 2, 3, 4]));
+        assert_eq!(b.bitmap.get_calls.into_inner(), vec![171]);
     }
 
     #[test]
@@ -489,6 +1523,155 @@ mod tests {
         assert!(b.bitmap.get_calls.into_inner().is_empty());
     }
 
+    #[test]
+    fn test_insert_contains_hashes() {
+        let mut b = new_test_bloom();
+        b.key_size = FilterSize::KeyBytes2;
+        b.hashes = Some(6);
+        b.hasher.return_hash = 12345678901234567890;
+
+        b.insert(&[1, 2, 3, 4]);
+
+        // The keys step through the key space by the (odd) high half of the
+        // hash, starting at the low half.
+        let h1 = 12345678901234567890_u64 & 0xFFFF;
+        let h2 = (12345678901234567890_u64 >> 32) & 0xFFFF | 1;
+        let want = (0..6)
+            .map(|i| (((h1 + i * h2) & 0xFFFF) as usize, true))
+            .collect::<Vec<_>>();
+        assert_eq!(b.bitmap.set_calls, want);
+
+        assert!(!b.contains(&[1, 2, 3, 4]));
+        assert_eq!(b.bitmap.get_calls.into_inner(), vec![want[0].0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one key")]
+    fn test_hashes_zero() {
+        let _ = BloomFilterBuilder::default().hashes(0);
+    }
+
+    #[quickcheck]
+    fn test_hashes_distinct(hash: u64, k: u8) {
+        // Invariant: the keys for a single value never repeat while the key
+        // space is not exhausted.
+        let k = k.max(1);
+        let keys = hash_to_keys(
+            u128::from(hash),
+            HashWidth::Bits64,
+            FilterSize::KeyBytes1,
+            Some(k),
+        )
+        .collect::<Vec<_>>();
+        assert_eq!(keys.len(), k as usize);
+        assert_eq!(keys.iter().collect::<HashSet<_>>().len(), keys.len());
+    }
+
+    #[quickcheck]
+    fn test_insert_hash(values: Vec<u32>, check: Vec<u32>, hashes: Option<u8>) {
+        let new = || {
+            let mut b =
+                BloomFilterBuilder::hasher(BuildHasherDefault::<twox_hash::XxHash64>::default())
+                    .size(FilterSize::KeyBytes2)
+                    .build();
+            b.hashes = hashes.map(|k| k.max(1));
+            b
+        };
+
+        let mut want = new();
+        let mut got = new();
+        for v in &values {
+            want.insert(v);
+            got.insert_hash(got.hasher.hash_one(v));
+        }
+
+        // Invariant: inserting the hash of a value is equivalent to inserting
+        // the value.
+        assert_eq!(got, want);
+        for v in values.iter().chain(&check) {
+            assert_eq!(got.contains_hash(got.hasher.hash_one(v)), want.contains(v));
+        }
+
+        // Invariant: the probe indices of a value are exactly the bits it
+        // sets.
+        for v in &values {
+            let keys = got.probe_indices(v).collect::<Vec<_>>();
+            assert_eq!(
+                keys.len(),
+                num_probes(got.key_size, got.hashes, HashWidth::Bits64, None)
+            );
+            assert!(keys.iter().all(|&key| got.bitmap.get(key)));
+
+            let mut b = new();
+            b.insert(v);
+            assert_eq!(
+                b.bitmap.iter_ones().collect::<HashSet<_>>(),
+                keys.into_iter().collect::<HashSet<_>>()
+            );
+        }
+    }
+
+    #[quickcheck]
+    fn test_hash_width_128(values: Vec<u32>, check: Vec<u32>, hashes: Option<u8>) {
+        let new = || {
+            let mut b =
+                BloomFilterBuilder::hasher(BuildHasherDefault::<twox_hash::XxHash64>::default())
+                    .size(FilterSize::KeyBytes4)
+                    .hash_width(HashWidth::Bits128)
+                    .build();
+            b.hashes = hashes.map(|k| k.max(1));
+            b
+        };
+        let hash = |b: &Bloom2<_, _, _>, v| HashWidth::Bits128.hash_one(&b.hasher, v);
+
+        let mut want = new();
+        let mut got = new();
+        let mut batch = new();
+        for v in &values {
+            want.insert(v);
+            got.insert_hash128(hash(&got, v));
+        }
+        batch.insert_many(&values);
+
+        // Invariant: inserting the hash of a value is equivalent to inserting
+        // the value, as is inserting it in a batch.
+        assert_eq!(got, want);
+        assert_eq!(batch, want);
+
+        let mut out = vec![false; check.len()];
+        want.contains_many(&check, &mut out);
+        for (v, &contains) in check.iter().zip(&out) {
+            assert_eq!(want.contains(v), contains);
+            assert_eq!(got.contains_hash128(hash(&got, v)), contains);
+        }
+
+        // Invariant: no false negatives, with every probe of the 128-bit hash
+        // set.
+        for v in &values {
+            assert!(want.contains(v));
+            assert_eq!(
+                want.probe_indices(v).count(),
+                hashes.map_or(4, |k| k.max(1) as usize)
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "requires a 128-bit hash")]
+    fn test_insert_hash_wide() {
+        let mut b: Bloom2<_, _, ()> = BloomFilterBuilder::default()
+            .hash_width(HashWidth::Bits128)
+            .build();
+        b.insert_hash(42);
+    }
+
+    #[test]
+    #[should_panic(expected = "requires a 64-bit hash")]
+    fn test_contains_hash128_narrow() {
+        let b: Bloom2<_, _, ()> = BloomFilterBuilder::default().build();
+        b.contains_hash128(42);
+    }
+
     #[test]
     fn test_issue_3() {
         let mut bloom_filter: Bloom2<RandomState, CompressedBitmap, &str> =
@@ -504,8 +1687,10 @@ mod tests {
 
     #[test]
     fn test_size_shrink() {
-        let mut bloom_filter: Bloom2<RandomState, CompressedBitmap, _> =
-            BloomFilterBuilder::default()
+        // A fixed hasher places every key in a distinct segment, making the
+        // allocated sizes deterministic.
+        let mut bloom_filter: Bloom2<BuildHasherDefault<twox_hash::XxHash64>, CompressedBitmap, _> =
+            BloomFilterBuilder::hasher(BuildHasherDefault::<twox_hash::XxHash64>::default())
                 .size(FilterSize::KeyBytes4)
                 .build();
 
@@ -513,9 +1698,9 @@ mod tests {
             bloom_filter.insert(&i);
         }
 
-        assert_eq!(bloom_filter.byte_size(), 8388920);
+        assert_eq!(bloom_filter.byte_size(), 8652240);
         bloom_filter.shrink_to_fit();
-        assert_eq!(bloom_filter.byte_size(), 8388824);
+        assert_eq!(bloom_filter.byte_size(), 8651472);
     }
 
     #[test]
@@ -583,6 +1768,768 @@ mod tests {
         }
     }
 
+    #[quickcheck]
+    fn test_intersect(mut a: Vec<usize>, mut b: Vec<usize>, mut control: Vec<usize>) {
+        // Reduce the test state space.
+        a.truncate(50);
+        b.truncate(50);
+        control.truncate(100);
+
+        let mut bitmap_a =
+            BloomFilterBuilder::hasher(BuildHasherDefault::<twox_hash::XxHash64>::default())
+                .size(FilterSize::KeyBytes2)
+                .build();
+
+        let mut bitmap_b = bitmap_a.clone();
+
+        for v in &a {
+            bitmap_a.insert(v);
+        }
+        for v in &b {
+            bitmap_b.insert(v);
+        }
+
+        let mut intersection = bitmap_a.clone();
+        intersection.intersect(&bitmap_b);
+
+        // Invariant 1: all values in both "a" and "b" must appear in the
+        // intersection.
+        for v in a.iter().filter(|v| b.contains(v)) {
+            assert!(intersection.contains(v));
+        }
+
+        // Invariant 2: values absent from either input must not appear in the
+        // intersection.
+        for v in a.iter().chain(&b).chain(&control) {
+            if !bitmap_a.contains(v) || !bitmap_b.contains(v) {
+                assert!(!intersection.contains(v));
+            }
+        }
+    }
+
+    #[quickcheck]
+    fn test_extend(values: Vec<u32>, hashes: Option<u8>) {
+        let new = || {
+            let mut b =
+                BloomFilterBuilder::hasher(BuildHasherDefault::<twox_hash::XxHash64>::default())
+                    .size(FilterSize::KeyBytes2)
+                    .build();
+            b.hashes = hashes.map(|k| k.max(1));
+            b
+        };
+
+        let mut want = new();
+        for v in &values {
+            want.insert(v);
+        }
+
+        // Invariant: extending by reference or by value is equivalent to
+        // inserting each value.
+        let mut got = new();
+        got.extend(&values);
+        assert_eq!(got, want);
+
+        let mut got = new();
+        got.extend(values.iter().copied());
+        assert_eq!(got, want);
+    }
+
+    fn assert_contains_many<B>(size: FilterSize, values: &[u32], check: &[u32], hashes: Option<u8>)
+    where
+        B: Bitmap + std::fmt::Debug + PartialEq,
+    {
+        let new = || {
+            let mut b =
+                BloomFilterBuilder::hasher(BuildHasherDefault::<twox_hash::XxHash64>::default())
+                    .with_bitmap::<B>()
+                    .size(size)
+                    .build();
+            b.hashes = hashes.map(|k| k.max(1));
+            b
+        };
+
+        let mut want = new();
+        for v in values {
+            want.insert(v);
+        }
+
+        let mut got = new();
+        got.insert_many(values);
+        assert_eq!(got, want);
+
+        // Invariant: the batch results match the per-value lookups exactly,
+        // including false positives.
+        let check = check.iter().chain(values).copied().collect::<Vec<_>>();
+        let mut out = vec![false; check.len()];
+        got.contains_many(&check, &mut out);
+        assert_eq!(
+            out,
+            check.iter().map(|v| want.contains(v)).collect::<Vec<_>>()
+        );
+    }
+
+    #[quickcheck]
+    fn test_contains_many(values: Vec<u32>, check: Vec<u32>, hashes: Option<u8>) {
+        let size = FilterSize::KeyBytes2;
+        assert_contains_many::<CompressedBitmap>(size, &values, &check, hashes);
+        assert_contains_many::<VecBitmap>(size, &values, &check, hashes);
+        assert_contains_many::<BlockedBitmap>(size, &values, &check, hashes);
+    }
+
+    #[test]
+    fn test_contains_many_batches() {
+        let values = (0..BATCH_SIZE as u32 * 3 + 1).collect::<Vec<_>>();
+        let check = (0..BATCH_SIZE as u32 * 6).collect::<Vec<_>>();
+        // Enough probes per batch to be ordered.
+        assert_contains_many::<CompressedBitmap>(FilterSize::KeyBytes3, &values, &check, None);
+    }
+
+    #[test]
+    fn test_prefetch_out_of_range() {
+        fn assert_prefetch<B: Bitmap>() {
+            let mut b = B::new_with_capacity(1024);
+            b.set(42, true);
+
+            // Invariant: a prefetch is only a hint, and never panics or
+            // changes the bitmap contents.
+            for key in [0, 42, 1024, 1 << 20, usize::MAX] {
+                b.prefetch(key);
+            }
+            assert!(b.get(42));
+            assert_eq!(b.count_ones(), 1);
+        }
+
+        assert_prefetch::<CompressedBitmap>();
+        assert_prefetch::<VecBitmap>();
+        assert_prefetch::<BlockedBitmap>();
+    }
+
+    #[quickcheck]
+    fn test_sort_probes(keys: Vec<u32>) {
+        let mut probes = keys
+            .iter()
+            .map(|&k| k as usize & 0xFF_FFFF)
+            // Enough probes to be ordered.
+            .chain((0..256 << PROBE_ORDER_SHIFT).step_by(49_999))
+            .enumerate()
+            .map(|(i, k)| (k, i))
+            .collect::<Vec<_>>();
+        let mut want = probes.clone();
+
+        sort_probes(&mut probes, &mut Vec::new(), FilterSize::KeyBytes3);
+
+        // Invariant: the probes are grouped in ascending order, and otherwise
+        // unchanged.
+        assert!(probes
+            .windows(2)
+            .all(|w| w[0].0 >> PROBE_ORDER_SHIFT <= w[1].0 >> PROBE_ORDER_SHIFT));
+        probes.sort_unstable();
+        want.sort_unstable();
+        assert_eq!(probes, want);
+    }
+
+    #[test]
+    #[should_panic(expected = "same length")]
+    fn test_contains_many_length_mismatch() {
+        let b = Bloom2::default();
+        b.contains_many(&[1, 2], &mut [false; 1]);
+    }
+
+    #[test]
+    fn test_extend_batches() {
+        let mut b =
+            BloomFilterBuilder::hasher(BuildHasherDefault::<twox_hash::XxHash64>::default())
+                .with_bitmap::<VecBitmap>()
+                .build();
+
+        // Spanning several batches.
+        b.extend(0..BATCH_SIZE * 3 + 1);
+        for v in 0..BATCH_SIZE * 3 + 1 {
+            assert!(b.contains(&v));
+        }
+    }
+
+    #[test]
+    fn test_from_iter() {
+        let values = vec!["bananas", "are", "great"];
+
+        let b: Bloom2<_, _, &str> = values.iter().collect();
+        for v in &values {
+            assert!(b.contains(v));
+        }
+
+        let b: Bloom2<_, _, &str> = values.clone().into_iter().collect();
+        for v in &values {
+            assert!(b.contains(v));
+        }
+    }
+
+    /// Return the theoretical false positive probability of a filter of
+    /// `key_size` splitting a hash of `width` after `n` unique inserts.
+    ///
+    /// Every probe addresses the full key space, so the classic approximation
+    /// holds.
+    fn theoretical_fpr(key_size: FilterSize, width: HashWidth, n: usize) -> f64 {
+        let m = key_size_to_bits(key_size) as f64;
+        let k = num_probes(key_size, None, width, None) as f64;
+        (1.0 - (-k * n as f64 / m).exp()).powf(k)
+    }
+
+    /// Insert `n` values into `trials` filters of `key_size` (probing `hashes`
+    /// keys, if set, from a hash of `width`) and return the mean false positive
+    /// rate observed for `queries` values never inserted.
+    fn empirical_fpr<B: Bitmap>(
+        key_size: FilterSize,
+        hashes: Option<u8>,
+        width: HashWidth,
+        n: usize,
+        queries: usize,
+        trials: usize,
+    ) -> f64 {
+        let mut false_positives = 0;
+
+        for trial in 0..trials {
+            let mut b =
+                BloomFilterBuilder::hasher(BuildHasherDefault::<twox_hash::XxHash64>::default())
+                    .with_bitmap::<B>()
+                    .size(key_size)
+                    .hash_width(width)
+                    .build();
+            b.hashes = hashes;
+
+            // Each trial inserts (and queries) a disjoint range of values.
+            let base = trial * (n + queries);
+            for v in base..base + n {
+                b.insert(&v);
+            }
+
+            false_positives += (base + n..base + n + queries)
+                .filter(|v| b.contains(v))
+                .count();
+        }
+
+        false_positives as f64 / (queries * trials) as f64
+    }
+
+    macro_rules! test_fpr {
+        (
+            $name:ident,
+            bitmap = $bitmap:ty,
+            size = $size:expr,
+            width = $width:expr,
+            n = $n:expr,
+            queries = $queries:expr,
+            trials = $trials:expr
+        ) => {
+            #[test]
+            fn $name() {
+                let want = theoretical_fpr($size, $width, $n);
+                let got = empirical_fpr::<$bitmap>($size, None, $width, $n, $queries, $trials);
+
+                // Allow a relative error of 20%, and a small absolute error
+                // for filters that should (nearly) never return a false
+                // positive.
+                assert!(
+                    (got - want).abs() <= want * 0.2 + 0.001,
+                    "false positive rate {} is not within tolerance of {}",
+                    got,
+                    want
+                );
+            }
+        };
+    }
+
+    test_fpr!(
+        test_fpr_kb1,
+        bitmap = CompressedBitmap,
+        size = FilterSize::KeyBytes1,
+        width = HashWidth::Bits64,
+        n = 40,
+        queries = 1_000,
+        trials = 100
+    );
+
+    test_fpr!(
+        test_fpr_kb2,
+        bitmap = CompressedBitmap,
+        size = FilterSize::KeyBytes2,
+        width = HashWidth::Bits64,
+        n = 10_000,
+        queries = 100_000,
+        trials = 1
+    );
+
+    // The larger filter sizes use the dense bitmap to keep the insert cost of
+    // the test reasonable.
+    test_fpr!(
+        test_fpr_kb3,
+        bitmap = VecBitmap,
+        size = FilterSize::KeyBytes3,
+        width = HashWidth::Bits64,
+        n = 500_000,
+        queries = 100_000,
+        trials = 1
+    );
+
+    // A KeyBytes4 filter cannot be loaded enough to observe false positives in
+    // a reasonable amount of time, but it should (nearly) never return one.
+    //
+    // KeyBytes5 is not tested, as even an empty filter requires 2GiB of block
+    // map.
+    test_fpr!(
+        test_fpr_kb4,
+        bitmap = CompressedBitmap,
+        size = FilterSize::KeyBytes4,
+        width = HashWidth::Bits64,
+        n = 500,
+        queries = 10_000,
+        trials = 1
+    );
+
+    // A 128-bit hash doubles the number of keys split from it.
+    test_fpr!(
+        test_fpr_kb2_wide,
+        bitmap = CompressedBitmap,
+        size = FilterSize::KeyBytes2,
+        width = HashWidth::Bits128,
+        n = 8_000,
+        queries = 100_000,
+        trials = 1
+    );
+
+    test_fpr!(
+        test_fpr_kb3_wide,
+        bitmap = VecBitmap,
+        size = FilterSize::KeyBytes3,
+        width = HashWidth::Bits128,
+        n = 2_000_000,
+        queries = 100_000,
+        trials = 1
+    );
+
+    #[test]
+    fn test_fpr_hashes() {
+        for (size, k, n, trials) in [
+            (FilterSize::KeyBytes1, 3, 20, 100),
+            (FilterSize::KeyBytes2, 7, 5_000, 1),
+            (FilterSize::KeyBytes2, 12, 2_000, 1),
+        ] {
+            // The classic approximation holds for a double hashed filter,
+            // with every probe addressing the full key space.
+            let m = key_size_to_bits(size) as f64;
+            let want = (1.0 - (-(k as f64) * n as f64 / m).exp()).powi(k as i32);
+            let got = empirical_fpr::<CompressedBitmap>(
+                size,
+                Some(k),
+                HashWidth::Bits64,
+                n,
+                100_000 / trials,
+                trials,
+            );
+
+            assert!(
+                (got - want).abs() <= want * 0.2 + 0.001,
+                "k={} false positive rate {} is not within tolerance of {}",
+                k,
+                got,
+                want
+            );
+        }
+    }
+
+    #[quickcheck]
+    fn test_chunk_keys(hash: u64) {
+        // Invariant: sizes that evenly divide the hash split it into
+        // big-endian chunks.
+        let keys = |size| {
+            hash_to_keys(u128::from(hash), HashWidth::Bits64, size, None).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            keys(FilterSize::KeyBytes2),
+            (0..4)
+                .rev()
+                .map(|i| (hash >> (16 * i)) as usize & 0xFFFF)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            keys(FilterSize::KeyBytes4),
+            vec![(hash >> 32) as usize, hash as u32 as usize]
+        );
+
+        // Invariant: other sizes take the leading full chunks from the hash,
+        // completing the final chunk with derived bits.
+        let kb3 = keys(FilterSize::KeyBytes3);
+        assert_eq!(kb3.len(), 3);
+        assert_eq!(kb3[0], (hash >> 40) as usize);
+        assert_eq!(kb3[1], (hash >> 16) as usize & 0xFF_FFFF);
+        assert_eq!(kb3[2] >> 8, hash as usize & 0xFFFF);
+
+        let kb5 = keys(FilterSize::KeyBytes5);
+        assert_eq!(kb5.len(), 2);
+        assert_eq!(kb5[0], (hash >> 24) as usize);
+        assert_eq!(kb5[1] >> 16, hash as usize & 0xFF_FFFF);
+    }
+
+    #[quickcheck]
+    fn test_chunk_keys_truncated(hash: u64) {
+        // Invariant: the truncated scheme splits the hash into big-endian
+        // chunks of the key size, as prior releases did, with the final chunk
+        // holding only the bytes remaining.
+        for size in FilterSize::ALL {
+            let got = probe_keys(
+                u128::from(hash),
+                HashWidth::Bits64,
+                size,
+                None,
+                ChunkScheme::Truncated,
+                None,
+            )
+            .collect::<Vec<_>>();
+
+            let want = hash
+                .to_be_bytes()
+                .chunks(size as usize)
+                .map(|c| c.iter().fold(0, |acc, &b| (acc << 8) | b as usize))
+                .collect::<Vec<_>>();
+
+            assert_eq!(got, want);
+        }
+    }
+
+    #[quickcheck]
+    fn test_chunk_keys_wide(hash: u128) {
+        let keys = |size| hash_to_keys(hash, HashWidth::Bits128, size, None).collect::<Vec<_>>();
+
+        // Invariant: a 128-bit hash yields twice the keys of a 64-bit hash,
+        // the first of which are the keys of its first 64 bits.
+        for size in FilterSize::ALL {
+            let narrow =
+                hash_to_keys(hash >> 64, HashWidth::Bits64, size, None).collect::<Vec<_>>();
+            let wide = keys(size);
+            assert_eq!(wide.len(), num_probes(size, None, HashWidth::Bits128, None));
+            assert_eq!(wide.len(), (16_usize).div_ceil(size as usize));
+            assert_eq!(
+                wide[..64 / (8 * size as usize)],
+                narrow[..64 / (8 * size as usize)]
+            );
+        }
+
+        assert_eq!(
+            keys(FilterSize::KeyBytes4),
+            (0..4)
+                .rev()
+                .map(|i| (hash >> (32 * i)) as u32 as usize)
+                .collect::<Vec<_>>()
+        );
+
+        // Invariant: the final partial chunk is completed with derived bits.
+        let kb3 = keys(FilterSize::KeyBytes3);
+        assert_eq!(kb3.len(), 6);
+        assert_eq!(kb3[4], (hash >> 8) as usize & 0xFF_FFFF);
+        assert_eq!(kb3[5] >> 16, hash as usize & 0xFF);
+
+        let kb5 = keys(FilterSize::KeyBytes5);
+        assert_eq!(kb5.len(), 4);
+        assert_eq!(kb5[2], (hash >> 8) as usize & 0xFF_FFFF_FFFF);
+        assert_eq!(kb5[3] >> 32, hash as usize & 0xFF);
+    }
+
+    #[quickcheck]
+    fn test_double_hash_wide(hash: u128) {
+        // Invariant: the two halves of a 128-bit hash provide h1 and h2.
+        let keys = hash_to_keys(hash, HashWidth::Bits128, FilterSize::KeyBytes5, Some(3))
+            .collect::<Vec<_>>();
+        let mask = key_size_to_bits(FilterSize::KeyBytes5) as u64 - 1;
+        let (h1, h2) = ((hash >> 64) as u64, hash as u64 | 1);
+        assert_eq!(
+            keys,
+            (0..3)
+                .map(|i| (h1.wrapping_add(h2.wrapping_mul(i)) & mask) as usize)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_chunk_keys_uniform() {
+        const SAMPLES: usize = 100_000;
+        const BUCKETS: usize = 16;
+
+        for (size, width) in [FilterSize::KeyBytes3, FilterSize::KeyBytes5]
+            .iter()
+            .flat_map(|&size| vec![(size, HashWidth::Bits64), (size, HashWidth::Bits128)])
+        {
+            let bits = 8 * size as u32;
+            let k = num_probes(size, None, width, None);
+
+            // Count the keys of each probe falling in each sixteenth of the
+            // key space, and by their low bits.
+            let mut high = vec![[0_usize; BUCKETS]; k];
+            let mut low = vec![[0_usize; BUCKETS]; k];
+            let hasher = BuildHasherDefault::<twox_hash::XxHash64>::default();
+            for v in 0..SAMPLES {
+                let hash = width.hash_one(&hasher, &v);
+                for (i, key) in hash_to_keys(hash, width, size, None).enumerate() {
+                    high[i][key >> (bits - 4)] += 1;
+                    low[i][key % BUCKETS] += 1;
+                }
+            }
+
+            // Invariant: the keys of every probe, including the last, are
+            // uniformly distributed over the whole key space.
+            //
+            // 37.7 is the critical value of the chi-squared distribution with
+            // 15 degrees of freedom at p = 0.001.
+            let expected = (SAMPLES / BUCKETS) as f64;
+            for (i, counts) in high.iter().chain(&low).enumerate() {
+                let chi2 = counts
+                    .iter()
+                    .map(|&c| (c as f64 - expected).powi(2) / expected)
+                    .sum::<f64>();
+                assert!(
+                    chi2 < 37.7,
+                    "{:?} {:?} probe {} is not uniform (chi2 = {}): {:?}",
+                    size,
+                    width,
+                    i % k,
+                    chi2,
+                    counts
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_num_probes() {
+        for size in [
+            FilterSize::KeyBytes1,
+            FilterSize::KeyBytes2,
+            FilterSize::KeyBytes3,
+            FilterSize::KeyBytes4,
+            FilterSize::KeyBytes5,
+        ] {
+            for hashes in [None, Some(1), Some(7)] {
+                for width in [HashWidth::Bits64, HashWidth::Bits128] {
+                    for block_bits in [None, BlockedBitmap::BLOCK_BITS] {
+                        for scheme in [ChunkScheme::Extended, ChunkScheme::Truncated] {
+                            assert_eq!(
+                                num_probes(size, hashes, width, block_bits),
+                                probe_keys(42, width, size, hashes, scheme, block_bits).count()
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_blocked_keys(
+            hash in any::<u128>(),
+            hashes in prop::option::of(1..20_u8),
+            size in prop::sample::select(FilterSize::ALL.to_vec()),
+            width in prop::sample::select(vec![HashWidth::Bits64, HashWidth::Bits128]),
+        ) {
+            let hash = match width {
+                HashWidth::Bits64 => hash as u64 as u128,
+                HashWidth::Bits128 => hash,
+            };
+            let bits = key_size_to_bits(size);
+            let block_bits = BlockedBitmap::BLOCK_BITS.unwrap();
+            let keys = probe_keys(
+                hash,
+                width,
+                size,
+                hashes,
+                ChunkScheme::Extended,
+                Some(block_bits),
+            )
+            .collect::<Vec<_>>();
+
+            // Invariant: every key falls within the filter, and within a
+            // single (cache line) block.
+            let block = keys[0] / block_bits;
+            assert!(keys.iter().all(|&k| k < bits && k / block_bits == block));
+
+            // Invariant: a 128-bit hash selects the block with its second
+            // half.
+            if width == HashWidth::Bits128 && bits > block_bits {
+                assert_eq!(block, hash as u64 as usize & (bits / block_bits - 1));
+            }
+
+            // Invariant: the first 8 probes each address a different word of
+            // the block.
+            let words = keys
+                .iter()
+                .take(8)
+                .map(|k| k / u64::BITS as usize)
+                .collect::<HashSet<_>>();
+            assert_eq!(words.len(), keys.len().min(8).min(bits / u64::BITS as usize));
+        }
+    }
+
+    #[test]
+    fn test_fpr_blocked() {
+        for (size, n) in [
+            (FilterSize::KeyBytes2, 5_000),
+            (FilterSize::KeyBytes3, 1_000_000),
+        ] {
+            // Blocking concentrates values in some blocks more than others,
+            // so the rate is a little higher than an unblocked filter.
+            let m = key_size_to_bits(size) as f64;
+            let k = BLOCKED_HASHES as f64;
+            let want = (1.0 - (-k * n as f64 / m).exp()).powf(k);
+            for width in [HashWidth::Bits64, HashWidth::Bits128] {
+                let got = empirical_fpr::<BlockedBitmap>(size, None, width, n, 100_000, 1);
+
+                assert!(
+                    got >= want * 0.8 && got <= want * 2.0,
+                    "{:?} {:?} false positive rate {} is not within tolerance of {}",
+                    size,
+                    width,
+                    got,
+                    want
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_blocked_extend() {
+        let mut want =
+            BloomFilterBuilder::hasher(BuildHasherDefault::<twox_hash::XxHash64>::default())
+                .with_bitmap::<BlockedBitmap>()
+                .size(FilterSize::KeyBytes3)
+                .build();
+        let mut got = want.clone();
+
+        for v in 0..1_000 {
+            want.insert(&v);
+        }
+        got.extend(0..1_000);
+
+        assert_eq!(got.bitmap, want.bitmap);
+        assert!((0..1_000).all(|v| got.contains(&v)));
+
+        // 8 probes within one block set ~8 bits per value.
+        let n = got.estimated_len();
+        assert!(n > 900.0 && n < 1_100.0, "estimated {}", n);
+    }
+
+    #[test]
+    fn test_estimates_empty() {
+        let b: Bloom2<_, CompressedBitmap, usize> = BloomFilterBuilder::default().build();
+        assert_eq!(b.fill_ratio(), 0.0);
+        assert_eq!(b.estimated_len(), 0.0);
+        assert_eq!(b.estimated_fpr(), 0.0);
+    }
+
+    #[test]
+    fn test_estimates_saturated() {
+        let mut b = BloomFilterBuilder::default()
+            .size(FilterSize::KeyBytes1)
+            .build();
+        for v in 0..10_000_usize {
+            b.insert(&v);
+        }
+
+        assert_eq!(b.fill_ratio(), 1.0);
+        assert_eq!(b.estimated_len(), f64::INFINITY);
+        assert_eq!(b.estimated_fpr(), 1.0);
+    }
+
+    #[test]
+    fn test_estimates() {
+        for hashes in [None, Some(3), Some(9)] {
+            let mut b =
+                BloomFilterBuilder::hasher(BuildHasherDefault::<twox_hash::XxHash64>::default())
+                    .size(FilterSize::KeyBytes2)
+                    .build();
+            b.hashes = hashes;
+
+            let n = 5_000;
+            for v in 0..n {
+                b.insert(&v);
+            }
+
+            let got = b.estimated_len();
+            assert!(
+                (got - n as f64).abs() <= n as f64 * 0.05,
+                "k={:?} estimated length {} is not within tolerance of {}",
+                hashes,
+                got,
+                n
+            );
+
+            // Invariant: the estimated false positive rate matches the
+            // observed rate.
+            let want = (n..n + 100_000).filter(|v| b.contains(v)).count() as f64 / 100_000.0;
+            let got = b.estimated_fpr();
+            assert!(
+                (got - want).abs() <= want * 0.2 + 0.001,
+                "k={:?} estimated false positive rate {} is not within tolerance of {}",
+                hashes,
+                got,
+                want
+            );
+        }
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let builder =
+            BloomFilterBuilder::hasher(BuildHasherDefault::<twox_hash::XxHash64>::default())
+                .with_bitmap::<VecBitmap>()
+                .with_capacity(20_000, 0.25)
+                .unwrap();
+
+        let params = *builder.params().unwrap();
+        assert_eq!(params.size(), FilterSize::KeyBytes2);
+        assert_eq!(params.hashes(), 2);
+
+        let mut b = builder.build();
+        assert_eq!(b.key_size, params.size());
+        assert_eq!(b.hashes, Some(params.hashes()));
+
+        for v in 0..20_000_usize {
+            b.insert(&v);
+        }
+        let got = (20_000..120_000_usize).filter(|v| b.contains(v)).count() as f64 / 100_000.0;
+
+        // Invariant: the observed false positive rate at capacity matches the
+        // prediction.
+        let want = params.predicted_fpr();
+        assert!(
+            (got - want).abs() <= want * 0.2,
+            "false positive rate {} is not within tolerance of {}",
+            got,
+            want
+        );
+    }
+
+    #[test]
+    fn test_with_capacity_unreachable() {
+        let got = BloomFilterBuilder::default().with_capacity(usize::MAX, 0.01);
+        assert!(matches!(got, Err(CapacityError::Unreachable { .. })));
+    }
+
+    #[test]
+    fn test_with_capacity_params_reset() {
+        let new = || {
+            BloomFilterBuilder::default()
+                .with_capacity(1_000, 0.01)
+                .unwrap()
+        };
+        assert!(new().params().is_some());
+
+        // Overriding the chosen parameters discards them.
+        assert!(new().size(FilterSize::KeyBytes1).params().is_none());
+        assert!(new().hashes(2).params().is_none());
+
+        // But changing the bitmap type does not.
+        assert!(new().with_bitmap::<VecBitmap>().params().is_some());
+    }
+
     #[cfg(feature = "serde")]
     #[test]
     fn serde() {
@@ -608,6 +2555,185 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_vec_bitmap() {
+        type MyBuildHasher = BuildHasherDefault<twox_hash::XxHash64>;
+
+        let mut bloom_filter: Bloom2<MyBuildHasher, VecBitmap, i32> =
+            BloomFilterBuilder::hasher(MyBuildHasher::default())
+                .with_bitmap::<VecBitmap>()
+                .size(FilterSize::KeyBytes2)
+                .build();
+
+        for i in 0..10 {
+            bloom_filter.insert(&i);
+        }
+
+        let encoded = serde_json::to_string(&bloom_filter).unwrap();
+        let decoded: Bloom2<MyBuildHasher, VecBitmap, i32> =
+            serde_json::from_str(&encoded).unwrap();
+
+        assert_eq!(bloom_filter.bitmap, decoded.bitmap);
+
+        for i in 0..10 {
+            assert!(decoded.contains(&i), "didn't contain {}", i);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_hashes() {
+        type MyBuildHasher = BuildHasherDefault<twox_hash::XxHash64>;
+
+        let mut bloom_filter: Bloom2<MyBuildHasher, VecBitmap, i32> =
+            BloomFilterBuilder::hasher(MyBuildHasher::default())
+                .with_bitmap::<VecBitmap>()
+                .size(FilterSize::KeyBytes1)
+                .hashes(3)
+                .build();
+        bloom_filter.insert(&42);
+
+        let encoded = serde_json::to_string(&bloom_filter).unwrap();
+        let decoded: Bloom2<MyBuildHasher, VecBitmap, i32> =
+            serde_json::from_str(&encoded).unwrap();
+        assert_eq!(decoded.hashes, Some(3));
+        assert!(decoded.contains(&42));
+
+        // Filters serialised by prior versions split the hash into chunks.
+        let mut value = serde_json::to_value(&bloom_filter).unwrap();
+        value.as_object_mut().unwrap().remove("hashes");
+        let decoded: Bloom2<MyBuildHasher, VecBitmap, i32> = serde_json::from_value(value).unwrap();
+        assert_eq!(decoded.hashes, None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_blocked() {
+        type MyBuildHasher = BuildHasherDefault<twox_hash::XxHash64>;
+
+        let mut bloom_filter: Bloom2<MyBuildHasher, BlockedBitmap, i32> =
+            BloomFilterBuilder::hasher(MyBuildHasher::default())
+                .with_bitmap::<BlockedBitmap>()
+                .size(FilterSize::KeyBytes3)
+                .build();
+        bloom_filter.extend(0..100);
+
+        let encoded = serde_json::to_string(&bloom_filter).unwrap();
+        let decoded: Bloom2<MyBuildHasher, BlockedBitmap, i32> =
+            serde_json::from_str(&encoded).unwrap();
+        assert_eq!(decoded, bloom_filter);
+        assert!((0..100).all(|v| decoded.contains(&v)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_hash_width() {
+        type MyBuildHasher = BuildHasherDefault<twox_hash::XxHash64>;
+
+        let mut bloom_filter: Bloom2<MyBuildHasher, VecBitmap, i32> =
+            BloomFilterBuilder::hasher(MyBuildHasher::default())
+                .with_bitmap::<VecBitmap>()
+                .size(FilterSize::KeyBytes1)
+                .hash_width(HashWidth::Bits128)
+                .build();
+        bloom_filter.insert(&42);
+
+        let encoded = serde_json::to_string(&bloom_filter).unwrap();
+        let decoded: Bloom2<MyBuildHasher, VecBitmap, i32> =
+            serde_json::from_str(&encoded).unwrap();
+        assert_eq!(decoded.hash_width, HashWidth::Bits128);
+        assert!(decoded.contains(&42));
+
+        // Filters serialised by prior versions use a 64-bit hash.
+        let mut value = serde_json::to_value(&bloom_filter).unwrap();
+        value.as_object_mut().unwrap().remove("hash_width");
+        let decoded: Bloom2<MyBuildHasher, VecBitmap, i32> = serde_json::from_value(value).unwrap();
+        assert_eq!(decoded.hash_width, HashWidth::Bits64);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_legacy_chunk_scheme() {
+        type MyBuildHasher = BuildHasherDefault<twox_hash::XxHash64>;
+
+        // A KeyBytes3 filter holding 0..3, serialised by a prior release
+        // before the chunk scheme was recorded.
+        let mut block_map = vec![0_u64; 4096];
+        for &(i, word) in &[
+            (1, 9223372036854775808),
+            (5, 8192),
+            (11, 1024),
+            (13, 288230376151711744),
+            (631, 8796093022208),
+            (942, 4611686018427387904),
+            (1548, 576460752303423488),
+            (3906, 4611686018427387904),
+            (4053, 2251799813685248),
+        ] {
+            block_map[i] = word;
+        }
+        let payload = serde_json::json!({
+            "bitmap": {
+                "block_map": block_map,
+                "bitmap": [
+                    2048_u64,
+                    131072_u64,
+                    1073741824_u64,
+                    4503599627370496_u64,
+                    2251799813685248_u64,
+                    274877906944_u64,
+                    268435456_u64,
+                    1048576_u64,
+                    1125899906842624_u64,
+                ],
+                "max_key": 16777216,
+            },
+            "key_size": "KeyBytes3",
+            "_key_type": null,
+        });
+
+        let decoded: Bloom2<MyBuildHasher, CompressedBitmap, u32> =
+            serde_json::from_value(payload).unwrap();
+        assert_eq!(decoded.chunk_scheme, ChunkScheme::Truncated);
+        assert!((0..3).all(|v| decoded.contains(&v)));
+
+        // The scheme of filters serialised by this release round trips.
+        let mut bloom_filter: Bloom2<MyBuildHasher, CompressedBitmap, u32> =
+            BloomFilterBuilder::hasher(MyBuildHasher::default())
+                .size(FilterSize::KeyBytes3)
+                .build();
+        bloom_filter.insert(&42);
+
+        let encoded = serde_json::to_string(&bloom_filter).unwrap();
+        let decoded: Bloom2<MyBuildHasher, CompressedBitmap, u32> =
+            serde_json::from_str(&encoded).unwrap();
+        assert_eq!(decoded.chunk_scheme, ChunkScheme::Extended);
+        assert!(decoded.contains(&42));
+    }
+
+    #[test]
+    fn test_with_bitmap_data_truncated() {
+        type MyBuildHasher = BuildHasherDefault<twox_hash::XxHash64>;
+
+        let mut legacy = BloomFilterBuilder::hasher(MyBuildHasher::default())
+            .size(FilterSize::KeyBytes5)
+            .chunk_scheme(ChunkScheme::Truncated)
+            .build();
+        for v in 0..100_u32 {
+            legacy.insert(&v);
+        }
+
+        // Invariant: a bitmap restored with the scheme it was populated with
+        // holds all its values.
+        let restored: Bloom2<_, _, u32> = BloomFilterBuilder::hasher(MyBuildHasher::default())
+            .with_bitmap_data(legacy.bitmap.clone(), FilterSize::KeyBytes5)
+            .chunk_scheme(ChunkScheme::Truncated)
+            .build();
+        assert_eq!(restored, legacy);
+        assert!((0..100).all(|v| restored.contains(&v)));
+    }
+
     /// Generate an arbitrary `usize` value.
     ///
     /// Prefers generating values from a small range to encourage collisions.
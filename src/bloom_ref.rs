@@ -0,0 +1,394 @@
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+
+use crate::{
+    bitmap::block_map_len,
+    bloom::{key_size_to_bits, probe_keys},
+    format::parse_file,
+    ChunkScheme, CompressedBitmap, CompressedBitmapRef, FilterSize, FormatError, HashWidth,
+    LayoutError, PortableBitmap,
+};
+
+/// A read-only, zero-copy [`Bloom2`] filter backed by a byte slice.
+///
+/// A `Bloom2Ref` answers [`contains`](Bloom2Ref::contains) queries directly
+/// against the bytes of a [`CompressedBitmap`] wrote with
+/// [`CompressedBitmap::write_to()`] - see [`CompressedBitmapRef`] for the
+/// layout. This allows a filter persisted to disk to be memory mapped and
+/// queried without deserialising it.
+///
+/// A filter wrote with [`Bloom2::write_to()`] can be queried in place with
+/// [`Bloom2Ref::from_file_bytes()`], which reads the filter configuration from
+/// the file. Otherwise the filter must be constructed with the same hasher,
+/// [`FilterSize`], number of [`hashes`](Bloom2Ref::hashes),
+/// [hash width](Bloom2Ref::hash_width) and
+/// [chunk scheme](Bloom2Ref::chunk_scheme) as the [`Bloom2`] the bitmap was
+/// wrote from, else lookups will return incorrect results.
+///
+/// ```rust
+/// use std::collections::hash_map::DefaultHasher;
+/// use std::hash::BuildHasherDefault;
+/// use bloom2::{Bloom2Ref, BloomFilterBuilder, FilterSize};
+///
+/// type MyHasher = BuildHasherDefault<DefaultHasher>;
+///
+/// let mut filter = BloomFilterBuilder::hasher(MyHasher::default())
+///     .size(FilterSize::KeyBytes2)
+///     .build();
+/// filter.insert(&"hello 🐐");
+///
+/// // Write the bitmap (for example, to a file that is later mapped into
+/// // memory).
+/// let mut bytes = Vec::new();
+/// filter.bitmap().write_to(&mut bytes).unwrap();
+///
+/// // And query the bytes directly.
+/// let view = Bloom2Ref::new(MyHasher::default(), FilterSize::KeyBytes2, &bytes).unwrap();
+/// assert!(view.contains(&"hello 🐐"));
+/// ```
+///
+/// [`Bloom2`]: crate::Bloom2
+/// [`Bloom2::write_to()`]: crate::Bloom2::write_to
+/// [`CompressedBitmap::write_to()`]: crate::CompressedBitmap::write_to
+#[derive(Debug, Clone)]
+pub struct Bloom2Ref<'a, H, T>
+where
+    H: BuildHasher,
+{
+    hasher: H,
+    bitmap: CompressedBitmapRef<'a>,
+    key_size: FilterSize,
+    hashes: Option<u8>,
+    hash_width: HashWidth,
+    chunk_scheme: ChunkScheme,
+    _key_type: PhantomData<T>,
+}
+
+impl<'a, H, T> Bloom2Ref<'a, H, T>
+where
+    H: BuildHasher,
+    T: Hash,
+{
+    /// Construct a `Bloom2Ref` over the bitmap layout in `bytes`, using the
+    /// provided `hasher` and `key_size`.
+    ///
+    /// Returns an error if `bytes` does not contain a valid bitmap layout, or
+    /// the bitmap is not sized for `key_size`.
+    pub fn new(hasher: H, key_size: FilterSize, bytes: &'a [u8]) -> Result<Self, LayoutError> {
+        let bitmap = CompressedBitmapRef::new(bytes)?;
+
+        // Invariant: the bitmap is large enough to hold every key produced for
+        // key_size.
+        if bitmap.block_map_len() != block_map_len(key_size_to_bits(key_size)) {
+            return Err(LayoutError::FilterSize);
+        }
+
+        Ok(Self {
+            hasher,
+            bitmap,
+            key_size,
+            hashes: None,
+            hash_width: HashWidth::default(),
+            chunk_scheme: ChunkScheme::default(),
+            _key_type: PhantomData,
+        })
+    }
+
+    /// Construct a `Bloom2Ref` over `bytes`, a [`CompressedBitmap`] filter
+    /// wrote with [`Bloom2::write_to()`], using the provided `hasher`.
+    ///
+    /// The [`FilterSize`], number of hashes, hash width and chunk scheme are
+    /// read from the file header. Returns an error if the file is corrupt,
+    /// holds a different kind of bitmap, or was wrote with a different
+    /// `hasher_id`. Validating the checksum reads every byte of `bytes`.
+    ///
+    /// ```rust
+    /// use std::collections::hash_map::DefaultHasher;
+    /// use std::hash::BuildHasherDefault;
+    /// use bloom2::{Bloom2Ref, BloomFilterBuilder, FilterSize};
+    ///
+    /// type MyHasher = BuildHasherDefault<DefaultHasher>;
+    /// const MY_HASHER_ID: u64 = 42;
+    ///
+    /// let mut filter = BloomFilterBuilder::hasher(MyHasher::default())
+    ///     .size(FilterSize::KeyBytes2)
+    ///     .hashes(4)
+    ///     .build();
+    /// filter.insert(&"hello 🐐");
+    ///
+    /// let mut bytes = Vec::new();
+    /// filter.write_to(&mut bytes, MY_HASHER_ID).unwrap();
+    ///
+    /// let view = Bloom2Ref::from_file_bytes(MyHasher::default(), MY_HASHER_ID, &bytes).unwrap();
+    /// assert!(view.contains(&"hello 🐐"));
+    /// ```
+    ///
+    /// [`Bloom2::write_to()`]: crate::Bloom2::write_to
+    pub fn from_file_bytes(
+        hasher: H,
+        hasher_id: u64,
+        bytes: &'a [u8],
+    ) -> Result<Self, FormatError> {
+        let (header, words) = parse_file(bytes, CompressedBitmap::KIND, hasher_id)?;
+
+        Ok(Self {
+            hashes: header.hashes,
+            hash_width: header.hash_width,
+            chunk_scheme: header.chunk_scheme,
+            ..Self::new(hasher, header.key_size, words)?
+        })
+    }
+
+    /// Probe `k` keys per value, matching a [`Bloom2`] built with
+    /// [`BloomFilterBuilder::hashes()`].
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `k` is 0.
+    ///
+    /// [`Bloom2`]: crate::Bloom2
+    /// [`BloomFilterBuilder::hashes()`]: crate::BloomFilterBuilder::hashes
+    pub fn hashes(self, k: u8) -> Self {
+        assert!(k > 0, "a filter must probe at least one key per value");
+        Self {
+            hashes: Some(k),
+            ..self
+        }
+    }
+
+    /// Hash values to a hash of `width`, matching a [`Bloom2`] built with
+    /// [`BloomFilterBuilder::hash_width()`].
+    ///
+    /// [`Bloom2`]: crate::Bloom2
+    /// [`BloomFilterBuilder::hash_width()`]: crate::BloomFilterBuilder::hash_width
+    pub fn hash_width(self, width: HashWidth) -> Self {
+        Self {
+            hash_width: width,
+            ..self
+        }
+    }
+
+    /// Split hashes into keys with `scheme`, matching a [`Bloom2`] built with
+    /// [`BloomFilterBuilder::chunk_scheme()`].
+    ///
+    /// [`Bloom2`]: crate::Bloom2
+    /// [`BloomFilterBuilder::chunk_scheme()`]: crate::BloomFilterBuilder::chunk_scheme
+    pub fn chunk_scheme(self, scheme: ChunkScheme) -> Self {
+        Self {
+            chunk_scheme: scheme,
+            ..self
+        }
+    }
+
+    /// Checks if `data` exists in the filter.
+    ///
+    /// If `contains` returns true, `data` has **probably** been inserted
+    /// previously. If `contains` returns false, `data` has **definitely not**
+    /// been inserted into the filter.
+    pub fn contains(&self, data: &'_ T) -> bool {
+        probe_keys(
+            self.hash_width.hash_one(&self.hasher, data),
+            self.hash_width,
+            self.key_size,
+            self.hashes,
+            self.chunk_scheme,
+            None,
+        )
+        .all(|key| self.bitmap.get(key))
+    }
+
+    /// Return the byte size of the underlying byte slice.
+    pub fn byte_size(&self) -> usize {
+        self.bitmap.byte_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hash::BuildHasherDefault;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{Bloom2, BloomFilterBuilder, VecBitmap};
+
+    type MyBuildHasher = BuildHasherDefault<twox_hash::XxHash64>;
+
+    fn new_filter(size: FilterSize) -> Bloom2<MyBuildHasher, CompressedBitmap, usize> {
+        BloomFilterBuilder::hasher(MyBuildHasher::default())
+            .size(size)
+            .build()
+    }
+
+    #[test]
+    fn test_filter_size_mismatch() {
+        let mut bytes = Vec::new();
+        new_filter(FilterSize::KeyBytes2)
+            .bitmap()
+            .write_to(&mut bytes)
+            .unwrap();
+
+        let got =
+            Bloom2Ref::<_, usize>::new(MyBuildHasher::default(), FilterSize::KeyBytes1, &bytes);
+        assert_eq!(got.unwrap_err(), LayoutError::FilterSize);
+    }
+
+    #[test]
+    fn test_hashes() {
+        let mut b = BloomFilterBuilder::hasher(MyBuildHasher::default())
+            .size(FilterSize::KeyBytes2)
+            .hashes(7)
+            .build();
+        for v in 0..100_usize {
+            b.insert(&v);
+        }
+
+        let mut bytes = Vec::new();
+        b.bitmap().write_to(&mut bytes).unwrap();
+        let r = Bloom2Ref::new(MyBuildHasher::default(), FilterSize::KeyBytes2, &bytes)
+            .unwrap()
+            .hashes(7);
+
+        for v in 0..1000_usize {
+            assert_eq!(r.contains(&v), b.contains(&v));
+        }
+    }
+
+    #[test]
+    fn test_hash_width() {
+        let mut b = BloomFilterBuilder::hasher(MyBuildHasher::default())
+            .size(FilterSize::KeyBytes2)
+            .hash_width(HashWidth::Bits128)
+            .build();
+        for v in 0..100_usize {
+            b.insert(&v);
+        }
+
+        let mut bytes = Vec::new();
+        b.bitmap().write_to(&mut bytes).unwrap();
+        let r = Bloom2Ref::new(MyBuildHasher::default(), FilterSize::KeyBytes2, &bytes)
+            .unwrap()
+            .hash_width(HashWidth::Bits128);
+
+        for v in 0..1000_usize {
+            assert_eq!(r.contains(&v), b.contains(&v));
+        }
+    }
+
+    #[test]
+    fn test_chunk_scheme() {
+        let mut b = BloomFilterBuilder::hasher(MyBuildHasher::default())
+            .size(FilterSize::KeyBytes3)
+            .chunk_scheme(ChunkScheme::Truncated)
+            .build();
+        for v in 0..100_usize {
+            b.insert(&v);
+        }
+
+        let mut bytes = Vec::new();
+        b.bitmap().write_to(&mut bytes).unwrap();
+        let r = Bloom2Ref::new(MyBuildHasher::default(), FilterSize::KeyBytes3, &bytes)
+            .unwrap()
+            .chunk_scheme(ChunkScheme::Truncated);
+
+        for v in 0..1000_usize {
+            assert_eq!(r.contains(&v), b.contains(&v));
+        }
+    }
+
+    #[test]
+    fn test_from_file_bytes() {
+        let mut b = BloomFilterBuilder::hasher(MyBuildHasher::default())
+            .size(FilterSize::KeyBytes3)
+            .hashes(5)
+            .hash_width(HashWidth::Bits128)
+            .chunk_scheme(ChunkScheme::Truncated)
+            .build();
+        for v in 0..100_usize {
+            b.insert(&v);
+        }
+
+        let mut bytes = Vec::new();
+        b.write_to(&mut bytes, 42).unwrap();
+
+        // Invariant: the view takes its configuration from the file header.
+        let r = Bloom2Ref::from_file_bytes(MyBuildHasher::default(), 42, &bytes).unwrap();
+        assert_eq!(r.key_size, FilterSize::KeyBytes3);
+        assert_eq!(r.hashes, Some(5));
+        assert_eq!(r.hash_width, HashWidth::Bits128);
+        assert_eq!(r.chunk_scheme, ChunkScheme::Truncated);
+
+        for v in 0..1000_usize {
+            assert_eq!(r.contains(&v), b.contains(&v));
+        }
+    }
+
+    #[test]
+    fn test_from_file_bytes_invalid() {
+        let new_ref = |bytes: &[u8], hasher_id| {
+            Bloom2Ref::<_, usize>::from_file_bytes(MyBuildHasher::default(), hasher_id, bytes)
+                .map(|_| ())
+        };
+
+        let mut bytes = Vec::new();
+        new_filter(FilterSize::KeyBytes2)
+            .write_to(&mut bytes, 42)
+            .unwrap();
+        assert!(new_ref(&bytes, 42).is_ok());
+
+        assert!(matches!(
+            new_ref(&bytes, 24),
+            Err(FormatError::Hasher { want: 24, got: 42 })
+        ));
+        assert!(matches!(
+            new_ref(&bytes[..bytes.len() - 8], 42),
+            Err(FormatError::Checksum)
+        ));
+        assert!(matches!(new_ref(&bytes[..39], 42), Err(FormatError::Io(_))));
+
+        // The raw bitmap layout is not a file.
+        let mut raw = Vec::new();
+        new_filter(FilterSize::KeyBytes2)
+            .bitmap()
+            .write_to(&mut raw)
+            .unwrap();
+        assert!(matches!(new_ref(&raw, 42), Err(FormatError::Magic)));
+
+        let mut corrupt = bytes.clone();
+        corrupt[40] ^= 1;
+        assert!(matches!(new_ref(&corrupt, 42), Err(FormatError::Checksum)));
+
+        let mut vec_bytes = Vec::new();
+        BloomFilterBuilder::hasher(MyBuildHasher::default())
+            .with_bitmap::<VecBitmap>()
+            .build::<usize>()
+            .write_to(&mut vec_bytes, 42)
+            .unwrap();
+        assert!(matches!(
+            new_ref(&vec_bytes, 42),
+            Err(FormatError::BitmapKind { want: 1, got: 0 })
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_ref_contains(
+            values in prop::collection::vec(any::<usize>(), 0..100),
+            check in prop::collection::vec(any::<usize>(), 0..100),
+        ) {
+            let mut b = new_filter(FilterSize::KeyBytes3);
+            for v in &values {
+                b.insert(v);
+            }
+
+            let mut bytes = Vec::new();
+            b.bitmap().write_to(&mut bytes).unwrap();
+            let r = Bloom2Ref::new(MyBuildHasher::default(), FilterSize::KeyBytes3, &bytes).unwrap();
+
+            // Invariant: the view and the owned filter agree on every value.
+            for v in values.iter().chain(&check) {
+                assert_eq!(r.contains(v), b.contains(v));
+            }
+        }
+    }
+}
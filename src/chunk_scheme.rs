@@ -0,0 +1,55 @@
+/// How the hash of a value is split into [`FilterSize`] chunks when the filter
+/// size does not evenly divide it, as for [`KeyBytes3`] and [`KeyBytes5`].
+///
+/// Filters built by this release complete the final, partial chunk of the
+/// hash with derived bits, so every key spans the whole key space. Prior
+/// releases instead read the final key from the bytes remaining in the hash
+/// alone, mapping values to different keys - a filter populated by a prior
+/// release must use [`ChunkScheme::Truncated`] to find the values it holds.
+///
+/// Deserialising a [`Bloom2`] serialised by a prior release selects
+/// [`ChunkScheme::Truncated`] automatically, while a bitmap restored with
+/// [`with_bitmap_data()`] or viewed with a [`Bloom2Ref`] must set it
+/// explicitly:
+///
+/// ```rust
+/// use bloom2::{BloomFilterBuilder, ChunkScheme, CompressedBitmap, FilterSize};
+///
+/// # let bitmap = CompressedBitmap::new(1 << 24);
+/// // A bitmap populated by a prior release.
+/// let filter = BloomFilterBuilder::default()
+///     .with_bitmap_data(bitmap, FilterSize::KeyBytes3)
+///     .chunk_scheme(ChunkScheme::Truncated)
+///     .build::<&str>();
+/// ```
+///
+/// The scheme has no effect on filters using sizes that divide the hash, or
+/// that derive their keys by double hashing (see
+/// [`hashes()`](crate::BloomFilterBuilder::hashes)).
+///
+/// [`FilterSize`]: crate::FilterSize
+/// [`KeyBytes3`]: crate::FilterSize::KeyBytes3
+/// [`KeyBytes5`]: crate::FilterSize::KeyBytes5
+/// [`Bloom2`]: crate::Bloom2
+/// [`Bloom2Ref`]: crate::Bloom2Ref
+/// [`with_bitmap_data()`]: crate::BloomFilterBuilder::with_bitmap_data
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChunkScheme {
+    /// The final chunk is completed with bits derived from the hash.
+    #[default]
+    Extended,
+
+    /// The final chunk is read from the bytes remaining in the hash, as by
+    /// prior releases - its keys address only a prefix of the key space,
+    /// increasing the false positive rate.
+    Truncated,
+}
+
+impl ChunkScheme {
+    /// The scheme of filters serialised without one, by prior releases.
+    #[cfg(feature = "serde")]
+    pub(crate) fn legacy() -> Self {
+        Self::Truncated
+    }
+}
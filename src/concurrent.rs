@@ -0,0 +1,287 @@
+use crate::{bloom::hash_to_keys, Bitmap, Bloom2, ChunkScheme, FilterSize, HashWidth};
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+
+/// A trait to abstract bit storage supporting concurrent writes through a
+/// shared reference, for use in a [`ConcurrentBloom2`] filter.
+pub trait SharedBitmap {
+    /// Construct a new [`SharedBitmap`] impl with capacity to hold at least
+    /// `max_key` number of bits.
+    fn new_with_capacity(max_key: usize) -> Self;
+
+    /// Set the bit indexed by `key` to `true`.
+    fn set(&self, key: usize);
+
+    /// Return `true` if the given bit index was previously set to `true`.
+    fn get(&self, key: usize) -> bool;
+
+    /// Return the size of the bitmap in bytes.
+    fn byte_size(&self) -> usize;
+}
+
+/// A bloom filter supporting concurrent inserts from many threads, without a
+/// global lock.
+///
+/// Unlike a [`Bloom2`], a `ConcurrentBloom2` inserts through a shared
+/// reference, allowing a single filter to be loaded by many threads at once
+/// (it is [`Sync`] when the hasher is):
+///
+/// ```rust
+/// use bloom2::{AtomicBitmap, BloomFilterBuilder, CompressedBitmap};
+///
+/// let filter = BloomFilterBuilder::default().build_concurrent::<AtomicBitmap, _>();
+///
+/// std::thread::scope(|s| {
+///     for t in 0..4 {
+///         let filter = &filter;
+///         s.spawn(move || {
+///             for i in (t..1_000).step_by(4) {
+///                 filter.insert(&i);
+///             }
+///         });
+///     }
+/// });
+///
+/// assert!(filter.contains(&42));
+///
+/// // Freeze the loaded filter into a (sparse) Bloom2.
+/// let filter = filter.freeze::<CompressedBitmap>();
+/// assert!(filter.contains(&42));
+/// ```
+///
+/// An insert is visible to lookups in other threads once they have
+/// synchronised with the inserting thread (for example, by joining it).
+///
+/// An [`AtomicBitmap`] allocates the whole (dense) bitmap up front - an
+/// [`AtomicCompressedBitmap`] retains the sparse memory footprint of a
+/// [`CompressedBitmap`], at the cost of slower inserts.
+///
+/// [`AtomicBitmap`]: crate::AtomicBitmap
+/// [`AtomicCompressedBitmap`]: crate::AtomicCompressedBitmap
+/// [`CompressedBitmap`]: crate::CompressedBitmap
+#[derive(Debug)]
+pub struct ConcurrentBloom2<H, S, T>
+where
+    H: BuildHasher,
+    S: SharedBitmap,
+{
+    hasher: H,
+    bitmap: S,
+    key_size: FilterSize,
+    hashes: Option<u8>,
+    hash_width: HashWidth,
+    _key_type: PhantomData<T>,
+}
+
+impl<H, S, T> ConcurrentBloom2<H, S, T>
+where
+    H: BuildHasher,
+    S: SharedBitmap,
+    T: Hash,
+{
+    pub(crate) fn new(
+        hasher: H,
+        bitmap: S,
+        key_size: FilterSize,
+        hashes: Option<u8>,
+        hash_width: HashWidth,
+    ) -> Self {
+        Self {
+            hasher,
+            bitmap,
+            key_size,
+            hashes,
+            hash_width,
+            _key_type: PhantomData,
+        }
+    }
+
+    /// Insert places `data` into the bloom filter.
+    ///
+    /// Any subsequent calls to [`contains`](ConcurrentBloom2::contains) for
+    /// the same `data` will always return true.
+    pub fn insert(&self, data: &'_ T) {
+        for key in self.keys(data) {
+            self.bitmap.set(key);
+        }
+    }
+
+    /// Checks if `data` exists in the filter.
+    ///
+    /// If `contains` returns true, `data` has **probably** been inserted
+    /// previously. If `contains` returns false, `data` has **definitely not**
+    /// been inserted into the filter.
+    pub fn contains(&self, data: &'_ T) -> bool {
+        self.keys(data).all(|key| self.bitmap.get(key))
+    }
+
+    /// Return the byte size of this filter.
+    pub fn byte_size(&self) -> usize {
+        self.bitmap.byte_size()
+    }
+
+    /// Return a reference to the underlying bitmap.
+    pub fn bitmap(&self) -> &S {
+        &self.bitmap
+    }
+
+    /// Convert this filter into a [`Bloom2`] of the same configuration, backed
+    /// by a [`Bitmap`] of type `B` holding the same bits.
+    pub fn freeze<B>(self) -> Bloom2<H, B, T>
+    where
+        B: Bitmap + From<S>,
+    {
+        Bloom2 {
+            hasher: self.hasher,
+            bitmap: B::from(self.bitmap),
+            key_size: self.key_size,
+            hashes: self.hashes,
+            hash_width: self.hash_width,
+            chunk_scheme: ChunkScheme::Extended,
+            _key_type: PhantomData,
+        }
+    }
+
+    /// Return the bitmap keys probed for `data`.
+    fn keys(&self, data: &'_ T) -> impl Iterator<Item = usize> {
+        hash_to_keys(
+            self.hash_width.hash_one(&self.hasher, data),
+            self.hash_width,
+            self.key_size,
+            self.hashes,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, hash::BuildHasherDefault};
+
+    use proptest::prelude::*;
+    use twox_hash::XxHash64;
+
+    use super::*;
+    use crate::{
+        AtomicBitmap, AtomicCompressedBitmap, BloomFilterBuilder, CompressedBitmap, VecBitmap,
+    };
+
+    type TestHasher = BuildHasherDefault<XxHash64>;
+
+    fn assert_sync<T: Sync>(_: &T) {}
+
+    #[test]
+    fn test_concurrent_insert() {
+        let b = BloomFilterBuilder::hasher(TestHasher::default())
+            .size(FilterSize::KeyBytes3)
+            .hashes(4)
+            .build_concurrent::<AtomicBitmap, u32>();
+        assert_sync(&b);
+
+        std::thread::scope(|s| {
+            for t in 0..8 {
+                let b = &b;
+                s.spawn(move || {
+                    for v in (t..10_000).step_by(8) {
+                        b.insert(&v);
+                    }
+                });
+            }
+        });
+
+        assert!((0..10_000).all(|v| b.contains(&v)));
+
+        // Invariant: the frozen filter holds the same bits as a filter loaded
+        // by a single thread.
+        let mut want = BloomFilterBuilder::hasher(TestHasher::default())
+            .with_bitmap::<VecBitmap>()
+            .size(FilterSize::KeyBytes3)
+            .hashes(4)
+            .build();
+        want.extend(0..10_000);
+
+        let got = b.freeze::<VecBitmap>();
+        assert_eq!(got.bitmap, want.bitmap);
+        assert_eq!(got.hashes, Some(4));
+    }
+
+    #[test]
+    fn test_concurrent_sparse_insert() {
+        let b = BloomFilterBuilder::hasher(TestHasher::default())
+            .size(FilterSize::KeyBytes4)
+            .build_concurrent::<AtomicCompressedBitmap, u32>();
+        assert_sync(&b);
+
+        std::thread::scope(|s| {
+            for t in 0..8 {
+                let b = &b;
+                s.spawn(move || {
+                    for v in (t..10_000).step_by(8) {
+                        b.insert(&v);
+                    }
+                });
+            }
+        });
+
+        assert!((0..10_000).all(|v| b.contains(&v)));
+
+        let mut want = BloomFilterBuilder::hasher(TestHasher::default())
+            .size(FilterSize::KeyBytes4)
+            .build();
+        want.extend(0..10_000);
+
+        let got = b.freeze::<CompressedBitmap>();
+        assert_eq!(got.bitmap, want.bitmap);
+    }
+
+    #[test]
+    fn test_freeze_hash_width() {
+        let b = BloomFilterBuilder::hasher(TestHasher::default())
+            .size(FilterSize::KeyBytes3)
+            .hash_width(HashWidth::Bits128)
+            .build_concurrent::<AtomicBitmap, u32>();
+
+        let mut want = BloomFilterBuilder::hasher(TestHasher::default())
+            .size(FilterSize::KeyBytes3)
+            .hash_width(HashWidth::Bits128)
+            .build();
+        for v in 0..1_000 {
+            b.insert(&v);
+            want.insert(&v);
+        }
+
+        // Invariant: the frozen filter retains the hash width, holding the
+        // same bits as a filter loaded directly.
+        let got = b.freeze::<CompressedBitmap>();
+        assert_eq!(got, want);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_freeze(
+            values in prop::collection::hash_set(any::<u32>(), 0..100),
+            check in prop::collection::hash_set(any::<u32>(), 0..100),
+        ) {
+            let b = BloomFilterBuilder::hasher(TestHasher::default())
+                .size(FilterSize::KeyBytes2)
+                .build_concurrent::<AtomicBitmap, u32>();
+            for v in &values {
+                b.insert(v);
+            }
+
+            let want = check
+                .iter()
+                .chain(&values)
+                .map(|v| (*v, b.contains(v)))
+                .collect::<HashSet<_>>();
+
+            // Invariant: no false negatives.
+            assert!(values.iter().all(|v| b.contains(v)));
+
+            // Invariant: the frozen filter agrees with the concurrent filter.
+            let got = b.freeze::<CompressedBitmap>();
+            for (v, contains) in want {
+                assert_eq!(got.contains(&v), contains);
+            }
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use crate::{
+    bitmap::{CompressedCounters, CounterWidth},
+    bloom::{hash_to_keys, key_size_to_bits},
+    Bitmap, Bloom2, ChunkScheme, CompressedBitmap, FilterSize, HashWidth,
+};
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+
+/// A sparse bloom filter supporting removal of values.
+///
+/// A [`Bloom2`] cannot remove a value, as the bits set for it may be shared
+/// with other values - clearing them would cause false negatives. A
+/// `CountingBloom2` instead maintains a small saturating counter for each key,
+/// incremented when a value is inserted and decremented when it is removed.
+///
+/// ```rust
+/// use bloom2::{BloomFilterBuilder, CounterWidth};
+///
+/// let mut filter = BloomFilterBuilder::default().build_counting(CounterWidth::Bits4);
+///
+/// filter.insert(&"hello 🐐");
+/// assert!(filter.contains(&"hello 🐐"));
+///
+/// assert!(filter.remove(&"hello 🐐"));
+/// assert!(!filter.contains(&"hello 🐐"));
+/// ```
+///
+/// Like the [`CompressedBitmap`], the counters are stored in lazily allocated
+/// blocks, and a block is freed once all the counters within it return to
+/// zero.
+///
+/// Once a counter reaches the maximum value of its [`CounterWidth`] it is
+/// never decremented, as it no longer tracks the number of values sharing the
+/// key. Any value probing a saturated key can never be fully removed, but will
+/// never produce a false negative.
+///
+/// A `CountingBloom2` can be converted into a (much smaller) [`Bloom2`] with
+/// the same configuration, containing the values that have not been removed:
+///
+/// ```rust
+/// use bloom2::{Bloom2, BloomFilterBuilder, CounterWidth};
+///
+/// let mut filter = BloomFilterBuilder::default().build_counting(CounterWidth::Bits8);
+/// filter.insert(&"cat");
+/// filter.insert(&"dog");
+/// filter.remove(&"dog");
+///
+/// let filter = Bloom2::from(filter);
+/// assert!(filter.contains(&"cat"));
+/// assert!(!filter.contains(&"dog"));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct CountingBloom2<H, T>
+where
+    H: BuildHasher,
+{
+    hasher: H,
+    counters: CompressedCounters,
+    key_size: FilterSize,
+    hashes: Option<u8>,
+    hash_width: HashWidth,
+    _key_type: PhantomData<T>,
+}
+
+impl<H, T> CountingBloom2<H, T>
+where
+    H: BuildHasher,
+    T: Hash,
+{
+    pub(crate) fn new(
+        hasher: H,
+        key_size: FilterSize,
+        hashes: Option<u8>,
+        hash_width: HashWidth,
+        width: CounterWidth,
+    ) -> Self {
+        Self {
+            hasher,
+            counters: CompressedCounters::new(key_size_to_bits(key_size), width),
+            key_size,
+            hashes,
+            hash_width,
+            _key_type: PhantomData,
+        }
+    }
+
+    /// Insert places `data` into the filter.
+    ///
+    /// Any subsequent calls to [`contains`](CountingBloom2::contains) for the
+    /// same `data` will return true until it is
+    /// [removed](CountingBloom2::remove).
+    pub fn insert(&mut self, data: &'_ T) {
+        for key in self.keys(data) {
+            self.counters.increment(key);
+        }
+    }
+
+    /// Remove a single instance of `data` from the filter, returning `true`
+    /// if it was (probably) present.
+    ///
+    /// If `data` is not contained in the filter, `false` is returned and the
+    /// filter is left unchanged.
+    ///
+    /// Only values that were previously inserted should be removed - removing
+    /// a false positive decrements counters belonging to other values, which
+    /// may then produce false negatives.
+    pub fn remove(&mut self, data: &'_ T) -> bool {
+        let hash = self.hash_width.hash_one(&self.hasher, data);
+        let keys = || hash_to_keys(hash, self.hash_width, self.key_size, self.hashes);
+        if !keys().all(|key| self.counters.get(key) > 0) {
+            return false;
+        }
+
+        for key in keys() {
+            self.counters.decrement(key);
+        }
+
+        true
+    }
+
+    /// Checks if `data` exists in the filter.
+    ///
+    /// If `contains` returns true, `data` has **probably** been inserted
+    /// previously (and not removed). If `contains` returns false, `data` is
+    /// **definitely not** in the filter.
+    pub fn contains(&self, data: &'_ T) -> bool {
+        self.keys(data).all(|key| self.counters.get(key) > 0)
+    }
+
+    /// Return the width of the counters in this filter.
+    pub fn counter_width(&self) -> CounterWidth {
+        self.counters.width()
+    }
+
+    /// Return the byte size of this filter.
+    pub fn byte_size(&self) -> usize {
+        self.counters.byte_size()
+    }
+
+    /// Return the bitmap keys probed for `data`.
+    fn keys(&self, data: &'_ T) -> impl Iterator<Item = usize> {
+        hash_to_keys(
+            self.hash_width.hash_one(&self.hasher, data),
+            self.hash_width,
+            self.key_size,
+            self.hashes,
+        )
+    }
+}
+
+/// Convert a [`CountingBloom2`] into a [`Bloom2`] of the same configuration,
+/// with a bit set for every non-zero counter.
+impl<H, T> From<CountingBloom2<H, T>> for Bloom2<H, CompressedBitmap, T>
+where
+    H: BuildHasher,
+{
+    fn from(v: CountingBloom2<H, T>) -> Self {
+        let mut bitmap = CompressedBitmap::new_with_capacity(key_size_to_bits(v.key_size));
+        for key in v.counters.iter_nonzero() {
+            bitmap.set(key, true);
+        }
+
+        Self {
+            hasher: v.hasher,
+            bitmap,
+            key_size: v.key_size,
+            hashes: v.hashes,
+            hash_width: v.hash_width,
+            chunk_scheme: ChunkScheme::Extended,
+            _key_type: PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, hash::BuildHasherDefault};
+
+    use proptest::prelude::*;
+    use twox_hash::XxHash64;
+
+    use super::*;
+    use crate::BloomFilterBuilder;
+
+    type TestHasher = BuildHasherDefault<XxHash64>;
+
+    fn new_test_filter(
+        size: FilterSize,
+        hashes: Option<u8>,
+        width: CounterWidth,
+    ) -> CountingBloom2<TestHasher, u32> {
+        CountingBloom2::new(
+            TestHasher::default(),
+            size,
+            hashes,
+            HashWidth::Bits64,
+            width,
+        )
+    }
+
+    #[test]
+    fn test_builder() {
+        let mut b = BloomFilterBuilder::hasher(TestHasher::default())
+            .size(FilterSize::KeyBytes3)
+            .hashes(5)
+            .build_counting(CounterWidth::Bits8);
+
+        b.insert(&42);
+        assert!(b.contains(&42));
+        assert_eq!(b.counter_width(), CounterWidth::Bits8);
+        assert_eq!(b.key_size, FilterSize::KeyBytes3);
+        assert_eq!(b.hashes, Some(5));
+    }
+
+    #[test]
+    fn test_hash_width() {
+        let mut b = BloomFilterBuilder::hasher(TestHasher::default())
+            .size(FilterSize::KeyBytes3)
+            .hash_width(HashWidth::Bits128)
+            .build_counting(CounterWidth::Bits4);
+
+        for v in 0..100 {
+            b.insert(&v);
+        }
+        assert!(b.remove(&42));
+        assert!(!b.contains(&42));
+
+        // Invariant: the converted filter probes the same keys.
+        let got = Bloom2::from(b);
+        assert_eq!(got.hash_width, HashWidth::Bits128);
+        assert!((0..100).filter(|v| *v != 42).all(|v| got.contains(&v)));
+    }
+
+    #[test]
+    fn test_remove_absent() {
+        let mut b = new_test_filter(FilterSize::KeyBytes2, None, CounterWidth::Bits4);
+        b.insert(&1);
+
+        let before = b.clone();
+        assert!(!b.remove(&2));
+        assert_eq!(b, before);
+    }
+
+    #[test]
+    fn test_remove_frees_blocks() {
+        let mut b = new_test_filter(FilterSize::KeyBytes3, Some(4), CounterWidth::Bits4);
+        let empty = b.byte_size();
+
+        for i in 0..100 {
+            b.insert(&i);
+        }
+        assert!(b.byte_size() > empty);
+
+        for i in 0..100 {
+            assert!(b.remove(&i));
+        }
+
+        assert_eq!(b.counters.num_blocks(), 0);
+        assert_eq!(b.counters.iter_nonzero().count(), 0);
+    }
+
+    #[test]
+    fn test_saturated_never_removed() {
+        let mut b = new_test_filter(FilterSize::KeyBytes2, None, CounterWidth::Bits4);
+
+        for _ in 0..20 {
+            b.insert(&42);
+        }
+
+        // Invariant: the counters for 42 saturate, and are never decremented.
+        for _ in 0..20 {
+            assert!(b.remove(&42));
+        }
+        assert!(b.contains(&42));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_insert_remove(
+            ops in prop::collection::vec((0..50_u32, any::<bool>()), 0..200),
+            hashes in prop::option::of(1..8_u8),
+            bits8 in any::<bool>(),
+        ) {
+            let width = if bits8 { CounterWidth::Bits8 } else { CounterWidth::Bits4 };
+            let mut b = new_test_filter(FilterSize::KeyBytes2, hashes, width);
+            let mut control = HashMap::<u32, usize>::new();
+
+            for (v, insert) in ops {
+                let count = control.entry(v).or_default();
+                if insert {
+                    b.insert(&v);
+                    *count += 1;
+                } else if *count > 0 {
+                    assert!(b.remove(&v));
+                    *count -= 1;
+                }
+            }
+
+            // Invariant: no false negatives for values inserted more times
+            // than they were removed.
+            for (v, count) in &control {
+                if *count > 0 {
+                    assert!(b.contains(v));
+                }
+            }
+
+            // Invariant: the converted filter agrees with the counting filter.
+            let converted = Bloom2::from(b.clone());
+            for v in 0..100 {
+                assert_eq!(converted.contains(&v), b.contains(&v));
+            }
+        }
+
+        #[test]
+        fn prop_into_bloom2(
+            values in prop::collection::hash_set(any::<u32>(), 0..100),
+            removed in prop::collection::hash_set(any::<u32>(), 0..100),
+            hashes in prop::option::of(1..8_u8),
+        ) {
+            let mut b = new_test_filter(FilterSize::KeyBytes2, hashes, CounterWidth::Bits8);
+            let mut want = BloomFilterBuilder::hasher(TestHasher::default())
+                .size(FilterSize::KeyBytes2);
+            if let Some(k) = hashes {
+                want = want.hashes(k);
+            }
+            let mut want = want.build();
+
+            for v in values.iter().chain(&removed) {
+                b.insert(v);
+            }
+            for v in &removed {
+                assert!(b.remove(v));
+            }
+            for v in &values {
+                want.insert(v);
+            }
+
+            // Invariant: removing values leaves the same bits as never
+            // inserting them, provided no counter saturates.
+            let got = Bloom2::from(b);
+            assert_eq!(got.bitmap, want.bitmap);
+        }
+    }
+}
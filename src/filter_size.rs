@@ -6,10 +6,17 @@
 /// The false positive probability for a bloom filter increases as the number of
 /// entries increases. This relationship is demonstrated using 64bit hashes as
 /// keys for each possible filter configuration below - you should choose a
-/// filter size for your expected load level and hash size.
+/// filter size for your expected load level and hash size, or use
+/// [`BloomFilterBuilder::with_capacity()`] to choose one for a target false
+/// positive rate.
 ///
-/// The value of FilterSize controls the `k` property of the filter: `k =
-/// input_length_bytes / FilterSize`.
+/// By default, the value of FilterSize controls the `k` property of the
+/// filter: `k = input_length_bytes / FilterSize`. The probability curves below
+/// use this default - `k` can instead be chosen independently of the filter
+/// size with [`BloomFilterBuilder::hashes()`](crate::BloomFilterBuilder::hashes),
+/// or doubled with a 128-bit [`HashWidth`](crate::HashWidth).
+///
+/// [`BloomFilterBuilder::with_capacity()`]: crate::BloomFilterBuilder::with_capacity
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FilterSize {
@@ -92,33 +99,33 @@ pub enum FilterSize {
     /// memory usage of ~262KB bytes and a maximum memory usage of ~2MB when
     /// fully populated.
     ///
-    /// When using a 64bit hash (2x3 byte keys, `k=2`) the probability of a
+    /// When using a 64bit hash (3x3 byte keys, `k=3`) the probability of a
     /// false positive is:
     ///
     /// ```text
-    ///         1 +--+------------------+-------------------+------------------+-----------+
-    ///           |                                                                   *    |
+    ///         1 +--+------------------+-------------------+------------------+------*----+
     ///           |                                                *                       |
+    ///           |                                  *                                     |
     ///       0.8 +                                                                        +
-    ///     P     |                                  *                                     |
-    ///     r     |                                                                        |
+    ///     P     |                                                                        |
+    ///     r     |                         *                                              |
     ///     o     |                                                                        |
-    ///     b 0.6 +                         *                                              +
-    ///     a     |                                                                        |
+    ///     b 0.6 +                                                                        +
+    ///     a     |                  *                                                     |
     ///     b     |                                                                        |
-    ///     i 0.4 +                  *                                                     +
-    ///     l     |                                                                        |
-    ///     i     |              *                                                         |
+    ///     i 0.4 +                                                                        +
+    ///     l     |              *                                                         |
+    ///     i     |                                                                        |
     ///     t 0.2 +                                                                        +
     ///     y     |          *                                                             |
-    ///           |      * *                                                               |
-    ///         0 +  ****                                                                  +
+    ///           |        *                                                               |
+    ///         0 +  *****                                                                 +
     ///           +--+------------------+-------------------+------------------+-----------+
     ///              0                1e+07               2e+07              3e+07
     ///                                       Number of Entries
     /// ```
     ///
-    /// The probability of false positives reaches 1-in-2 after 10300768
+    /// The probability of false positives reaches 1-in-2 after 8827200
     /// entries.
     ///
     /// An empty sparse bloom filter would require 4096x64 bit block map entries
@@ -173,33 +180,33 @@ pub enum FilterSize {
     /// If you actually need this get in touch - I have some ideas for reducing
     /// the memory footprint even further.
     ///
-    /// When using a 64bit hash (1x5 byte keys, `k=1`) the probability of a
+    /// When using a 64bit hash (2x5 byte keys, `k=2`) the probability of a
     /// false positive is:
     ///
     /// ```text
     ///           +--+----------+---------+---------+----------+---------+---------+-------+
-    ///         1 +                                                *                  *    +
-    ///           |                                  *                                     |
+    ///         1 +                                  *             *                  *    +
     ///           |                         *                                              |
+    ///           |                  *                                                     |
     ///     P 0.8 +                                                                        +
-    ///     r     |                  *                                                     |
-    ///     o     |              *                                                         |
+    ///     r     |              *                                                         |
+    ///     o     |                                                                        |
     ///     b 0.6 +                                                                        +
     ///     a     |          *                                                             |
     ///     b     |                                                                        |
     ///     i 0.4 +        *                                                               +
     ///     l     |                                                                        |
     ///     i     |      *                                                                 |
-    ///     t 0.2 +     *                                                                  +
-    ///     y     |    *                                                                   |
-    ///           |   *                                                                    |
+    ///     t 0.2 +                                                                        +
+    ///     y     |     *                                                                  |
+    ///           |    *                                                                   |
     ///         0 +  **                                                                    +
     ///           +--+----------+---------+---------+----------+---------+---------+-------+
     ///              0        1e+12     2e+12     3e+12      4e+12     5e+12     6e+12
     ///                                       Number of Entries
     /// ```
     ///
-    /// The probability of false positives reaches 1-in-2 after 762123384786
+    /// The probability of false positives reaches 1-in-2 after 675071099868
     /// entries.
     ///
     /// An empty sparse bloom filter would require 268435456x64 bit block map
@@ -208,3 +215,252 @@ pub enum FilterSize {
     ///
     KeyBytes5 = 5,
 }
+
+impl FilterSize {
+    /// All filter sizes, from smallest to largest.
+    pub(crate) const ALL: [FilterSize; 5] = [
+        FilterSize::KeyBytes1,
+        FilterSize::KeyBytes2,
+        FilterSize::KeyBytes3,
+        FilterSize::KeyBytes4,
+        FilterSize::KeyBytes5,
+    ];
+
+    /// The number of bits in a bitmap of this size.
+    fn bits(self) -> f64 {
+        2_f64.powi(8 * self as i32)
+    }
+}
+
+/// The filter parameters chosen for an expected number of items and target
+/// false positive rate.
+///
+/// See [`BloomFilterBuilder::with_capacity()`].
+///
+/// ```rust
+/// use bloom2::{FilterParams, FilterSize};
+///
+/// let params = FilterParams::new(1_000_000, 0.01).unwrap();
+///
+/// assert_eq!(params.size(), FilterSize::KeyBytes3);
+/// assert!(params.predicted_fpr() <= 0.01);
+/// ```
+///
+/// [`BloomFilterBuilder::with_capacity()`]: crate::BloomFilterBuilder::with_capacity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterParams {
+    size: FilterSize,
+    hashes: u8,
+    expected_items: usize,
+    predicted_fpr: f64,
+}
+
+impl FilterParams {
+    /// Choose the smallest [`FilterSize`], and the fewest hashes for it, that
+    /// is predicted to hold `expected_items` with a false positive rate no
+    /// more than `target_fpr`.
+    ///
+    /// Returns an error if `target_fpr` is not within `(0, 1)`, or cannot be
+    /// reached by any filter size.
+    pub fn new(expected_items: usize, target_fpr: f64) -> Result<Self, CapacityError> {
+        if !(target_fpr > 0.0 && target_fpr < 1.0) {
+            return Err(CapacityError::FalsePositiveRate);
+        }
+
+        let mut best = 1.0_f64;
+        for &size in FilterSize::ALL.iter() {
+            let params = Self::optimal(size, expected_items);
+            if params.predicted_fpr <= target_fpr {
+                return Ok(params.fewest_hashes(target_fpr));
+            }
+            best = best.min(params.predicted_fpr);
+        }
+
+        Err(CapacityError::Unreachable { best })
+    }
+
+    /// Return the parameters minimising the false positive rate of a filter
+    /// of `size` holding `n` items.
+    fn optimal(size: FilterSize, n: usize) -> Self {
+        // The rate is minimised at k = (m / n) * ln(2) - evaluate the integers
+        // either side of it. An empty filter never returns a false positive,
+        // and needs only a single hash.
+        let k = match n {
+            0 => 1.0,
+            n => (size.bits() / n as f64 * std::f64::consts::LN_2).clamp(1.0, u8::MAX as f64),
+        };
+
+        let (hashes, predicted_fpr) = [k.floor(), k.ceil()]
+            .iter()
+            .map(|&k| (k as u8, predicted_fpr(size, k as u8, n)))
+            .fold((1, 1.0), |best, v| if v.1 < best.1 { v } else { best });
+
+        Self {
+            size,
+            hashes,
+            expected_items: n,
+            predicted_fpr,
+        }
+    }
+
+    /// Return the parameters with the fewest hashes reaching `target_fpr`,
+    /// given these optimal parameters reach it.
+    fn fewest_hashes(self, target_fpr: f64) -> Self {
+        // The rate falls with each hash added up to the optimum, so binary
+        // search for the first to reach the target.
+        let (mut lo, mut hi) = (1, self.hashes);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if predicted_fpr(self.size, mid, self.expected_items) <= target_fpr {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+
+        Self {
+            hashes: lo,
+            predicted_fpr: predicted_fpr(self.size, lo, self.expected_items),
+            ..self
+        }
+    }
+
+    /// The chosen filter size.
+    pub fn size(&self) -> FilterSize {
+        self.size
+    }
+
+    /// The chosen number of hashes (`k`) probed per item.
+    pub fn hashes(&self) -> u8 {
+        self.hashes
+    }
+
+    /// The expected number of items these parameters were chosen for.
+    pub fn expected_items(&self) -> usize {
+        self.expected_items
+    }
+
+    /// The predicted false positive rate once the filter holds
+    /// [`expected_items()`](FilterParams::expected_items) unique items.
+    pub fn predicted_fpr(&self) -> f64 {
+        self.predicted_fpr
+    }
+}
+
+/// Return the approximate false positive rate of a filter of `size` probing
+/// `k` hashes per item after `n` unique inserts:
+///
+/// ```text
+///     (1 - e^(-kn/m))^k
+/// ```
+fn predicted_fpr(size: FilterSize, k: u8, n: usize) -> f64 {
+    let k = k as f64;
+    // exp_m1() retains precision for the tiny exponents of large filters.
+    (-(-k * n as f64 / size.bits()).exp_m1()).powf(k)
+}
+
+/// An error returned when no filter parameters satisfy the requested capacity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CapacityError {
+    /// The target false positive rate is not within `(0, 1)`.
+    FalsePositiveRate,
+
+    /// The target false positive rate cannot be reached for the expected
+    /// number of items - the `best` rate is the lowest achievable.
+    Unreachable {
+        /// The lowest achievable false positive rate.
+        best: f64,
+    },
+}
+
+impl std::fmt::Display for CapacityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FalsePositiveRate => write!(f, "false positive rate must be within (0, 1)"),
+            Self::Unreachable { best } => write!(
+                f,
+                "target false positive rate is unreachable (lowest achievable is {})",
+                best
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CapacityError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_params() {
+        for &(n, fpr, want_size) in &[
+            (0, 0.5, FilterSize::KeyBytes1),
+            (10, 0.01, FilterSize::KeyBytes1),
+            (1_000, 0.01, FilterSize::KeyBytes2),
+            (20_000, 0.25, FilterSize::KeyBytes2),
+            (20_000, 0.01, FilterSize::KeyBytes3),
+            (1_000_000, 0.01, FilterSize::KeyBytes3),
+            (100_000_000, 0.01, FilterSize::KeyBytes4),
+            (1_000_000_000, 0.01, FilterSize::KeyBytes5),
+        ] {
+            let got = FilterParams::new(n, fpr).unwrap();
+            assert_eq!(got.size(), want_size, "n={} fpr={}", n, fpr);
+            assert_eq!(got.expected_items(), n);
+            assert!(got.predicted_fpr() <= fpr);
+            assert!(got.hashes() >= 1);
+
+            // Invariant: one fewer hash does not reach the target.
+            assert!(got.hashes() == 1 || predicted_fpr(got.size(), got.hashes() - 1, n) > fpr);
+        }
+    }
+
+    #[test]
+    fn test_optimal_hashes() {
+        // m / n * ln(2) = 65536 / 20000 * 0.693 = 2.27
+        let got = FilterParams::new(20_000, 0.25).unwrap();
+        assert_eq!(got.hashes(), 2);
+        assert!((got.predicted_fpr() - 0.2088).abs() < 0.001);
+
+        // The optimum of m / n * ln(2) = 256 * 0.693 = 177.4 hashes is far
+        // below the target, which a single hash reaches.
+        let got = FilterParams::new(1, 0.01).unwrap();
+        assert_eq!(got.size(), FilterSize::KeyBytes1);
+        assert_eq!(got.hashes(), 1);
+        assert!((got.predicted_fpr() - 0.0039).abs() < 0.0001);
+
+        // m / n * ln(2) = 65536 / 1000 * 0.693 = 45.4, but 2 hashes reach the
+        // target.
+        let got = FilterParams::new(1_000, 0.01).unwrap();
+        assert_eq!(got.size(), FilterSize::KeyBytes2);
+        assert_eq!(got.hashes(), 2);
+
+        // Very lightly loaded filters are capped at the maximum number of
+        // hashes.
+        assert_eq!(
+            FilterParams::optimal(FilterSize::KeyBytes2, 1).hashes(),
+            255
+        );
+
+        // And empty filters need only a single hash.
+        assert_eq!(FilterParams::optimal(FilterSize::KeyBytes2, 0).hashes(), 1);
+    }
+
+    #[test]
+    fn test_invalid_fpr() {
+        for &fpr in &[0.0, 1.0, -0.1, 1.5, f64::NAN] {
+            assert_eq!(
+                FilterParams::new(100, fpr),
+                Err(CapacityError::FalsePositiveRate)
+            );
+        }
+    }
+
+    #[test]
+    fn test_unreachable() {
+        match FilterParams::new(usize::MAX, 0.01) {
+            Err(CapacityError::Unreachable { best }) => assert!(best > 0.01),
+            v => panic!("unexpected result {:?}", v),
+        }
+    }
+}
@@ -0,0 +1,765 @@
+//! A versioned, portable binary file format for [`Bloom2`] filters.
+//!
+//! A filter wrote with [`Bloom2::write_to()`] uses the layout below, with all
+//! multi-byte integers encoded as little-endian:
+//!
+//! ```text
+//!     ┌──────────────────────┐
+//!     │     magic number     │  8 bytes, "bloom2\0\0"
+//!     ├──────────────────────┤
+//!     │    format version    │  u32
+//!     ├──────────────────────┤
+//!     │     filter size      │  u8, the FilterSize key bytes
+//!     ├──────────────────────┤
+//!     │     bitmap kind      │  u8
+//!     ├──────────────────────┤
+//!     │    hash count (k)    │  u8, 0 when split from the hash
+//!     ├──────────────────────┤
+//!     │      hash width      │  u8, 0 for 64-bit or 1 for 128-bit
+//!     ├──────────────────────┤
+//!     │     chunk scheme     │  u8, 0 for extended or 1 for truncated
+//!     ├──────────────────────┤
+//!     │       reserved       │  7 bytes, zero
+//!     ├──────────────────────┤
+//!     │      hasher ID       │  u64
+//!     ├──────────────────────┤
+//!     │    bitmap words      │  u64 words, see below
+//!     ├──────────────────────┤
+//!     │       checksum       │  u64, FNV-1a of all preceding bytes
+//!     └──────────────────────┘
+//! ```
+//!
+//! The header is 32 bytes long, so the bitmap words are 8-byte aligned
+//! relative to the start of the file. The bitmap words are encoded according
+//! to the bitmap kind:
+//!
+//! * `0` - a [`VecBitmap`]: the number of words `N`, followed by `N` words.
+//! * `1` - a [`CompressedBitmap`]: the layout documented in
+//!   [`CompressedBitmapRef`](crate::CompressedBitmapRef).
+//! * `2` - a [`BlockedBitmap`]: the number of block map words `N`, the number
+//!   of non-empty blocks `M`, followed by the `N` block map words marking the
+//!   non-empty blocks and the 8 words of each of the `M` blocks, ordered by
+//!   their block index.
+//!
+//! The hash count is the number of keys set by
+//! [`BloomFilterBuilder::hashes()`](crate::BloomFilterBuilder::hashes), or `0`
+//! for filters that split the hash into [`FilterSize`] chunks. The hash width
+//! and chunk scheme are the [`HashWidth`] and [`ChunkScheme`] of the filter.
+//!
+//! The hasher ID is an arbitrary, caller-provided value identifying the hash
+//! function (and any seed / key) used to populate the filter - the bitmap is
+//! meaningless when queried with a different hasher, and reading a file with a
+//! mismatched hasher ID is rejected.
+//!
+//! A file holding a [`CompressedBitmap`] can also be queried in place, without
+//! reading it into memory, with
+//! [`Bloom2Ref::from_file_bytes()`](crate::Bloom2Ref::from_file_bytes).
+//!
+//! [`VecBitmap`]: crate::VecBitmap
+//! [`CompressedBitmap`]: crate::CompressedBitmap
+//! [`BlockedBitmap`]: crate::BlockedBitmap
+//! [`HashWidth`]: crate::HashWidth
+//! [`ChunkScheme`]: crate::ChunkScheme
+
+use std::{
+    convert::TryFrom,
+    hash::{BuildHasher, Hash},
+    io::{self, Read, Write},
+    marker::PhantomData,
+};
+
+use crate::{Bitmap, Bloom2, ChunkScheme, FilterSize, HashWidth, LayoutError};
+
+const MAGIC: [u8; 8] = *b"bloom2\0\0";
+
+/// The current file format version.
+const VERSION: u32 = 1;
+
+/// The length of the file header in bytes.
+const HEADER_LEN: usize = 32;
+
+/// The length of the trailing checksum in bytes.
+const CHECKSUM_LEN: usize = 8;
+
+/// A [`Bitmap`] that can be persisted in the [`Bloom2`] file format.
+///
+/// See [`Bloom2::write_to()`].
+pub trait PortableBitmap: Bitmap + Sized {
+    /// The bitmap kind identifier stored in the file header.
+    const KIND: u8;
+
+    /// Write the bitmap to `w` as a sequence of little-endian `u64` words.
+    fn write_words<W: Write>(&self, w: W) -> io::Result<()>;
+
+    /// Read a bitmap with capacity for `max_key` bits previously wrote by
+    /// [`write_words()`](PortableBitmap::write_words) from `r`.
+    fn read_words<R: Read>(r: R, max_key: usize) -> Result<Self, FormatError>;
+}
+
+/// An error reading a [`Bloom2`] filter from the file format.
+#[derive(Debug)]
+pub enum FormatError {
+    /// An I/O error occurred reading the filter, including a file that ends
+    /// unexpectedly.
+    Io(io::Error),
+
+    /// The file does not begin with the expected magic number.
+    Magic,
+
+    /// The file was wrote with an unsupported format version.
+    Version(u32),
+
+    /// The file contains an invalid [`FilterSize`].
+    FilterSize(u8),
+
+    /// The file contains an invalid [`HashWidth`].
+    HashWidth(u8),
+
+    /// The file contains an invalid [`ChunkScheme`].
+    ChunkScheme(u8),
+
+    /// The file contains a different kind of bitmap than requested.
+    BitmapKind {
+        /// The bitmap kind requested.
+        want: u8,
+        /// The bitmap kind in the file.
+        got: u8,
+    },
+
+    /// The file was wrote with a different hasher than requested.
+    Hasher {
+        /// The hasher ID requested.
+        want: u64,
+        /// The hasher ID in the file.
+        got: u64,
+    },
+
+    /// The bitmap words are invalid.
+    Layout(LayoutError),
+
+    /// The checksum does not match the file content.
+    Checksum,
+}
+
+impl std::fmt::Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "i/o error: {}", e),
+            Self::Magic => write!(f, "not a bloom2 file"),
+            Self::Version(v) => write!(f, "unsupported format version {}", v),
+            Self::FilterSize(v) => write!(f, "invalid filter size {}", v),
+            Self::HashWidth(v) => write!(f, "invalid hash width {}", v),
+            Self::ChunkScheme(v) => write!(f, "invalid chunk scheme {}", v),
+            Self::BitmapKind { want, got } => {
+                write!(f, "bitmap kind {} does not match requested {}", got, want)
+            }
+            Self::Hasher { want, got } => {
+                write!(f, "hasher ID {} does not match requested {}", got, want)
+            }
+            Self::Layout(e) => write!(f, "invalid bitmap: {}", e),
+            Self::Checksum => write!(f, "checksum mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Layout(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FormatError {
+    fn from(v: io::Error) -> Self {
+        Self::Io(v)
+    }
+}
+
+impl From<LayoutError> for FormatError {
+    fn from(v: LayoutError) -> Self {
+        Self::Layout(v)
+    }
+}
+
+impl<H, B, T> Bloom2<H, B, T>
+where
+    H: BuildHasher,
+    B: PortableBitmap,
+    T: Hash,
+{
+    /// Write the filter to `w` in the portable, versioned file format.
+    ///
+    /// The `hasher_id` is an arbitrary value identifying the hasher (and any
+    /// seed) used by this filter, which must be provided again when reading
+    /// the filter with [`Bloom2::read_from()`]. Filters using a hasher with
+    /// random state (such as the default [`RandomState`]) cannot be usefully
+    /// read in another process.
+    ///
+    /// This method writes many small values - using a buffered writer such as
+    /// [`BufWriter`](std::io::BufWriter) is recommended.
+    ///
+    /// ```rust
+    /// use std::collections::hash_map::DefaultHasher;
+    /// use std::hash::BuildHasherDefault;
+    /// use bloom2::{Bloom2, BloomFilterBuilder, CompressedBitmap};
+    ///
+    /// type MyHasher = BuildHasherDefault<DefaultHasher>;
+    /// const MY_HASHER_ID: u64 = 42;
+    ///
+    /// let mut filter = BloomFilterBuilder::hasher(MyHasher::default()).build();
+    /// filter.insert(&"hello 🐐");
+    ///
+    /// let mut buf = Vec::new();
+    /// filter.write_to(&mut buf, MY_HASHER_ID).unwrap();
+    ///
+    /// let filter: Bloom2<_, CompressedBitmap, &str> =
+    ///     Bloom2::read_from(buf.as_slice(), MyHasher::default(), MY_HASHER_ID).unwrap();
+    /// assert!(filter.contains(&"hello 🐐"));
+    /// ```
+    ///
+    /// [`RandomState`]: std::collections::hash_map::RandomState
+    pub fn write_to<W: Write>(&self, w: W, hasher_id: u64) -> io::Result<()> {
+        let mut w = ChecksumWriter::new(w);
+
+        w.write_all(&MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&[
+            self.key_size as u8,
+            B::KIND,
+            self.hashes.unwrap_or(0),
+            hash_width_to_u8(self.hash_width),
+            chunk_scheme_to_u8(self.chunk_scheme),
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ])?;
+        w.write_all(&hasher_id.to_le_bytes())?;
+        self.bitmap.write_words(&mut w)?;
+
+        let (mut w, checksum) = w.finish();
+        w.write_all(&checksum.to_le_bytes())
+    }
+
+    /// Read a filter wrote by [`Bloom2::write_to()`] from `r`, using `hasher`
+    /// to hash values.
+    ///
+    /// Returns an error if the file is corrupt, uses a different bitmap type
+    /// to `B`, or was wrote with a different `hasher_id`.
+    pub fn read_from<R: Read>(r: R, hasher: H, hasher_id: u64) -> Result<Self, FormatError> {
+        let mut r = ChecksumReader::new(r);
+
+        let mut header = [0; HEADER_LEN];
+        r.read_exact(&mut header)?;
+        let header = Header::parse(&header, B::KIND, hasher_id)?;
+
+        let bitmap = B::read_words(&mut r, crate::bloom::key_size_to_bits(header.key_size))?;
+
+        let (mut r, want) = r.finish();
+        let mut checksum = [0; CHECKSUM_LEN];
+        r.read_exact(&mut checksum)?;
+        if u64::from_le_bytes(checksum) != want {
+            return Err(FormatError::Checksum);
+        }
+
+        Ok(Self {
+            hasher,
+            bitmap,
+            key_size: header.key_size,
+            hashes: header.hashes,
+            hash_width: header.hash_width,
+            chunk_scheme: header.chunk_scheme,
+            _key_type: PhantomData,
+        })
+    }
+}
+
+/// The filter configuration held in the file header.
+#[derive(Debug)]
+pub(crate) struct Header {
+    pub(crate) key_size: FilterSize,
+    pub(crate) hashes: Option<u8>,
+    pub(crate) hash_width: HashWidth,
+    pub(crate) chunk_scheme: ChunkScheme,
+}
+
+impl Header {
+    /// Parse and validate `header`, which must describe a bitmap of `kind`
+    /// populated using the hasher identified by `hasher_id`.
+    pub(crate) fn parse(
+        header: &[u8; HEADER_LEN],
+        kind: u8,
+        hasher_id: u64,
+    ) -> Result<Self, FormatError> {
+        if header[..8] != MAGIC {
+            return Err(FormatError::Magic);
+        }
+
+        let version = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        if version != VERSION {
+            return Err(FormatError::Version(version));
+        }
+
+        let key_size = filter_size_from_u8(header[12])?;
+
+        if header[13] != kind {
+            return Err(FormatError::BitmapKind {
+                want: kind,
+                got: header[13],
+            });
+        }
+
+        let hashes = match header[14] {
+            0 => None,
+            k => Some(k),
+        };
+
+        let hash_width = hash_width_from_u8(header[15])?;
+        let chunk_scheme = chunk_scheme_from_u8(header[16])?;
+
+        let mut id = [0; 8];
+        id.copy_from_slice(&header[24..]);
+        let got = u64::from_le_bytes(id);
+        if got != hasher_id {
+            return Err(FormatError::Hasher {
+                want: hasher_id,
+                got,
+            });
+        }
+
+        Ok(Self {
+            key_size,
+            hashes,
+            hash_width,
+            chunk_scheme,
+        })
+    }
+}
+
+/// Parse and validate the header and checksum of `bytes`, a complete file
+/// holding a bitmap of `kind`, returning the header and the bitmap words.
+pub(crate) fn parse_file(
+    bytes: &[u8],
+    kind: u8,
+    hasher_id: u64,
+) -> Result<(Header, &[u8]), FormatError> {
+    if bytes.len() < HEADER_LEN + CHECKSUM_LEN {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    let (content, checksum) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+    let (header, words) = content.split_at(HEADER_LEN);
+    let header = Header::parse(
+        <&[u8; HEADER_LEN]>::try_from(header).unwrap(),
+        kind,
+        hasher_id,
+    )?;
+
+    let checksum = <[u8; CHECKSUM_LEN]>::try_from(checksum).unwrap();
+    if u64::from_le_bytes(checksum) != fnv1a(FNV_OFFSET, content) {
+        return Err(FormatError::Checksum);
+    }
+
+    Ok((header, words))
+}
+
+fn filter_size_from_u8(v: u8) -> Result<FilterSize, FormatError> {
+    Ok(match v {
+        1 => FilterSize::KeyBytes1,
+        2 => FilterSize::KeyBytes2,
+        3 => FilterSize::KeyBytes3,
+        4 => FilterSize::KeyBytes4,
+        5 => FilterSize::KeyBytes5,
+        _ => return Err(FormatError::FilterSize(v)),
+    })
+}
+
+fn hash_width_to_u8(v: HashWidth) -> u8 {
+    match v {
+        HashWidth::Bits64 => 0,
+        HashWidth::Bits128 => 1,
+    }
+}
+
+fn hash_width_from_u8(v: u8) -> Result<HashWidth, FormatError> {
+    Ok(match v {
+        0 => HashWidth::Bits64,
+        1 => HashWidth::Bits128,
+        _ => return Err(FormatError::HashWidth(v)),
+    })
+}
+
+fn chunk_scheme_to_u8(v: ChunkScheme) -> u8 {
+    match v {
+        ChunkScheme::Extended => 0,
+        ChunkScheme::Truncated => 1,
+    }
+}
+
+fn chunk_scheme_from_u8(v: u8) -> Result<ChunkScheme, FormatError> {
+    Ok(match v {
+        0 => ChunkScheme::Extended,
+        1 => ChunkScheme::Truncated,
+        _ => return Err(FormatError::ChunkScheme(v)),
+    })
+}
+
+/// Read `n` little-endian `u64` words from `r`.
+pub(crate) fn read_words<R: Read>(mut r: R, n: usize) -> io::Result<Vec<u64>> {
+    // Grow the vec as words are read rather than trusting `n` to size the
+    // allocation up-front, as it may come from a corrupt file.
+    let mut words = Vec::new();
+    let mut buf = [0; 8];
+    for _ in 0..n {
+        r.read_exact(&mut buf)?;
+        words.push(u64::from_le_bytes(buf));
+    }
+    Ok(words)
+}
+
+/// Read a single little-endian `u64` word from `r`, as a `usize`.
+pub(crate) fn read_usize<R: Read>(mut r: R) -> Result<usize, FormatError> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    usize::try_from(u64::from_le_bytes(buf)).map_err(|_| FormatError::Layout(LayoutError::Length))
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// A [`Write`] implementation computing the checksum of all bytes wrote.
+#[derive(Debug)]
+struct ChecksumWriter<W> {
+    inner: W,
+    hash: u64,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hash: FNV_OFFSET,
+        }
+    }
+
+    /// Return the inner writer, and the checksum of all bytes wrote.
+    fn finish(self) -> (W, u64) {
+        (self.inner, self.hash)
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hash = fnv1a(self.hash, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A [`Read`] implementation computing the checksum of all bytes read.
+#[derive(Debug)]
+struct ChecksumReader<R> {
+    inner: R,
+    hash: u64,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hash: FNV_OFFSET,
+        }
+    }
+
+    /// Return the inner reader, and the checksum of all bytes read.
+    fn finish(self) -> (R, u64) {
+        (self.inner, self.hash)
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hash = fnv1a(self.hash, &buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hash::BuildHasherDefault;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{BlockedBitmap, BloomFilterBuilder, CompressedBitmap, VecBitmap};
+
+    type MyBuildHasher = BuildHasherDefault<twox_hash::XxHash64>;
+
+    const HASHER_ID: u64 = 42;
+
+    fn new_filter<B: Bitmap>(values: &[usize]) -> Bloom2<MyBuildHasher, B, usize> {
+        let mut b = BloomFilterBuilder::hasher(MyBuildHasher::default())
+            .with_bitmap::<B>()
+            .size(FilterSize::KeyBytes2)
+            .build();
+        for v in values {
+            b.insert(v);
+        }
+        b
+    }
+
+    fn encode<B: PortableBitmap>(b: &Bloom2<MyBuildHasher, B, usize>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        b.write_to(&mut buf, HASHER_ID).unwrap();
+        buf
+    }
+
+    fn decode<B: PortableBitmap>(
+        buf: &[u8],
+    ) -> Result<Bloom2<MyBuildHasher, B, usize>, FormatError> {
+        Bloom2::read_from(buf, MyBuildHasher::default(), HASHER_ID)
+    }
+
+    #[test]
+    fn test_header() {
+        let buf = encode(&new_filter::<CompressedBitmap>(&[1, 2, 3]));
+
+        assert_eq!(&buf[..8], b"bloom2\0\0");
+        assert_eq!(&buf[8..12], &[1, 0, 0, 0]);
+        assert_eq!(buf[12], 2);
+        assert_eq!(buf[13], 1);
+        assert_eq!(&buf[14..24], &[0; 10]);
+        assert_eq!(&buf[24..32], &HASHER_ID.to_le_bytes());
+    }
+
+    #[test]
+    fn test_hashes() {
+        let mut b = BloomFilterBuilder::hasher(MyBuildHasher::default())
+            .size(FilterSize::KeyBytes2)
+            .hashes(7)
+            .with_bitmap::<VecBitmap>()
+            .build();
+        for v in 0..100_usize {
+            b.insert(&v);
+        }
+
+        let buf = encode(&b);
+        assert_eq!(buf[14], 7);
+
+        let got = decode::<VecBitmap>(&buf).unwrap();
+        assert_eq!(got, b);
+    }
+
+    #[test]
+    fn test_blocked() {
+        let mut b = BloomFilterBuilder::hasher(MyBuildHasher::default())
+            .size(FilterSize::KeyBytes3)
+            .with_bitmap::<BlockedBitmap>()
+            .build();
+        for v in 0..100_usize {
+            b.insert(&v);
+        }
+
+        let buf = encode(&b);
+        assert_eq!(buf[13], 2);
+
+        let got = decode::<BlockedBitmap>(&buf).unwrap();
+        assert_eq!(got, b);
+        assert!((0..100).all(|v| got.contains(&v)));
+
+        assert!(matches!(
+            decode::<CompressedBitmap>(&buf),
+            Err(FormatError::BitmapKind { want: 1, got: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_hash_width() {
+        let mut b = BloomFilterBuilder::hasher(MyBuildHasher::default())
+            .size(FilterSize::KeyBytes2)
+            .hash_width(HashWidth::Bits128)
+            .build();
+        for v in 0..100_usize {
+            b.insert(&v);
+        }
+
+        let buf = encode(&b);
+        assert_eq!(buf[15], 1);
+
+        let got = decode::<CompressedBitmap>(&buf).unwrap();
+        assert_eq!(got, b);
+        assert!((0..100).all(|v| got.contains(&v)));
+    }
+
+    #[test]
+    fn test_chunk_scheme() {
+        let mut b = BloomFilterBuilder::hasher(MyBuildHasher::default())
+            .size(FilterSize::KeyBytes3)
+            .chunk_scheme(ChunkScheme::Truncated)
+            .build();
+        for v in 0..100_usize {
+            b.insert(&v);
+        }
+
+        let buf = encode(&b);
+        assert_eq!(buf[16], 1);
+
+        let got = decode::<CompressedBitmap>(&buf).unwrap();
+        assert_eq!(got, b);
+        assert!((0..100).all(|v| got.contains(&v)));
+    }
+
+    #[test]
+    fn test_bad_magic() {
+        let mut buf = encode(&new_filter::<VecBitmap>(&[1, 2, 3]));
+        buf[0] = b'B';
+        assert!(matches!(decode::<VecBitmap>(&buf), Err(FormatError::Magic)));
+    }
+
+    #[test]
+    fn test_bad_version() {
+        let mut buf = encode(&new_filter::<VecBitmap>(&[1, 2, 3]));
+        buf[8] = 42;
+        assert!(matches!(
+            decode::<VecBitmap>(&buf),
+            Err(FormatError::Version(42))
+        ));
+    }
+
+    #[test]
+    fn test_bad_filter_size() {
+        let mut buf = encode(&new_filter::<VecBitmap>(&[1, 2, 3]));
+        buf[12] = 6;
+        assert!(matches!(
+            decode::<VecBitmap>(&buf),
+            Err(FormatError::FilterSize(6))
+        ));
+    }
+
+    #[test]
+    fn test_bad_hash_width() {
+        let mut buf = encode(&new_filter::<VecBitmap>(&[1, 2, 3]));
+        buf[15] = 2;
+        assert!(matches!(
+            decode::<VecBitmap>(&buf),
+            Err(FormatError::HashWidth(2))
+        ));
+    }
+
+    #[test]
+    fn test_bad_chunk_scheme() {
+        let mut buf = encode(&new_filter::<VecBitmap>(&[1, 2, 3]));
+        buf[16] = 2;
+        assert!(matches!(
+            decode::<VecBitmap>(&buf),
+            Err(FormatError::ChunkScheme(2))
+        ));
+    }
+
+    #[test]
+    fn test_bitmap_kind_mismatch() {
+        let buf = encode(&new_filter::<VecBitmap>(&[1, 2, 3]));
+        assert!(matches!(
+            decode::<CompressedBitmap>(&buf),
+            Err(FormatError::BitmapKind { want: 1, got: 0 })
+        ));
+    }
+
+    #[test]
+    fn test_hasher_mismatch() {
+        let buf = encode(&new_filter::<VecBitmap>(&[1, 2, 3]));
+        let got =
+            Bloom2::<_, VecBitmap, usize>::read_from(buf.as_slice(), MyBuildHasher::default(), 24);
+        assert!(matches!(
+            got,
+            Err(FormatError::Hasher { want: 24, got: 42 })
+        ));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let buf = encode(&new_filter::<CompressedBitmap>(&[1, 2, 3]));
+        for n in [0, 10, 24, 40, buf.len() - 1] {
+            assert!(
+                matches!(
+                    decode::<CompressedBitmap>(&buf[..n]),
+                    Err(FormatError::Io(_))
+                ),
+                "truncated at {}",
+                n
+            );
+        }
+    }
+
+    #[test]
+    fn test_bad_checksum() {
+        let mut buf = encode(&new_filter::<CompressedBitmap>(&[1, 2, 3]));
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        assert!(matches!(
+            decode::<CompressedBitmap>(&buf),
+            Err(FormatError::Checksum)
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_bitmap() {
+        // Flip a bit in the block map of a compressed bitmap, marking a block
+        // as allocated that has no data.
+        let mut buf = encode(&new_filter::<CompressedBitmap>(&[]));
+        buf[32 + 16] ^= 1;
+        assert!(matches!(
+            decode::<CompressedBitmap>(&buf),
+            Err(FormatError::Layout(LayoutError::RankDirectory))
+        ));
+
+        // And change the word count of a vec bitmap.
+        let mut buf = encode(&new_filter::<VecBitmap>(&[]));
+        buf[32] ^= 1;
+        assert!(matches!(
+            decode::<VecBitmap>(&buf),
+            Err(FormatError::Layout(LayoutError::Length))
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_round_trip_compressed(
+            values in prop::collection::vec(any::<usize>(), 0..100),
+        ) {
+            let b = new_filter::<CompressedBitmap>(&values);
+            let got = decode::<CompressedBitmap>(&encode(&b)).unwrap();
+            assert_eq!(got.bitmap, b.bitmap);
+            assert_eq!(got.key_size, b.key_size);
+        }
+
+        #[test]
+        fn prop_round_trip_vec(
+            values in prop::collection::vec(any::<usize>(), 0..100),
+        ) {
+            let b = new_filter::<VecBitmap>(&values);
+            let got = decode::<VecBitmap>(&encode(&b)).unwrap();
+            assert_eq!(got.bitmap, b.bitmap);
+            assert_eq!(got.key_size, b.key_size);
+        }
+    }
+}
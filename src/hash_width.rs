@@ -0,0 +1,131 @@
+use std::hash::{BuildHasher, Hash, Hasher};
+
+/// The value hashed before each value to derive the second half of a 128-bit
+/// hash, ensuring it differs from the first.
+const SECOND_HASH_SEED: u64 = 0x6A09_E667_F3BC_C908;
+
+/// The width of the hash computed for each value in a filter, from which the
+/// bitmap keys probed for the value are derived.
+///
+/// By default, a value is hashed once to a 64-bit hash. Split into
+/// [`FilterSize`] chunks, a 64-bit hash provides only two keys for a
+/// [`KeyBytes4`] or [`KeyBytes5`] filter, and with
+/// [`hashes()`](crate::BloomFilterBuilder::hashes) set, both halves of the
+/// double hashing scheme are taken from the same 64 bits.
+///
+/// A `Bits128` filter hashes each value twice with its [`BuildHasher`] - once
+/// as normal, and once prefixed with a fixed seed - and derives its keys from
+/// the concatenation of both hashes:
+///
+/// ```text
+///     ┌─────────────┬────────┬─────────┐
+///     │ Filter size │ Bits64 │ Bits128 │
+///     ├─────────────┼────────┼─────────┤
+///     │  KeyBytes1  │   8    │   16    │
+///     │  KeyBytes2  │   4    │    8    │
+///     │  KeyBytes3  │   3    │    6    │
+///     │  KeyBytes4  │   2    │    4    │
+///     │  KeyBytes5  │   2    │    4    │
+///     └─────────────┴────────┴─────────┘
+///              Keys probed per value
+/// ```
+///
+/// And when using double hashing, the two 64-bit hashes provide independent
+/// halves of the scheme. This requires no change to the [`Hash`]
+/// implementation of the values:
+///
+/// ```rust
+/// use bloom2::{BloomFilterBuilder, FilterSize, HashWidth};
+///
+/// let mut filter = BloomFilterBuilder::default()
+///     .size(FilterSize::KeyBytes4)
+///     .hash_width(HashWidth::Bits128)
+///     .build();
+///
+/// filter.insert(&"hello 🐐");
+/// assert!(filter.contains(&"hello 🐐"));
+/// ```
+///
+/// Hashing each value twice roughly doubles the cost of hashing, and probing
+/// more keys increases the number of bits set per value - a wide hash is
+/// intended for large, lightly loaded filters, where the extra probes reduce
+/// the false positive rate. Filters backed by a
+/// [`BlockedBitmap`](crate::BlockedBitmap) probe the same number of keys at
+/// either width, with a 128-bit hash selecting the block and the bits within it
+/// from independent halves.
+///
+/// [`FilterSize`]: crate::FilterSize
+/// [`KeyBytes4`]: crate::FilterSize::KeyBytes4
+/// [`KeyBytes5`]: crate::FilterSize::KeyBytes5
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HashWidth {
+    /// A single 64-bit hash of each value.
+    #[default]
+    Bits64 = 64,
+
+    /// Two 64-bit hashes of each value, concatenated.
+    Bits128 = 128,
+}
+
+impl HashWidth {
+    /// The number of bits in a hash of this width.
+    pub(crate) fn bits(self) -> u32 {
+        self as u32
+    }
+
+    /// Hash `data` with `hasher` to a hash of this width, held in the low bits
+    /// of the returned value.
+    ///
+    /// The first (most significant) 64 bits are always the hash `hasher`
+    /// produces for `data`.
+    pub(crate) fn hash_one<H, T>(self, hasher: &H, data: &T) -> u128
+    where
+        H: BuildHasher,
+        T: Hash + ?Sized,
+    {
+        let first = u128::from(hasher.hash_one(data));
+        match self {
+            Self::Bits64 => first,
+            Self::Bits128 => {
+                let mut h = hasher.build_hasher();
+                h.write_u64(SECOND_HASH_SEED);
+                data.hash(&mut h);
+                (first << 64) | u128::from(h.finish())
+            }
+        }
+    }
+
+    /// Return the first 64 bits of `hash`, a hash of this width.
+    pub(crate) fn first_u64(self, hash: u128) -> u64 {
+        (hash >> (self.bits() - u64::BITS)) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hash::BuildHasherDefault;
+
+    use quickcheck_macros::quickcheck;
+    use twox_hash::XxHash64;
+
+    use super::*;
+
+    type TestHasher = BuildHasherDefault<XxHash64>;
+
+    #[quickcheck]
+    fn test_hash_one(v: u64) {
+        let hasher = TestHasher::default();
+        let want = hasher.hash_one(v);
+
+        let narrow = HashWidth::Bits64.hash_one(&hasher, &v);
+        assert_eq!(narrow, u128::from(want));
+        assert_eq!(HashWidth::Bits64.first_u64(narrow), want);
+
+        // Invariant: the first half of a wide hash is the narrow hash, and the
+        // second half is an unrelated hash.
+        let wide = HashWidth::Bits128.hash_one(&hasher, &v);
+        assert_eq!(HashWidth::Bits128.first_u64(wide), want);
+        assert_ne!(wide as u64, want);
+    }
+}
@@ -32,5 +32,29 @@ pub use bitmap::*;
 mod bloom;
 pub use bloom::*;
 
+mod bloom_ref;
+pub use bloom_ref::*;
+
+mod chunk_scheme;
+pub use chunk_scheme::*;
+
+mod concurrent;
+pub use concurrent::*;
+
+mod counting;
+pub use counting::*;
+
 mod filter_size;
 pub use filter_size::*;
+
+mod format;
+pub use format::*;
+
+mod hash_width;
+pub use hash_width::*;
+
+mod rotating;
+pub use rotating::*;
+
+mod scalable;
+pub use scalable::*;
@@ -0,0 +1,414 @@
+use crate::{
+    bloom::{hash_to_keys, key_size_to_bits},
+    Bitmap, CompressedBitmap, FilterSize, HashWidth,
+};
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+
+/// A bloom filter of values inserted within a sliding window, made up of a
+/// fixed number of generations.
+///
+/// Values are inserted into the newest generation, and a lookup checks every
+/// generation. Each call to [`rotate()`](RotatingBloom2::rotate) discards the
+/// oldest generation, and reuses its (cleared) bitmap as the new, empty newest
+/// generation - a value inserted before the last `N` rotations is forgotten.
+///
+/// ```rust
+/// use bloom2::BloomFilterBuilder;
+///
+/// let mut filter = BloomFilterBuilder::default().build_rotating(2);
+///
+/// filter.insert(&"cat");
+/// filter.rotate();
+/// filter.insert(&"dog");
+/// assert!(filter.contains(&"cat"));
+///
+/// filter.rotate();
+/// assert!(!filter.contains(&"cat"));
+/// assert!(filter.contains(&"dog"));
+/// ```
+///
+/// A `RotatingBloom2` can also rotate automatically after a number of inserts
+/// into the newest generation with
+/// [`with_max_inserts()`](RotatingBloom2::with_max_inserts), or once a period
+/// of time has elapsed with
+/// [`with_interval()`](RotatingBloom2::with_interval):
+///
+/// ```rust
+/// use std::time::Duration;
+/// use bloom2::BloomFilterBuilder;
+///
+/// // Remember values inserted within the last 5 to 10 minutes.
+/// let mut filter = BloomFilterBuilder::default()
+///     .build_rotating(2)
+///     .with_interval(Duration::from_secs(5 * 60));
+///
+/// filter.insert(&"hello 🐐");
+/// assert!(filter.contains(&"hello 🐐"));
+/// ```
+///
+/// Time is measured with [`Instant::now()`] unless a different clock is
+/// provided with [`with_clock()`](RotatingBloom2::with_clock).
+#[derive(Debug, Clone)]
+pub struct RotatingBloom2<H, T, C = fn() -> Instant>
+where
+    H: BuildHasher,
+{
+    hasher: H,
+    generations: Vec<CompressedBitmap>,
+
+    /// The index of the newest generation in `generations`.
+    newest: usize,
+
+    key_size: FilterSize,
+    hashes: Option<u8>,
+    hash_width: HashWidth,
+
+    /// The number of inserts into the newest generation, and the number after
+    /// which it is rotated.
+    inserts: usize,
+    max_inserts: Option<usize>,
+
+    /// The time the newest generation was started, and the duration after
+    /// which it is rotated.
+    clock: C,
+    started_at: Instant,
+    interval: Option<Duration>,
+
+    _key_type: PhantomData<T>,
+}
+
+impl<H, T> RotatingBloom2<H, T>
+where
+    H: BuildHasher,
+    T: Hash,
+{
+    pub(crate) fn new(
+        hasher: H,
+        key_size: FilterSize,
+        hashes: Option<u8>,
+        hash_width: HashWidth,
+        generations: usize,
+    ) -> Self {
+        assert!(
+            generations > 0,
+            "a filter must have at least one generation"
+        );
+
+        let clock = Instant::now as fn() -> Instant;
+        Self {
+            hasher,
+            generations: vec![
+                CompressedBitmap::new_with_capacity(key_size_to_bits(key_size));
+                generations
+            ],
+            newest: 0,
+            key_size,
+            hashes,
+            hash_width,
+            inserts: 0,
+            max_inserts: None,
+            started_at: clock(),
+            clock,
+            interval: None,
+            _key_type: PhantomData,
+        }
+    }
+}
+
+impl<H, T, C> RotatingBloom2<H, T, C>
+where
+    H: BuildHasher,
+    T: Hash,
+    C: Fn() -> Instant,
+{
+    /// Rotate automatically once `max_inserts` values have been inserted into
+    /// the newest generation.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `max_inserts` is 0.
+    pub fn with_max_inserts(self, max_inserts: usize) -> Self {
+        assert!(
+            max_inserts > 0,
+            "a generation must hold at least one insert"
+        );
+        Self {
+            max_inserts: Some(max_inserts),
+            ..self
+        }
+    }
+
+    /// Rotate automatically each time `interval` elapses.
+    ///
+    /// Elapsed intervals are applied before each insert, or when calling
+    /// [`tick()`](RotatingBloom2::tick). If several intervals elapsed since
+    /// the last rotation, the filter is rotated once for each of them.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `interval` is zero.
+    pub fn with_interval(self, interval: Duration) -> Self {
+        assert!(
+            !interval.is_zero(),
+            "the rotation interval must be non-zero"
+        );
+        Self {
+            started_at: (self.clock)(),
+            interval: Some(interval),
+            ..self
+        }
+    }
+
+    /// Measure time for [`with_interval()`](RotatingBloom2::with_interval)
+    /// using `clock`, instead of [`Instant::now()`].
+    pub fn with_clock<D>(self, clock: D) -> RotatingBloom2<H, T, D>
+    where
+        D: Fn() -> Instant,
+    {
+        RotatingBloom2 {
+            hasher: self.hasher,
+            generations: self.generations,
+            newest: self.newest,
+            key_size: self.key_size,
+            hashes: self.hashes,
+            hash_width: self.hash_width,
+            inserts: self.inserts,
+            max_inserts: self.max_inserts,
+            started_at: clock(),
+            clock,
+            interval: self.interval,
+            _key_type: PhantomData,
+        }
+    }
+
+    /// Insert places `data` into the newest generation of the filter.
+    ///
+    /// Any subsequent calls to [`contains`](RotatingBloom2::contains) for the
+    /// same `data` will return true until the generation is rotated out of
+    /// the filter.
+    pub fn insert(&mut self, data: &'_ T) {
+        self.tick();
+        if self.max_inserts.is_some_and(|max| self.inserts >= max) {
+            self.rotate();
+        }
+
+        let hash = self.hash_width.hash_one(&self.hasher, data);
+        let bitmap = &mut self.generations[self.newest];
+        for key in hash_to_keys(hash, self.hash_width, self.key_size, self.hashes) {
+            bitmap.set(key, true);
+        }
+        self.inserts += 1;
+    }
+
+    /// Checks if `data` exists in any generation of the filter.
+    ///
+    /// If `contains` returns true, `data` has **probably** been inserted
+    /// within the window of the filter. If `contains` returns false, `data`
+    /// has **definitely not** been inserted within it.
+    ///
+    /// Lookups do not apply elapsed
+    /// [rotation intervals](RotatingBloom2::with_interval) - call
+    /// [`tick()`](RotatingBloom2::tick) first to expire them.
+    pub fn contains(&self, data: &'_ T) -> bool {
+        let hash = self.hash_width.hash_one(&self.hasher, data);
+        self.generations.iter().any(|b| {
+            hash_to_keys(hash, self.hash_width, self.key_size, self.hashes).all(|key| b.get(key))
+        })
+    }
+
+    /// Discard the oldest generation, and start a new, empty generation.
+    ///
+    /// The bitmap of the oldest generation is cleared and reused, retaining
+    /// its allocated memory.
+    pub fn rotate(&mut self) {
+        self.newest = (self.newest + 1) % self.generations.len();
+        self.generations[self.newest].clear();
+        self.inserts = 0;
+    }
+
+    /// Rotate the filter once for each [rotation
+    /// interval](RotatingBloom2::with_interval) that has elapsed.
+    pub fn tick(&mut self) {
+        let interval = match self.interval {
+            Some(v) => v,
+            None => return,
+        };
+
+        let now = (self.clock)();
+        let elapsed = now.saturating_duration_since(self.started_at).as_nanos();
+        let intervals = elapsed / interval.as_nanos();
+        if intervals == 0 {
+            return;
+        }
+
+        // Rotating once per generation expires them all, regardless of how
+        // many more intervals elapsed.
+        for _ in 0..intervals.min(self.generations.len() as u128) {
+            self.rotate();
+        }
+
+        // Align the start of the newest generation to the interval boundary.
+        let remainder = Duration::from_nanos((elapsed % interval.as_nanos()) as u64);
+        self.started_at = now - remainder;
+    }
+
+    /// Return the number of generations in the filter.
+    pub fn num_generations(&self) -> usize {
+        self.generations.len()
+    }
+
+    /// Return the byte size of this filter.
+    pub fn byte_size(&self) -> usize {
+        self.generations.iter().map(Bitmap::byte_size).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, hash::BuildHasherDefault, rc::Rc};
+
+    use proptest::prelude::*;
+    use twox_hash::XxHash64;
+
+    use super::*;
+    use crate::BloomFilterBuilder;
+
+    type TestHasher = BuildHasherDefault<XxHash64>;
+
+    fn new_test_filter(generations: usize) -> RotatingBloom2<TestHasher, u32> {
+        RotatingBloom2::new(
+            TestHasher::default(),
+            FilterSize::KeyBytes2,
+            None,
+            HashWidth::Bits64,
+            generations,
+        )
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one generation")]
+    fn test_no_generations() {
+        new_test_filter(0);
+    }
+
+    #[test]
+    fn test_builder() {
+        let mut b = BloomFilterBuilder::hasher(TestHasher::default())
+            .size(FilterSize::KeyBytes1)
+            .hashes(3)
+            .hash_width(HashWidth::Bits128)
+            .build_rotating(4);
+
+        b.insert(&42);
+        assert!(b.contains(&42));
+        assert_eq!(b.num_generations(), 4);
+        assert_eq!(b.key_size, FilterSize::KeyBytes1);
+        assert_eq!(b.hashes, Some(3));
+        assert_eq!(b.hash_width, HashWidth::Bits128);
+    }
+
+    #[test]
+    fn test_rotate_reuses_memory() {
+        let mut b = new_test_filter(2);
+        for i in 0..1_000 {
+            b.insert(&i);
+        }
+        b.rotate();
+        let size = b.byte_size();
+
+        // Invariant: rotating clears the oldest generation, retaining its
+        // allocation for reuse.
+        b.rotate();
+        assert_eq!(b.byte_size(), size);
+        for i in 0..1_000 {
+            assert!(!b.contains(&i));
+        }
+    }
+
+    #[test]
+    fn test_max_inserts() {
+        let mut b = new_test_filter(2).with_max_inserts(10);
+
+        for i in 0..20 {
+            b.insert(&i);
+        }
+        assert!((0..20).all(|v| b.contains(&v)));
+
+        // The 21st insert rotates out the first 10 values.
+        b.insert(&20);
+        assert!((10..21).all(|v| b.contains(&v)));
+        assert!((0..10).all(|v| !b.contains(&v)));
+    }
+
+    #[test]
+    fn test_interval() {
+        let start = Instant::now();
+        let now = Rc::new(Cell::new(start));
+        let clock = {
+            let now = Rc::clone(&now);
+            move || now.get()
+        };
+
+        let interval = Duration::from_secs(60);
+        let mut b = new_test_filter(3).with_interval(interval).with_clock(clock);
+
+        b.insert(&1);
+        now.set(start + Duration::from_secs(59));
+        b.insert(&2);
+        assert_eq!(b.newest, 0);
+
+        now.set(start + Duration::from_secs(61));
+        b.insert(&3);
+        assert_eq!(b.newest, 1);
+
+        now.set(start + Duration::from_secs(150));
+        b.tick();
+        assert!(b.contains(&1));
+        assert!(b.contains(&3));
+
+        // The generation holding 1 and 2 expires after three intervals.
+        now.set(start + Duration::from_secs(180));
+        b.tick();
+        assert!(!b.contains(&1));
+        assert!(!b.contains(&2));
+        assert!(b.contains(&3));
+
+        // Invariant: an idle filter expires every generation.
+        now.set(start + Duration::from_secs(3600));
+        b.tick();
+        assert!(!b.contains(&3));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_window(
+            ops in prop::collection::vec(prop::option::of(any::<u32>()), 0..200),
+            generations in 1..5_usize,
+        ) {
+            let mut b = new_test_filter(generations);
+
+            // Each entry holds the values of a generation, newest last.
+            let mut window = vec![Vec::new()];
+            for op in ops {
+                match op {
+                    Some(v) => {
+                        b.insert(&v);
+                        window.last_mut().unwrap().push(v);
+                    }
+                    None => {
+                        b.rotate();
+                        window.push(Vec::new());
+                    }
+                }
+            }
+
+            // Invariant: no false negatives for values in the window.
+            let start = window.len().saturating_sub(generations);
+            for v in window[start..].iter().flatten() {
+                assert!(b.contains(v));
+            }
+        }
+    }
+}
@@ -0,0 +1,411 @@
+use crate::{Bitmap, Bloom2, BloomFilterBuilder, CapacityError, HashWidth};
+use std::convert::TryFrom;
+use std::hash::{BuildHasher, Hash};
+
+/// The multiple by which the capacity of each stage grows over the previous
+/// stage.
+const GROWTH: usize = 2;
+
+/// The ratio by which the false positive rate of each stage is tightened over
+/// the previous stage.
+const TIGHTENING: f64 = 0.5;
+
+/// A bloom filter that grows to hold an unknown number of values, while
+/// bounding the false positive rate.
+///
+/// Sizing a [`Bloom2`] requires guessing the number of values it will hold up
+/// front - guess too low and the false positive rate climbs as the filter
+/// fills. A `ScalableBloom2` instead starts with a single small [`Bloom2`]
+/// stage, and adds a new stage once the current stage holds as many values as
+/// it was sized for, following [Almeida et al.].
+///
+/// Each stage holds twice as many values as the previous stage, with half the
+/// false positive rate. Halving the rate of each stage bounds the compound
+/// false positive rate of all the stages to the target rate, regardless of how
+/// many stages are added:
+///
+/// ```rust
+/// use bloom2::BloomFilterBuilder;
+///
+/// let mut filter = BloomFilterBuilder::default()
+///     .build_scalable(100, 0.01)
+///     .expect("unreachable false positive rate");
+///
+/// for i in 0..1_000 {
+///     filter.insert(&i);
+/// }
+///
+/// assert!(filter.contains(&42));
+/// assert!(filter.num_stages() > 1);
+/// ```
+///
+/// A [`contains`](ScalableBloom2::contains) lookup checks every stage, with
+/// the value hashed once. As the stages are sparse, the stages sized larger
+/// than needed (due to the granularity of the [`FilterSize`]) consume memory
+/// proportional to the number of values they hold.
+///
+/// Once a stage can no longer reach its false positive rate with the largest
+/// [`FilterSize`], no further stages are added and the false positive rate
+/// degrades as the last stage fills.
+///
+/// If the `serde` feature is enabled, the whole chain of stages supports
+/// (de)serialisation with [serde]. As with a [`Bloom2`], the hasher is not
+/// serialised, and must produce the same hashes when deserialised.
+///
+/// [Almeida et al.]: https://doi.org/10.1016/j.ipl.2006.10.007
+/// [`FilterSize`]: crate::FilterSize
+/// [serde]: https://github.com/serde-rs/serde
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        serialize = "B: serde::Serialize",
+        deserialize = "H: Default, B: serde::Deserialize<'de>"
+    ))
+)]
+pub struct ScalableBloom2<H, B, T>
+where
+    H: BuildHasher,
+    B: Bitmap,
+{
+    #[cfg_attr(feature = "serde", serde(skip))]
+    hasher: H,
+    stages: Vec<Bloom2<H, B, T>>,
+
+    /// The width of the hash of each value, shared by all stages.
+    #[cfg_attr(feature = "serde", serde(default))]
+    hash_width: HashWidth,
+
+    /// The number of values the first stage is sized for.
+    initial_capacity: usize,
+
+    /// The compound false positive rate of all stages.
+    target_fpr: f64,
+
+    /// The number of values the last stage is sized for, or `usize::MAX` if
+    /// no further stages can be added.
+    stage_capacity: usize,
+
+    /// The number of values inserted into the last stage.
+    stage_len: usize,
+
+    len: usize,
+}
+
+impl<H, B, T> ScalableBloom2<H, B, T>
+where
+    H: BuildHasher + Clone,
+    B: Bitmap,
+    T: Hash,
+{
+    pub(crate) fn new(
+        hasher: H,
+        hash_width: HashWidth,
+        initial_capacity: usize,
+        target_fpr: f64,
+    ) -> Result<Self, CapacityError> {
+        let mut b = Self {
+            hasher,
+            stages: Vec::new(),
+            hash_width,
+            initial_capacity: initial_capacity.max(1),
+            target_fpr,
+            stage_capacity: 0,
+            stage_len: 0,
+            len: 0,
+        };
+
+        let stage = b.new_stage(0)?;
+        b.stages.push(stage);
+        b.stage_capacity = b.initial_capacity;
+
+        Ok(b)
+    }
+
+    /// Insert places `data` into the filter, adding a new stage if the current
+    /// stage is full.
+    ///
+    /// Any subsequent calls to [`contains`](ScalableBloom2::contains) for the
+    /// same `data` will always return true.
+    pub fn insert(&mut self, data: &'_ T) {
+        let hash = self.hash_width.hash_one(&self.hasher, data);
+
+        // Values already (probably) contained in the filter do not consume
+        // the capacity of the current stage.
+        if self.contains_hash(hash) {
+            return;
+        }
+
+        if self.stage_len >= self.stage_capacity {
+            self.grow();
+        }
+
+        let stage = self.stages.last_mut().expect("filter has no stages");
+        let keys = stage.keys(hash);
+        stage.bitmap.set_all(keys);
+
+        self.stage_len += 1;
+        self.len += 1;
+    }
+
+    /// Checks if `data` exists in any stage of the filter.
+    ///
+    /// If `contains` returns true, `data` has **probably** been inserted
+    /// previously. If `contains` returns false, `data` has **definitely not**
+    /// been inserted into the filter.
+    pub fn contains(&self, data: &'_ T) -> bool {
+        self.contains_hash(self.hash_width.hash_one(&self.hasher, data))
+    }
+
+    /// Return the number of values inserted into the filter.
+    ///
+    /// Inserting a value the filter already (possibly falsely) contains is
+    /// not counted.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Return `true` if no values have been inserted into the filter.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the number of stages in the filter.
+    pub fn num_stages(&self) -> usize {
+        self.stages.len()
+    }
+
+    /// Return the stages of the filter, from the oldest to the newest.
+    pub fn stages(&self) -> &[Bloom2<H, B, T>] {
+        &self.stages
+    }
+
+    /// Return the byte size of this filter.
+    pub fn byte_size(&self) -> usize {
+        self.stages.iter().map(|s| s.bitmap.byte_size()).sum()
+    }
+
+    /// Estimate the compound false positive rate of all stages, from the
+    /// [estimated rate](Bloom2::estimated_fpr) of each.
+    pub fn estimated_fpr(&self) -> f64 {
+        1.0 - self
+            .stages
+            .iter()
+            .map(|s| 1.0 - s.estimated_fpr())
+            .product::<f64>()
+    }
+
+    fn contains_hash(&self, hash: u128) -> bool {
+        self.stages.iter().any(|s| s.bitmap.get_all(s.keys(hash)))
+    }
+
+    /// Add a new stage, or stop growing if the next stage cannot reach its
+    /// false positive rate.
+    fn grow(&mut self) {
+        let i = self.stages.len();
+        match self.new_stage(i) {
+            Ok(stage) => {
+                self.stages.push(stage);
+                self.stage_capacity = stage_capacity(self.initial_capacity, i);
+                self.stage_len = 0;
+            }
+            Err(_) => self.stage_capacity = usize::MAX,
+        }
+    }
+
+    /// Construct stage `i`, sized for its capacity and false positive rate.
+    fn new_stage(&self, i: usize) -> Result<Bloom2<H, B, T>, CapacityError> {
+        // The rates of all stages form a geometric series summing to no more
+        // than target_fpr.
+        let fpr = self.target_fpr * (1.0 - TIGHTENING) * TIGHTENING.powi(i as i32);
+
+        Ok(BloomFilterBuilder::hasher(self.hasher.clone())
+            .with_bitmap::<B>()
+            .with_capacity(stage_capacity(self.initial_capacity, i), fpr)?
+            .hash_width(self.hash_width)
+            .build())
+    }
+}
+
+/// Return the number of values stage `i` is sized for.
+fn stage_capacity(initial_capacity: usize, i: usize) -> usize {
+    u32::try_from(i)
+        .ok()
+        .and_then(|i| GROWTH.checked_pow(i))
+        .and_then(|v| v.checked_mul(initial_capacity))
+        .unwrap_or(usize::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hash::BuildHasherDefault;
+
+    use proptest::prelude::*;
+    use twox_hash::XxHash64;
+
+    use super::*;
+    use crate::{CompressedBitmap, FilterSize, VecBitmap};
+
+    type TestHasher = BuildHasherDefault<XxHash64>;
+
+    fn new_test_filter(
+        initial_capacity: usize,
+        target_fpr: f64,
+    ) -> ScalableBloom2<TestHasher, CompressedBitmap, u32> {
+        ScalableBloom2::new(
+            TestHasher::default(),
+            HashWidth::Bits64,
+            initial_capacity,
+            target_fpr,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_stage_capacity() {
+        assert_eq!(stage_capacity(100, 0), 100);
+        assert_eq!(stage_capacity(100, 3), 800);
+        assert_eq!(stage_capacity(100, 100), usize::MAX);
+        assert_eq!(stage_capacity(usize::MAX, 1), usize::MAX);
+    }
+
+    #[test]
+    fn test_grow() {
+        let mut b = new_test_filter(100, 0.01);
+        assert_eq!(b.num_stages(), 1);
+        assert!(b.is_empty());
+
+        for i in 0..100 {
+            b.insert(&i);
+        }
+        assert_eq!(b.num_stages(), 1);
+
+        // Inserting the same values again consumes no capacity.
+        for i in 0..100 {
+            b.insert(&i);
+        }
+        assert_eq!(b.num_stages(), 1);
+
+        // Values (falsely) reported as present consume no capacity, so the
+        // first stage fills after at least 100 inserts.
+        let mut n = 100;
+        while b.num_stages() == 1 {
+            b.insert(&n);
+            n += 1;
+        }
+        assert!(n > 100);
+        assert_eq!(b.len(), 101);
+
+        for i in 0..n {
+            assert!(b.contains(&i));
+        }
+    }
+
+    #[test]
+    fn test_fpr() {
+        let target = 0.01;
+        let mut b = new_test_filter(100, target);
+
+        let n = 20_000;
+        for i in 0..n {
+            b.insert(&i);
+        }
+        assert!(b.num_stages() > 5);
+        assert!(b.estimated_fpr() <= target);
+
+        let queries = 100_000;
+        let fp = (n..n + queries).filter(|v| b.contains(v)).count();
+        let fpr = fp as f64 / queries as f64;
+        assert!(fpr <= target, "fpr {} exceeds target {}", fpr, target);
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let got = ScalableBloom2::<TestHasher, VecBitmap, u32>::new(
+            TestHasher::default(),
+            HashWidth::Bits64,
+            usize::MAX,
+            0.01,
+        );
+        assert!(matches!(got, Err(CapacityError::Unreachable { .. })));
+
+        let got = ScalableBloom2::<TestHasher, VecBitmap, u32>::new(
+            TestHasher::default(),
+            HashWidth::Bits64,
+            1,
+            0.0,
+        );
+        assert_eq!(got, Err(CapacityError::FalsePositiveRate));
+    }
+
+    #[test]
+    fn test_builder() {
+        let mut b = BloomFilterBuilder::hasher(TestHasher::default())
+            .with_bitmap::<VecBitmap>()
+            .build_scalable(10, 0.1)
+            .unwrap();
+
+        b.insert(&42);
+        assert!(b.contains(&42));
+        assert_eq!(b.stages()[0].key_size, FilterSize::KeyBytes1);
+    }
+
+    #[test]
+    fn test_hash_width() {
+        let mut b = BloomFilterBuilder::hasher(TestHasher::default())
+            .hash_width(HashWidth::Bits128)
+            .build_scalable(10, 0.01)
+            .unwrap();
+
+        for i in 0..100 {
+            b.insert(&i);
+        }
+
+        assert!(b.num_stages() > 1);
+        assert!(b
+            .stages()
+            .iter()
+            .all(|s| s.hash_width == HashWidth::Bits128));
+        assert!((0..100).all(|i| b.contains(&i)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let mut b = new_test_filter(10, 0.01);
+        for i in 0..100 {
+            b.insert(&i);
+        }
+
+        let encoded = serde_json::to_string(&b).unwrap();
+        let decoded: ScalableBloom2<TestHasher, CompressedBitmap, u32> =
+            serde_json::from_str(&encoded).unwrap();
+
+        assert_eq!(decoded, b);
+        for i in 0..100 {
+            assert!(decoded.contains(&i));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_no_false_negatives(
+            values in prop::collection::vec(any::<u32>(), 0..200),
+            initial_capacity in 1..50_usize,
+        ) {
+            let mut b = new_test_filter(initial_capacity, 0.01);
+            for v in &values {
+                b.insert(v);
+            }
+
+            for v in &values {
+                assert!(b.contains(v));
+            }
+
+            // Invariant: no stage holds more values than it was sized for.
+            assert!(b.len() <= values.len());
+            assert!(b.stage_len <= b.stage_capacity);
+        }
+    }
+}